@@ -0,0 +1,243 @@
+// Expressive performance: how loud and how long each note of the timeline is played.
+//   \p, \mf, \ff ...  ->  a velocity level, kept until the next dynamic
+//   c\< d e f\f       ->  the velocity rises from the level at c to the f at the next dynamic
+//   c\> d e f\!       ->  without a dynamic at the end, the hairpin moves one hairpin_step
+//   \accent, \marcato, \sfz  ->  louder than the current level (\fp: forte, then piano)
+//   \staccato, \staccatissimo, \portato  ->  part of the written length; \tenuto  ->  the full length
+//   c( d e)           ->  c and d overlap the next note a little, e is shortened
+//   \fermata          ->  the music is held: the note and everything sounding with it last longer
+// Every effect is set in ExpressionOptions; 1 for a length, 0 for a velocity change turns it off.
+use serde::{Deserialize, Serialize};
+
+use crate::lilypond_parser::{is_timed_note, note_fraction, Articulation, Fermata, Hairpin, LilyPondNote};
+use crate::slice::TempoMap;
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExpressionOptions {
+    pub dynamics: bool,         // Dynamics and hairpins set the velocity; otherwise every note is at default_velocity
+    pub default_velocity: f64,  // Velocity (0 to 1) before the first dynamic, mf
+    pub hairpin_step: f64,      // Velocity change of a hairpin that ends without a dynamic
+    pub accent: f64,            // Velocity added by \accent, \sf, \sfz and \rfz
+    pub marcato: f64,           // Velocity added by \marcato and \sff
+    pub staccato: f64,          // Part of the written length played
+    pub staccatissimo: f64,
+    pub portato: f64,
+    pub legato_overlap: f64,    // Seconds a slurred note overlaps the next one
+    pub slur_end: f64,          // Part of the written length of the last note of a slur
+    pub fermata: f64,           // A \fermata holds the music this many times as long (shorter and longer fermatas in proportion)
+}
+
+impl Default for ExpressionOptions {
+    fn default() -> Self {
+        Self {
+            dynamics: true,
+            default_velocity: 0.64,
+            hairpin_step: 0.12,
+            accent: 0.15,
+            marcato: 0.25,
+            staccato: 0.5,
+            staccatissimo: 0.25,
+            portato: 0.75,
+            legato_overlap: 0.03,
+            slur_end: 0.8,
+            fermata: 2.0,
+        }
+    }
+}
+
+// Velocity of each dynamic level
+fn dynamic_level(name: &str) -> Option<f64> {
+    Some(match name {
+        "ppppp" => 0.12,
+        "pppp" => 0.18,
+        "ppp" => 0.25,
+        "pp" | "spp" => 0.33,
+        "p" | "sp" => 0.42,
+        "mp" => 0.52,
+        "mf" => 0.64,
+        "f" => 0.76,
+        "ff" => 0.86,
+        "fff" => 0.94,
+        "ffff" => 0.97,
+        "fffff" => 1.0,
+        _ => return None,
+    })
+}
+
+// A velocity level from `time` (whole notes); with `ramp`, moving towards the next one
+struct Keyframe {
+    time: f64,
+    level: f64,
+    ramp: bool,
+}
+
+/// Velocity over the time of one staff or voice, from its dynamics and hairpins
+pub(crate) struct Dynamics {
+    keyframes: Vec<Keyframe>,
+    default: f64,
+}
+
+impl Dynamics {
+    pub fn new(notes: &[LilyPondNote], order: &[u32], options: &ExpressionOptions) -> Self {
+        let mut dynamics = Dynamics { keyframes: Vec::new(), default: options.default_velocity };
+        if !options.dynamics {
+            return dynamics;
+        }
+        // Keyframe where the open hairpin starts, and whether it grows
+        let mut open: Option<(usize, bool)> = None;
+        let mut time = 0.0;
+        for &index in order {
+            let note = &notes[index as usize];
+            // Written order on a note: \! ends a hairpin, a dynamic sets the level, \< or \> start one
+            if note.hairpins.contains(&Hairpin::Stop) {
+                dynamics.close(open.take(), time, None, options);
+            }
+            let level = note.dynamic.as_deref().and_then(|name| match name {
+                "fp" => dynamic_level("p"),
+                name => dynamic_level(name),
+            });
+            if let Some(level) = level {
+                dynamics.close(open.take(), time, Some(level), options);
+                dynamics.keyframes.push(Keyframe { time, level, ramp: false });
+            }
+            for hairpin in &note.hairpins {
+                if *hairpin != Hairpin::Stop {
+                    dynamics.close(open.take(), time, None, options);
+                    let level = dynamics.level(time);
+                    dynamics.keyframes.push(Keyframe { time, level, ramp: false });
+                    open = Some((dynamics.keyframes.len() - 1, *hairpin == Hairpin::Crescendo));
+                }
+            }
+            if is_timed_note(note) {
+                time += note_fraction(note).unwrap_or(0.0);
+            }
+        }
+        dynamics.close(open, time, None, options);
+        dynamics
+    }
+
+    // Ends the hairpin started at keyframe `open` at `time`, on `target` or one step further
+    fn close(&mut self, open: Option<(usize, bool)>, time: f64, target: Option<f64>, options: &ExpressionOptions) {
+        let Some((start, grows)) = open else { return };
+        if time <= self.keyframes[start].time + EPSILON {
+            return;
+        }
+        let from = self.keyframes[start].level;
+        let step = if grows { options.hairpin_step } else { -options.hairpin_step };
+        self.keyframes[start].ramp = true;
+        self.keyframes.push(Keyframe { time, level: target.unwrap_or((from + step).clamp(0.0, 1.0)), ramp: false });
+    }
+
+    /// Velocity level at `time` (whole notes)
+    pub fn level(&self, time: f64) -> f64 {
+        let Some(current) = self.keyframes.iter().rposition(|k| k.time <= time + EPSILON) else {
+            return self.default;
+        };
+        let keyframe = &self.keyframes[current];
+        match self.keyframes.get(current + 1) {
+            Some(next) if keyframe.ramp && next.time > keyframe.time => {
+                keyframe.level + (next.level - keyframe.level) * (time - keyframe.time) / (next.time - keyframe.time)
+            },
+            _ => keyframe.level,
+        }
+    }
+}
+
+/// Velocity of a note at the level `level`, with its accents
+pub(crate) fn note_velocity(note: &LilyPondNote, level: f64, options: &ExpressionOptions) -> f64 {
+    let mut velocity = level;
+    if options.dynamics && note.dynamic.as_deref() == Some("fp") {
+        velocity = dynamic_level("f").unwrap_or(level);
+    }
+    if note.articulations.contains(&Articulation::Marcato) || note.dynamic.as_deref() == Some("sff") {
+        velocity += options.marcato;
+    } else if note.articulations.contains(&Articulation::Accent) || matches!(note.dynamic.as_deref(), Some("sf" | "sfz" | "rfz")) {
+        velocity += options.accent;
+    }
+    velocity.clamp(0.0, 1.0)
+}
+
+/// Part of its written length a note is played, from its articulations
+/// (`slur_end` when it is the last note of a slur)
+pub(crate) fn length_factor(note: &LilyPondNote, slur_end: bool, options: &ExpressionOptions) -> f64 {
+    let has = |articulation| note.articulations.contains(&articulation);
+    if has(Articulation::Tenuto) {
+        1.0
+    } else if has(Articulation::Staccatissimo) {
+        options.staccatissimo
+    } else if has(Articulation::Staccato) {
+        options.staccato
+    } else if has(Articulation::Portato) {
+        options.portato
+    } else if slur_end {
+        options.slur_end
+    } else {
+        1.0
+    }
+}
+
+/// Whether a note is played detached: it then never overlaps the next one, even under a slur
+pub(crate) fn is_detached(note: &LilyPondNote) -> bool {
+    note.articulations.iter().any(|a| matches!(a, Articulation::Staccato | Articulation::Staccatissimo | Articulation::Portato))
+}
+
+/// Fermatas of one staff or voice met in `order`: (start, end, how many times longer), in whole notes
+pub(crate) fn fermata_holds(notes: &[LilyPondNote], order: &[u32], options: &ExpressionOptions) -> Vec<(f64, f64, f64)> {
+    let mut holds = Vec::new();
+    let mut time = 0.0;
+    for &index in order {
+        let note = &notes[index as usize];
+        if !is_timed_note(note) {
+            continue;
+        }
+        let length = note_fraction(note).unwrap_or(0.0);
+        if let Some(fermata) = note.fermata {
+            let scale = match fermata {
+                Fermata::VeryShort => 0.25,
+                Fermata::Short => 0.5,
+                Fermata::Normal => 1.0,
+                Fermata::Long => 1.5,
+                Fermata::VeryLong => 2.0,
+            };
+            holds.push((time, time + length, 1.0 + (options.fermata - 1.0) * scale));
+        }
+        time += length;
+    }
+    holds
+}
+
+/// Seconds of each moment of the score, at its tempo and held at its fermatas
+pub(crate) struct Clock {
+    tempo: TempoMap,
+    holds: Vec<(f64, f64, f64)>, // Not overlapping, by start
+}
+
+impl Clock {
+    pub fn new(tempo: TempoMap, mut holds: Vec<(f64, f64, f64)>) -> Self {
+        // Fermatas at the same moment in several staves hold the music once, as long as the longest
+        holds.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f64, f64, f64)> = Vec::new();
+        for hold in holds {
+            match merged.last_mut() {
+                Some(last) if hold.0 < last.1 - EPSILON => {
+                    last.1 = last.1.max(hold.1);
+                    last.2 = last.2.max(hold.2);
+                },
+                _ => merged.push(hold),
+            }
+        }
+        Self { tempo, holds: merged }
+    }
+
+    /// Seconds from the start of the score to `wholes`
+    pub fn seconds(&self, wholes: f64) -> f64 {
+        let mut seconds = self.tempo.seconds(wholes);
+        for &(start, end, factor) in self.holds.iter().take_while(|hold| hold.0 < wholes) {
+            seconds += (factor - 1.0) * (self.tempo.seconds(end.min(wholes)) - self.tempo.seconds(start));
+        }
+        seconds
+    }
+}
@@ -1,5 +1,16 @@
 // Export the lilypond_parser module
 pub mod lilypond_parser;
+pub mod playback;
+pub mod pitch;
+pub mod music_function;
+pub mod scheme;
+pub mod markup;
+pub mod properties;
+pub mod slice;
+pub mod performance;
+pub mod ornaments;
+pub mod expression;
+pub mod pedals;
 
 // Re-export the types from lilypond_parser for external use
 pub use lilypond_parser::{LilyPondNote, ParsedMusic, MusicMode, ApiParsedMusic};
@@ -1042,7 +1053,1127 @@ testChord = \relative c'' { <e-1 g-2 c-5>4 }
         assert_eq!(*c_octave, 6, "c should be octave 6 (above g, is a major 6th, so goes up to next octave)");
     }
 
+    #[test]
+    fn test_bar_checks() {
+        // The second bar check comes one quarter too early, \barNumberCheck #4 is correct
+        // and \barNumberCheck #6 is wrong
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \time 3/4
+  c'4 d' e' | f'2 | g'4 a'2 b'4 |
+  \barNumberCheck #4
+  c''2. |
+  \barNumberCheck #6
+  c''2.
+} }"#;
+        
+        let result = lilypond_parser::parse_lilypond(test_content);
+        assert!(result.is_ok(), "Failed to parse bar checks: {:?}", result);
+        
+        let parsed = result.unwrap();
+        for warning in &parsed.warnings {
+            println!("{}:{} {:?} {}", warning.line, warning.column, warning.kind, warning.message);
+        }
+        
+        let bar_check_failures: Vec<_> = parsed.warnings.iter()
+            .filter(|w| w.kind == lilypond_parser::WarningKind::BarCheckFailed)
+            .collect();
+        assert_eq!(bar_check_failures.len(), 1, "Only the second bar check should fail");
+        assert_eq!(bar_check_failures[0].line, 4);
+        assert_eq!(bar_check_failures[0].column, 19);
+        
+        let bar_number_failures: Vec<_> = parsed.warnings.iter()
+            .filter(|w| w.kind == lilypond_parser::WarningKind::BarNumberCheckFailed)
+            .collect();
+        assert_eq!(bar_number_failures.len(), 1, "Only \\barNumberCheck #6 should fail");
+        assert_eq!(bar_number_failures[0].line, 7);
+    }
+    
+    #[test]
+    fn test_measure_fullness_warnings() {
+        // With a pickup of one quarter, the second measure is overfull (a whole note in 3/4)
+        // and the last one is underfull
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \time 3/4
+  \partial 4
+  g'4 | c''1 | e''2. | d''2
+} }"#;
+        
+        let result = lilypond_parser::parse_lilypond(test_content);
+        assert!(result.is_ok(), "Failed to parse: {:?}", result);
+        
+        let parsed = result.unwrap();
+        for warning in &parsed.warnings {
+            println!("{}:{} {:?} {}", warning.line, warning.column, warning.kind, warning.message);
+        }
+        
+        let overfull: Vec<_> = parsed.warnings.iter()
+            .filter(|w| w.kind == lilypond_parser::WarningKind::MeasureOverfull)
+            .collect();
+        assert_eq!(overfull.len(), 1, "The whole note measure should be overfull");
+        assert!(overfull[0].message.contains("Measure 1"), "Bar numbering starts at 0 for the pickup: {}", overfull[0].message);
+        assert_eq!((overfull[0].line, overfull[0].column), (5, 9));
+        
+        let underfull: Vec<_> = parsed.warnings.iter()
+            .filter(|w| w.kind == lilypond_parser::WarningKind::MeasureUnderfull)
+            .collect();
+        assert_eq!(underfull.len(), 1, "Only the last measure should be underfull");
+        assert!(underfull[0].message.contains("Measure 3"), "{}", underfull[0].message);
+    }
+    
+    #[test]
+    fn test_barline_types() {
+        use lilypond_parser::BarlineType;
+        // Repeat barlines are inferred from \repeat volta, \bar overrides them
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \time 4/4
+  c'1 |
+  \repeat volta 2 { d'1 | e'1 }
+  \alternative { { f'1 } { g'1 } }
+  a'1 \bar "||"
+  \repeat volta 2 { b'1 }
+  c''1 \bar "!"
+  d''1 \bar "|."
+} }"#;
+        
+        let result = lilypond_parser::parse_lilypond(test_content);
+        assert!(result.is_ok(), "Failed to parse barlines: {:?}", result);
+        
+        let parsed = result.unwrap();
+        let barlines: Vec<BarlineType> = parsed.staves[0].measures.iter()
+            .map(|m| m.barline)
+            .collect();
+        println!("Barlines: {:?}", barlines);
+        
+        assert_eq!(barlines, vec![
+            BarlineType::StartRepeat,   // c'1
+            BarlineType::Single,        // d'1
+            BarlineType::Single,        // e'1
+            BarlineType::EndRepeat,     // f'1 (first alternative)
+            BarlineType::Single,        // g'1
+            BarlineType::Double,        // a'1, \bar "||" replaces the start repeat
+            BarlineType::EndRepeat,     // b'1
+            BarlineType::Dashed,        // c''1
+            BarlineType::Final,         // d''1
+        ]);
+    }
+    
+    // Pitches of a staff in playing order, markers left out
+    fn played_pitches(staff: &lilypond_parser::Staff) -> Vec<String> {
+        staff.performance_order.iter()
+            .map(|&i| &staff.base.notes[i as usize])
+            .filter(|n| !n.pitch.is_empty())
+            .map(|n| n.pitch.clone())
+            .collect()
+    }
+    
+    #[test]
+    fn test_dc_al_fine_and_repeat_segno() {
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  c'4 d' e' f'^"Fine" | g'4 a' b' c''_"D.C. al Fine" |
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse D.C. al Fine");
+        let played = played_pitches(&parsed.staves[0]);
+        println!("D.C. al Fine: {:?}", played);
+        assert_eq!(played, vec!["c", "d", "e", "f", "g", "a", "b", "c", "c", "d", "e", "f"]);
+        
+        // \repeat segno with alternatives: D.S. after the first ending, the last ending is the coda
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \repeat segno 2 { c'4 d' } \alternative { { e'2 } { f'2 } } g'1
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse repeat segno");
+        let played = played_pitches(&parsed.staves[0]);
+        println!("repeat segno: {:?}", played);
+        assert_eq!(played, vec!["c", "d", "e", "c", "d", "f", "g"]);
+    }
+    
+    #[test]
+    fn test_ds_al_coda_marks() {
+        // Repeats inside the D.S. section are played once after the jump
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  c'1 \segnoMark
+  \repeat volta 2 { d'1 } e'1 \mark "To Coda"
+  f'1 \mark \markup { \italic "D.S. al Coda" }
+  \codaMark g'1 \fine
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse D.S. al Coda");
+        let notes = &parsed.staves[0].base.notes;
+        assert!(notes.iter().any(|n| n.navigation.contains(&lilypond_parser::NavigationMark::Segno) && n.pitch == "d"));
+        
+        let played = played_pitches(&parsed.staves[0]);
+        println!("D.S. al Coda: {:?}", played);
+        assert_eq!(played, vec!["c", "d", "d", "e", "f", "d", "e", "g"]);
+    }
+    
+    #[test]
+    fn test_repeat_unfold_percent_tremolo() {
+        use lilypond_parser::{NoteType, Tremolo};
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \repeat unfold 4 { c'8 d' }
+  \repeat percent 2 { e'4 f' g' a' }
+  \repeat tremolo 8 { c'16 e' }
+  \repeat tremolo 4 { g'16 }
+  b'4:32 c''2:
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse repeats");
+        let notes = &parsed.staves[0].base.notes;
+        assert!(!notes.iter().any(|n| matches!(n.note_type, NoteType::RepeatStart | NoteType::RepeatEnd)),
+            "unfold, percent and tremolo must not produce repeat signs");
+        
+        // unfold: 8 eighth notes written inline
+        let unfolded: Vec<&str> = notes[..8].iter().map(|n| n.pitch.as_str()).collect();
+        assert_eq!(unfolded, vec!["c", "d", "c", "d", "c", "d", "c", "d"]);
+        
+        // percent: the second pass is kept for playback and flagged for the renderer
+        assert!(notes[8..12].iter().all(|n| !n.pitch.is_empty() && n.percent_repeat.is_none()));
+        assert!(notes[12..16].iter().all(|n| n.percent_repeat == Some(2)));
+        
+        // tremolo: two half notes with sixteenth strokes, then a quarter with a stem tremolo
+        assert_eq!((notes[16].duration.as_str(), notes[16].tremolo), ("2", Some(Tremolo::Start(16))));
+        assert_eq!((notes[17].duration.as_str(), notes[17].tremolo), ("2", Some(Tremolo::End(16))));
+        assert_eq!((notes[18].duration.as_str(), notes[18].tremolo), ("4", Some(Tremolo::Stem(16))));
+        
+        // stem tremolo written on the note
+        assert_eq!(notes[19].tremolo, Some(Tremolo::Stem(32)));
+        assert_eq!((notes[20].duration.as_str(), notes[20].tremolo), ("2", Some(Tremolo::Stem(8))));
+        
+        // Every measure is full: unfold, percent (2), two-note tremolo, the rest
+        assert_eq!(parsed.staves[0].measures.len(), 5);
+        assert!(parsed.warnings.is_empty(), "Unexpected warnings: {:?}", parsed.warnings);
+    }
+    
+    #[test]
+    fn test_nested_repeats_and_volta_numbers() {
+        use lilypond_parser::NoteType;
+        // LilyPond 2.24 syntax: the alternatives end the repeated music and name their passes,
+        // and the first ending contains a repeat of its own
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff {
+  \repeat volta 3 {
+    c'1
+    \alternative {
+      \volta 1,2 { d'1 \repeat volta 2 { e'1 } \alternative { { f'1 } { g'1 } } }
+      \volta 3 { a'1 }
+    }
+  }
+  b'1
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse nested repeats");
+        let staff = &parsed.staves[0];
+        let alternatives: Vec<Vec<i32>> = staff.base.notes.iter()
+            .filter(|n| n.note_type == NoteType::AlternativeStart)
+            .map(|n| n.alternative_index.clone())
+            .collect();
+        assert_eq!(alternatives, vec![vec![1, 2], vec![1], vec![2], vec![3]]);
+        
+        let played = played_pitches(staff);
+        println!("Nested repeats: {:?}", played);
+        assert_eq!(played, vec!["c", "d", "e", "f", "e", "g", "c", "d", "e", "f", "e", "g", "c", "a", "b"]);
+        
+        // One whole note per measure
+        assert_eq!(staff.measures.len(), 7);
+        assert!(parsed.warnings.is_empty(), "Unexpected warnings: {:?}", parsed.warnings);
+        
+        // Without \volta, the first of two endings is played on the first two of three passes
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff { \repeat volta 3 { c'1 } \alternative { { d'1 } { e'1 } } } }"#;
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse repeat");
+        assert_eq!(played_pitches(&parsed.staves[0]), vec!["c", "d", "c", "d", "c", "e"]);
+    }
 
+    #[test]
+    fn test_key_signature_modes() {
+        use lilypond_parser::NoteType;
+        let key_of = |music: &str| {
+            let test_content = format!("\\version \"2.24.0\"\n{}", music);
+            let parsed = lilypond_parser::parse_lilypond(&test_content).expect("Failed to parse key");
+            // A leading \key is a staff directive, a later one a key marker
+            let staff = &parsed.staves[0];
+            staff.base.notes.iter()
+                .find(|n| n.note_type == NoteType::Key)
+                .and_then(|n| n.key_sig.clone())
+                .or_else(|| staff.base.key_signature.clone())
+                .expect("No key signature")
+        };
+        
+        assert_eq!(key_of(r#"\score { \new Staff { \key d \dorian c'1 } }"#), "C");
+        assert_eq!(key_of(r#"\score { \new Staff { \key bes \lydian c'1 } }"#), "F");
+        assert_eq!(key_of(r#"\score { \new Staff { \key e \phrygian c'1 } }"#), "C");
+        assert_eq!(key_of(r#"\score { \new Staff { \key fis \minor c'1 } }"#), "F#m");
+        // Eight sharps keep the tonic's spelling, with f as a double sharp
+        assert_eq!(key_of(r#"\score { \new Staff { \key gis \major c'1 } }"#), "G#");
+        assert_eq!(key_of(r#"\score { \new Staff { \key fes \major c'1 } }"#), "Fb");
+        let parsed = lilypond_parser::parse_lilypond(r#"\score { \new Staff { \key gis \major gis'4 ais' bis' fisis' | f'1 } }"#)
+            .expect("Failed to parse key");
+        let shown: Vec<_> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.note_type == NoteType::Default)
+            .map(|n| n.accidentals.clone())
+            .collect();
+        let natural = Some(pitch::PrintedAccidental { accidental: pitch::Accidental::Natural, cautionary: false });
+        assert_eq!(shown, vec![vec![None], vec![None], vec![None], vec![None], vec![natural]]);
+        // The tonic follows the note-name language
+        assert_eq!(key_of(r#"\language "deutsch" \score { \new Staff { \key h \minor c'1 } }"#), "Bm");
+        assert_eq!(key_of(r#"\language "deutsch" \score { \new Staff { \key es \major c'1 } }"#), "Eb");
+        // English names: s and -sharp for sharps, f and -flat for flats
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key fs \minor c'1 } }"#), "F#m");
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key cs \major c'1 } }"#), "C#");
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key bf \minor c'1 } }"#), "Bbm");
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key e-flat \major c'1 } }"#), "Eb");
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key as \minor c'1 } }"#), "A#m");
+        // Without \language the names are Dutch, LilyPond's default: as and es are flats
+        assert_eq!(key_of(r#"\score { \new Staff { \key as \major c'1 } }"#), "Ab");
+        assert_eq!(key_of(r#"\score { \new Staff { \key es \minor c'1 } }"#), "Ebm");
+        assert_eq!(key_of(r#"\language "english" \score { \new Staff { \key g-sharp \minor c'1 } }"#), "G#m");
+    }
+    
+    #[test]
+    fn test_accidental_display() {
+        use pitch::{Accidental, PrintedAccidental};
+        let test_content = r#"\version "2.24.0"
+\score { \new Staff { \key g \major \time 4/4
+  fis'4 f'4 f'4 fis'4~ | fis'4 c'!4 c'?4 <f' fis''>4 |
+} }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse accidentals");
+        let shown: Vec<Vec<Option<PrintedAccidental>>> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.span.is_some() && n.pitch != "r")
+            .map(|n| n.accidentals.clone())
+            .collect();
+        let plain = |accidental| Some(PrintedAccidental { accidental, cautionary: false });
+        assert_eq!(shown, vec![
+            vec![None],                                   // F sharp is in the key
+            vec![plain(Accidental::Natural)],
+            vec![None],                                   // Natural still in effect
+            vec![plain(Accidental::Sharp)],
+            vec![None],                                   // Tied across the barline
+            vec![plain(Accidental::Natural)],             // Forced with !
+            vec![Some(PrintedAccidental { accidental: Accidental::Natural, cautionary: true })],
+            vec![plain(Accidental::Natural), None],       // New measure: back to the key signature
+        ]);
+    }
+    
+    #[test]
+    fn test_relative_diatonic_and_octave_checks() {
+        use lilypond_parser::{NoteType, WarningKind};
+        // eis -> b is six semitones either way, but b lies a fourth below on the staff;
+        // the note after a chord is relative to the chord's first note
+        let test_content = r#"\version "2.24.0"
+melody = \relative c' {
+  ces4 fis eis b | <c e g> c d='' e | \octaveCheck c'' d \octaveCheck a'' b
+}
+\score { \new Staff { \melody } }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse relative music");
+        let octaves: Vec<(String, i32)> = parsed.staves[0].base.notes.iter()
+            .filter(|n| matches!(n.note_type, NoteType::Default | NoteType::Chord))
+            .map(|n| (n.pitch.clone(), n.octave))
+            .collect();
+        let expected: Vec<(String, i32)> = [("ces", 4), ("fis", 4), ("eis", 4), ("b", 3), ("c", 4), ("c", 4), ("d", 5), ("e", 5), ("d", 5), ("b", 5)]
+            .iter().map(|(p, o)| (p.to_string(), *o)).collect();
+        assert_eq!(octaves, expected);
+        
+        // d='' came out as d' and \octaveCheck a'' as a'
+        let checks: Vec<&String> = parsed.warnings.iter()
+            .filter(|w| w.kind == WarningKind::OctaveCheckFailed)
+            .map(|w| &w.message)
+            .collect();
+        assert_eq!(checks.len(), 2, "Unexpected octave check warnings: {:?}", checks);
+        assert!(checks[0].contains("expected d''"));
+        assert!(checks[1].contains("found a'"));
+    }
+    
+    #[test]
+    fn test_nested_pitch_modes() {
+        use lilypond_parser::NoteType;
+        // \inner keeps its own \relative; \plain takes the mode of the place where it is used;
+        // \relative does not reach into \transpose, and the next note follows the pitch before it
+        let test_content = r#"\version "2.24.0"
+inner = \relative c'' { c4 d }
+plain = { c e }
+melody = \relative c' {
+  e4 \inner f \fixed c { c' d } g \plain
+  \transpose c d { \key g \major e f } a
+  \transpose c es \relative c' { fis } b
+}
+\score { \new Staff { \melody } }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse nested modes");
+        let notes = &parsed.staves[0].base.notes;
+        let pitches: Vec<(String, i32)> = notes.iter()
+            .filter(|n| n.note_type == NoteType::Default)
+            .map(|n| (n.pitch.clone(), n.octave))
+            .collect();
+        let expected: Vec<(String, i32)> = [
+            ("e", 4), ("c", 5), ("d", 5), ("f", 4), ("c", 4), ("d", 3), ("g", 4), ("c", 5), ("e", 5),
+            ("fis", 3), ("g", 3), ("a", 5), ("a", 4), ("b", 5),
+        ].iter().map(|(p, o)| (p.to_string(), *o)).collect();
+        assert_eq!(pitches, expected);
+        
+        // The key inside \transpose c d moves up a whole tone too
+        let key = notes.iter().find(|n| n.note_type == NoteType::Key).and_then(|n| n.key_sig.clone());
+        assert_eq!(key.as_deref(), Some("A"));
+    }
+    
+    #[test]
+    fn test_variable_environment() {
+        use lilypond_parser::{NoteType, VariableValue, WarningKind};
+        // The score comes first and \melody refers to \motif, defined after it
+        let test_content = r#"\version "2.24.0"
+\header { title = \pieceTitle }
+\score { \new Staff { \melody } }
+melody = \relative c' { c4 d \motif \noSuchMusic }
+motif = { e f }
+pieceTitle = "Forward"
+verse = \lyricmode { la la }
+count = 3
+label = \markup { \bold "Intro" }
+mods = \with { \consists "Span_arpeggio_engraver" }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse variables");
+        let pitches: Vec<String> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.note_type == NoteType::Default)
+            .map(|n| format!("{}{}", n.pitch, n.octave))
+            .collect();
+        assert_eq!(pitches, vec!["c4", "d4", "e4", "f4"]);
+        assert_eq!(parsed.title.as_deref(), Some("Forward"));
+        
+        // \noSuchMusic is defined nowhere in the file: read as an unknown command, not a missing variable
+        let unknown: Vec<&String> = parsed.warnings.iter()
+            .filter(|w| w.kind == WarningKind::UnknownCommand)
+            .map(|w| &w.message)
+            .collect();
+        assert_eq!(unknown, vec!["Unknown command \\noSuchMusic, ignored"]);
+        assert!(!parsed.warnings.iter().any(|w| w.kind == WarningKind::UndefinedVariable));
 
+        // \first is defined, but only in terms of \second, which uses it back
+        let circular = lilypond_parser::parse_lilypond("first = { c'4 \\second }\nsecond = { d'4 \\first }\n\\score { \\new Staff { \\first \\stemUp } }")
+            .expect("Failed to parse circular variables");
+        let undefined: Vec<&String> = circular.warnings.iter()
+            .filter(|w| w.kind == WarningKind::UndefinedVariable)
+            .map(|w| &w.message)
+            .collect();
+        assert_eq!(undefined, vec!["Undefined variable \\first"]);
+        assert!(!circular.warnings.iter().any(|w| w.message.contains("stemUp")));
+        
+        assert!(matches!(parsed.variables["verse"].value, VariableValue::Lyrics));
+        assert!(matches!(parsed.variables["count"].value, VariableValue::Number(n) if n == 3.0));
+        assert!(matches!(&parsed.variables["label"].value, VariableValue::Markup(markup) if markup.text == "Intro"));
+        assert!(matches!(&parsed.variables["mods"].value, VariableValue::ContextModification(body) if body.contains("Span_arpeggio_engraver")));
+    }
+    
+    #[test]
+    fn test_variable_scoping() {
+        // \both is defined with the top-level \music; the book's own \music does not leak out
+        let test_content = r#"\version "2.24.0"
+music = { c'1 }
+both = << \new Staff { \music } \new Staff { d'1 } >>
+\book {
+  music = { e'1 }
+  \score { \new Staff { \music } }
+  \score { \both }
 }
+\score { \new Staff { \music } }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse scoped variables");
+        let first_pitches: Vec<String> = parsed.staves.iter()
+            .map(|staff| staff.base.notes.first().map(|n| n.pitch.clone()).unwrap_or_default())
+            .collect();
+        assert_eq!(first_pitches, vec!["e", "c", "d", "c"]);
+        assert!(parsed.warnings.is_empty(), "Unexpected warnings: {:?}", parsed.warnings);
+    }
+    
+    #[test]
+    fn test_music_function_templates() {
+        let test_content = r#"\version "2.24.0"
+tripletFor = #(define-music-function (m) (ly:music?) #{ \tuplet 3/2 $m #})
+twice = #(define-music-function (parser location n music) (number? ly:music?) #{ \repeat unfold #n $music #})
+\score {
+  \new Staff { \tripletFor { c'8 d' e' } \twice #2 { f'4 } \twice { g'4 } }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse music functions");
+        let notes: Vec<_> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.note_type == lilypond_parser::NoteType::Default)
+            .collect();
+        assert_eq!(notes.iter().map(|n| n.pitch.as_str()).collect::<Vec<_>>(), vec!["c", "d", "e", "f", "f"]);
+        assert!(notes[..3].iter().all(|n| n.tuplet_fraction.as_deref() == Some("3/2")));
+        
+        // Expanded notes point at the call, not into the template
+        let call = test_content.find(r"\tripletFor {").unwrap();
+        let span = notes[0].span.expect("expanded note has a span");
+        assert_eq!(span.start, call);
+        assert_eq!(&test_content[span.start..span.end], r"\tripletFor { c'8 d' e' }");
+        
+        // Wrong number of arguments is reported at the call
+        let warning = parsed.warnings.iter()
+            .find(|w| w.kind == lilypond_parser::WarningKind::MusicFunctionError)
+            .expect("argument count warning");
+        assert_eq!(warning.span.map(|s| s.start), test_content.find(r"\twice { g'4 }"));
+        
+        // Music passed in a variable, and a function without parameters called by its name
+        let test_content = r#"\version "2.24.0"
+tripletFor = #(define-music-function (m) (ly:music?) #{ \tuplet 3/2 $m #})
+fermataC = #(define-music-function () () #{ c''2\fermata #})
+melody = { c'8 d' e' }
+\score {
+  \new Staff { \tripletFor \melody \melody \fermataC \tripletFor \melody }
+}"#;
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse music function arguments");
+        let notes: Vec<_> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.note_type == lilypond_parser::NoteType::Default)
+            .collect();
+        let played: Vec<_> = notes.iter().map(|n| (n.pitch.as_str(), n.octave, n.tuplet_fraction.as_deref())).collect();
+        assert_eq!(played, vec![
+            ("c", 4, Some("3/2")), ("d", 4, Some("3/2")), ("e", 4, Some("3/2")),
+            ("c", 4, None), ("d", 4, None), ("e", 4, None),
+            ("c", 5, None),
+            ("c", 4, Some("3/2")), ("d", 4, Some("3/2")), ("e", 4, Some("3/2")),
+        ]);
+        assert!(notes[6].fermata.is_some());
+        let span = notes[0].span.unwrap();
+        assert_eq!(&test_content[span.start..span.end], r"\tripletFor \melody");
+        assert!(parsed.warnings.iter().all(|w| w.kind != lilypond_parser::WarningKind::MusicFunctionError));
+    }
+    
+    #[test]
+    fn test_scheme_evaluation() {
+        let mut env = scheme::SchemeEnv::new();
+        let no_variables = |_: &str| None;
+        assert_eq!(env.eval("#(+ 1/8 1/8)", &no_variables), Ok(scheme::SchemeValue::Rational { numerator: 1, denominator: 4 }));
+        assert_eq!(env.eval("##t", &no_variables), Ok(scheme::SchemeValue::Boolean(true)));
+        assert_eq!(env.eval("#'(a \"b\" 3)", &no_variables).map(|v| v.display()), Ok("(a b 3)".to_string()));
+        assert_eq!(env.eval("#(ly:make-moment 60 4)", &no_variables), Ok(scheme::SchemeValue::Moment { numerator: 15, denominator: 1 }));
+        assert!(matches!(env.eval("#(system \"rm -rf /\")", &no_variables), Err(scheme::SchemeError::Unsupported(_))));
+        // Fractions that overflow become floats
+        assert_eq!(env.eval("#(/ -9223372036854775808 -1)", &no_variables), Ok(scheme::SchemeValue::Real(9223372036854775808.0)));
+        assert_eq!(env.eval("#1/-9223372036854775808", &no_variables), Ok(scheme::SchemeValue::Real(-1.0 / 9223372036854775808.0)));
+        assert_eq!(env.eval("#(abs -9223372036854775808)", &no_variables), Ok(scheme::SchemeValue::Real(9223372036854775808.0)));
+        
+        let test_content = r#"\version "2.24.0"
+#(set-global-staff-size 18)
+#(define quarters 60)
+#(ly:set-option 'point-and-click #f)
+#(+ 1 "two")
+big = #(/ -9223372036854775808 -1)
+movement = #(string-append "Sonata " "No. " (number->string (* 2 quarters)))
+\header { title = \movement }
+\score {
+  \new Staff { \set Score.tempoWholesPerMinute = #(ly:make-moment quarters 4) c'1 }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse Scheme values");
+        assert_eq!(parsed.global_staff_size, Some(18.0));
+        assert_eq!(parsed.title.as_deref(), Some("Sonata No. 120"));
+        assert_eq!(parsed.tempo_bpm, Some(60.0));
+        // Unknown procedures are skipped quietly; wrong code is reported
+        let kinds: Vec<_> = parsed.warnings.iter().map(|w| &w.kind).collect();
+        assert_eq!(kinds, vec![&lilypond_parser::WarningKind::InvalidScheme]);
+    }
+    
+    #[test]
+    fn test_movements() {
+        let test_content = r#"\version "2.24.0"
+\header { composer = "Mozart" }
+\book {
+  \header { title = "Sonata" }
+  \score { \header { title = "I. Allegro" } \new Staff { c'1 } }
+  \bookpart {
+    \header { title = "II. Menuetto" }
+    \score { \new Staff { \partial 4 d'4 | e'1 } }
+  }
+}
+\score { \new Staff { f'1 } }"#;
+        
+        let parse = || lilypond_parser::parse_lilypond(test_content).expect("Failed to parse movements");
+        let parsed = parse();
+        // The explicit book, then the implicit one holding the top-level score
+        assert_eq!(parsed.books.len(), 2);
+        let movements = parsed.movements();
+        let titles: Vec<_> = movements.iter().map(|m| m.title.as_deref()).collect();
+        assert_eq!(titles, vec![Some("I. Allegro"), Some("II. Menuetto"), None]);
+        assert!(parsed.scores().all(|score| score.header.text("composer").as_deref() == Some("Mozart")));
+        // Settings stay with their own score
+        let partials: Vec<_> = parsed.scores().map(|score| score.partial.as_deref()).collect();
+        assert_eq!(partials, vec![None, Some("4"), None]);
+        
+        let second = ApiParsedMusic::movement(parse(), 1).expect("second movement");
+        assert_eq!(second.staves.len(), 1);
+        assert_eq!(second.staves[0].base.notes.iter().find(|n| n.note_type == lilypond_parser::NoteType::Default).map(|n| n.pitch.as_str()), Some("d"));
+        assert_eq!(second.partial.as_deref(), Some("4"));
+        assert!(ApiParsedMusic::movement(parse(), 3).is_err());
+        // Older callers get the first movement on its own
+        let first = ApiParsedMusic::from(parsed);
+        assert_eq!((first.movement, first.staves.len(), first.movements.len()), (Some(0), 1, 3));
+    }
+    
+    #[test]
+    fn test_full_header() {
+        use lilypond_parser::HeaderValue;
+        use markup::TextStyle;
+        
+        let test_content = r#"\version "2.24.0"
+dedicationText = \markup { \italic { to Therese } }
+\header {
+  title = \markup { \bold "Für Elise" \fontsize #-2 { WoO 59 } }
+  subtitle = "Bagatelle"
+  opus = ##f
+  dedication = \dedicationText
+  meter = \markup { \column { "Poco moto" \fromproperty #'header:piece } }
+  copyright = "Public domain"
+}
+\score { \new Staff { c'1 } }"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse header");
+        let names: Vec<_> = parsed.header.fields.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, vec!["title", "subtitle", "opus", "dedication", "meter", "copyright"]);
+        assert_eq!(parsed.title.as_deref(), Some("Für Elise WoO 59"));
+        assert_eq!(parsed.header.get("opus"), Some(&HeaderValue::Boolean(false)));
+        assert_eq!(parsed.header.text("opus"), None);
+        
+        let Some(HeaderValue::Markup(title)) = parsed.header.get("title") else { panic!("title is markup") };
+        let styles: Vec<_> = title.spans.iter().map(|s| (s.start, s.end, s.style.clone())).collect();
+        assert_eq!(styles, vec![(0, 9, TextStyle::Bold), (10, 16, TextStyle::FontSize(-2.0))]);
+        
+        let Some(HeaderValue::Markup(meter)) = parsed.header.get("meter") else { panic!("meter is markup") };
+        // The referenced property goes on the second line of the column
+        assert_eq!(meter.text, "Poco moto\n");
+        let reference = meter.spans.iter().find(|s| s.style == TextStyle::FromProperty("header:piece".to_string()));
+        assert_eq!(reference.map(|s| (s.start, s.end)), Some((10, 10)));
+        assert_eq!(parsed.header.text("dedication").as_deref(), Some("to Therese"));
+        
+        // A movement shows its score's header merged over the file's
+        let movement = ApiParsedMusic::from(parsed);
+        assert_eq!(movement.header.text("copyright").as_deref(), Some("Public domain"));
+    }
+    
+    #[test]
+    fn test_markup_tree() {
+        use lilypond_parser::{NavigationMark, ScriptContent};
+        use markup::{parse_markup, ColumnAlign, Markup, TextStyle};
+        
+        let text = |text: &str| Markup::Text { text: text.to_string() };
+        let styled = |style: TextStyle, markup: Markup| Markup::Styled { style, markup: Box::new(markup) };
+        let markup = parse_markup(r#"\markup \center-column {
+  \line { \bold "Sonata" \super 2 }
+  \concat { \with-color #(rgb-color 1 0 0) \dynamic sfz \hspace #1.5 \note {4.} #UP }
+  \musicglyph #"scripts.segno"
+}"#);
+        assert_eq!(markup, Markup::Column { align: ColumnAlign::Center, items: vec![
+            Markup::Line { items: vec![styled(TextStyle::Bold, text("Sonata")), styled(TextStyle::Superscript, text("2"))] },
+            Markup::Concat { items: vec![
+                styled(TextStyle::Color("#ff0000".to_string()), styled(TextStyle::Dynamic, text("sfz"))),
+                Markup::HSpace { amount: 1.5 },
+                Markup::Note { duration: "4.".to_string(), direction: 1.0 },
+            ] },
+            Markup::MusicGlyph { name: "scripts.segno".to_string() },
+        ] });
+        assert_eq!(markup.to_styled_text().text, "Sonata 2\nsfz");
+        
+        // Markup without braces in scripts, marks, tempo and lyrics
+        let test_content = r#"\version "2.24.0"
+\score { <<
+  \new Staff { \new Voice = "melody" {
+    \tempo \markup \italic "Andante" 4 = 72
+    c'4^\markup \italic "dolce" d'4 \mark \markup \musicglyph #"scripts.segno" e'4 f'4
+  } }
+  \new Lyrics \lyricsto "melody" { \markup \italic "la la" do re mi }
+>> }"#;
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse markup");
+        assert_eq!(parsed.tempo.as_deref(), Some("Andante"));
+        assert_eq!(parsed.tempo_bpm, Some(72.0));
+        let voice = &parsed.staves[0].voices[0];
+        let c = voice.base.notes.iter().find(|n| n.pitch == "c").expect("c");
+        assert_eq!(c.script_attachments[0].content, ScriptContent::Markup(styled(TextStyle::Italic, text("dolce"))));
+        assert!(voice.base.notes.iter().any(|n| n.navigation.contains(&NavigationMark::Segno) && n.pitch == "e"));
+        let lyric = &voice.lyrics[0];
+        assert_eq!(lyric.text_nodes, vec!["la la", "do", "re", "mi"]);
+    }
+    
+    #[test]
+    fn test_staff_groups() {
+        use lilypond_parser::{GroupDelimiter, StaffGroupType};
+        
+        let test_content = r#"\version "2.24.0"
+violin = \relative c'' { \set Staff.shortInstrumentName = "Vl." c1 }
+\score {
+  \new StaffGroup <<
+    \new Staff \with { instrumentName = "Violin" } { \violin }
+    \new ChoirStaff \with { instrumentName = \markup \bold "Choir" } <<
+      \new Staff { \set Staff.instrumentName = "Soprano" c''1 }
+      \new Staff { \set Staff.instrumentName = "Alto" g'1 }
+    >>
+    \new PianoStaff \with { instrumentName = "Piano" systemStartDelimiter = #'SystemStartBracket } <<
+      \new Staff { c'1 }
+      \new Staff { \clef bass c1 }
+    >>
+  >>
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse staff groups");
+        let names: Vec<_> = parsed.staves.iter().map(|s| s.base.instrument.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("Violin"), Some("Soprano"), Some("Alto"), None, None]);
+        assert_eq!(parsed.staves[0].base.instrument.short_name.as_deref(), Some("Vl."));
+        
+        assert_eq!(parsed.staff_groups.len(), 1);
+        let outer = &parsed.staff_groups[0];
+        assert_eq!((outer.group_type, outer.delimiter, outer.staves.clone()), (StaffGroupType::StaffGroup, GroupDelimiter::Bracket, 0..5));
+        let inner: Vec<_> = outer.groups.iter()
+            .map(|g| (g.group_type, g.delimiter, g.staves.clone(), g.instrument.name.as_deref()))
+            .collect();
+        assert_eq!(inner, vec![
+            (StaffGroupType::ChoirStaff, GroupDelimiter::Bracket, 1..3, Some("Choir")),
+            (StaffGroupType::PianoStaff, GroupDelimiter::Bracket, 3..5, Some("Piano")),
+        ]);
+        assert_eq!(parsed.scores().next().map(|score| score.staff_groups.len()), Some(1));
+    }
+
+    #[test]
+    fn test_property_events() {
+        use properties::{property_at, PropertyAction};
+        use scheme::SchemeValue;
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff \with { midiInstrument = "piano" } {
+    c'4 \set Staff.midiInstrument = #"violin" d'4
+    \once \override Stem.direction = #UP e'4 f'4
+    \set Score.currentBarNumber = #10 \override Voice.Stem.direction = #DOWN g'1
+    \unset Staff.midiInstrument \revert Voice.Stem.direction a'1
+  }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse property commands");
+        let notes = &parsed.staves[0].base.notes;
+        let first = &notes[0].properties[0];
+        assert_eq!((first.action, first.context.as_deref(), first.property.as_str()), (PropertyAction::Set, Some("Staff"), "midiInstrument"));
+        let stem = &notes[2].properties[0];
+        assert_eq!((stem.action, stem.context.as_deref(), stem.property.as_str(), stem.once), (PropertyAction::Override, None, "Stem.direction", true));
+        assert_eq!(stem.value, Some(SchemeValue::Integer(1)));
+        
+        let instrument = |moment| property_at(notes, Some("Staff"), "midiInstrument", moment);
+        assert_eq!(instrument(0.0), Some(SchemeValue::String("piano".to_string())));
+        assert_eq!(instrument(0.25), Some(SchemeValue::String("violin".to_string())));
+        assert_eq!(instrument(2.0), None);
+        assert_eq!(property_at(notes, Some("Score"), "currentBarNumber", 1.0), Some(SchemeValue::Integer(10)));
+        
+        // \once lasts for its note; the voice override from g' on is reverted at a'
+        let stem_at = |context, moment| property_at(notes, context, "Stem.direction", moment);
+        assert_eq!(stem_at(None, 0.5), Some(SchemeValue::Integer(1)));
+        assert_eq!(stem_at(None, 0.75), None);
+        assert_eq!(stem_at(Some("Voice"), 1.5), Some(SchemeValue::Integer(-1)));
+        assert_eq!(stem_at(Some("Voice"), 2.5), None);
+    }
+
+    #[test]
+    fn test_temporary_polyphony() {
+        use lilypond_parser::StemDirection;
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff { \relative c'' {
+    \time 2/4 c4 d
+    << { e4 f } \\ { c,2 } >>
+    g'2
+    << { a4 b } \\ { e,4. d8 } \\ { c4 \stemUp c } >>
+    \voiceTwo a'4 \oneVoice b4
+  } }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse polyphony");
+        let staff = &parsed.staves[0];
+        assert!(staff.base.notes.is_empty());
+        let numbers: Vec<_> = staff.voices.iter().map(|v| v.number).collect();
+        assert_eq!(numbers, vec![Some(1), Some(2), Some(3)]);
+        // Every voice has the same measures, the temporary ones padded with spacer rests
+        for voice in &staff.voices {
+            assert_eq!(voice.measures.len(), 5);
+        }
+        
+        let written = |voice: usize| -> Vec<(String, i32, Option<StemDirection>)> {
+            staff.voices[voice].base.notes.iter()
+                .filter(|n| n.note_type != lilypond_parser::NoteType::Time && !n.spacer)
+                .map(|n| (format!("{}{}{}", n.pitch, n.duration, n.dots), n.octave, n.stem_direction))
+                .collect()
+        };
+        let up = Some(StemDirection::Up);
+        let down = Some(StemDirection::Down);
+        // The music after >> continues from the first part and goes back to automatic stems
+        assert_eq!(written(0), vec![
+            ("c4".to_string(), 5, None), ("d4".to_string(), 5, None), ("e4".to_string(), 5, up), ("f4".to_string(), 5, up),
+            ("g2".to_string(), 6, None), ("a4".to_string(), 6, up), ("b4".to_string(), 6, up),
+            ("a4".to_string(), 7, down), ("b4".to_string(), 7, None),
+        ]);
+        assert_eq!(written(1), vec![("c2".to_string(), 4, down), ("e4.".to_string(), 5, down), ("d8".to_string(), 5, down)]);
+        assert_eq!(written(2), vec![("c4".to_string(), 7, up), ("c4".to_string(), 7, up)]);
+        let spacers = staff.voices[2].base.notes.iter().filter(|n| n.spacer).count();
+        assert_eq!(spacers, 7);
+    }
 
+    #[test]
+    fn test_cross_staff_notes() {
+        use lilypond_parser::WarningKind;
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new PianoStaff <<
+    \new Staff = "upper" { \relative c'' { c4 d \change Staff = "lower" c, b \change Staff = "upper" c'1 \change Staff = "nowhere" d1 } }
+    \new Staff = "lower" { \clef bass c1 c1 c1 }
+  >>
+}
+\score {
+  \new PianoStaff \autoChange \relative c' { c4 g e' a, }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse cross-staff notes");
+        let shown_on = |staff: usize| -> Vec<Option<usize>> {
+            parsed.staves[staff].base.notes.iter().map(|n| n.display_staff).collect()
+        };
+        // Notes stay in their voice; only the staff they are drawn on changes
+        assert_eq!(shown_on(0), vec![None, None, Some(1), Some(1), None, None]);
+        assert_eq!(parsed.warnings.iter().filter(|w| w.kind == WarningKind::UndefinedStaff).count(), 1);
+        
+        // \autoChange in a PianoStaff makes the staves "up" and "down"; indices count from the score
+        let names: Vec<_> = parsed.staves[2..].iter().map(|s| s.base.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("up"), Some("down")]);
+        assert_eq!(shown_on(2), vec![None, Some(1), None, Some(1)]);
+        assert!(parsed.staves[3].base.notes.iter().all(|n| n.spacer));
+        assert_eq!(parsed.staves[3].measures.len(), parsed.staves[2].measures.len());
+        
+        // The split pitch is read in the file's note-name language: fs' is F sharp
+        let english = lilypond_parser::parse_lilypond(r#"\language "english"
+\score { \new PianoStaff \autoChange fs' { e'4 g'4 } }"#).expect("Failed to parse \\autoChange split pitch");
+        let shown: Vec<_> = english.staves[0].base.notes.iter().map(|n| n.display_staff).collect();
+        assert_eq!(shown, vec![Some(1), None]);
+    }
+
+    #[test]
+    fn test_measure_grid() {
+        use lilypond_parser::{GridMeasure, WarningKind};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  <<
+    \new Staff { \time 3/4 \partial 4 c4 | c2. | c2. | c2 c4 }
+    \new Staff { \time 3/4 \partial 4 r4 | c2. | c2. | c2. }
+  >>
+}
+\score {
+  <<
+    \new Staff { c1 c1 c1 }
+    \new Staff { c1 c1 }
+  >>
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse measure grid");
+        let scores: Vec<_> = parsed.scores().collect();
+        assert_eq!(scores[0].measure_grid, vec![
+            GridMeasure { number: 0, start: 0.0, length: 0.25 },
+            GridMeasure { number: 1, start: 0.25, length: 0.75 },
+            GridMeasure { number: 2, start: 1.0, length: 0.75 },
+            GridMeasure { number: 3, start: 1.75, length: 0.75 },
+        ]);
+        let grid: Vec<_> = parsed.staves[1].measures.iter().map(|m| m.grid).collect();
+        assert_eq!(grid, vec![Some(0), Some(1), Some(2), Some(3)]);
+        
+        // The shorter staff still points into the grid, and is reported
+        assert_eq!(scores[1].measure_grid.len(), 3);
+        let grid: Vec<_> = parsed.staves[3].measures.iter().map(|m| m.grid).collect();
+        assert_eq!(grid, vec![Some(0), Some(1)]);
+        let mismatches: Vec<_> = parsed.warnings.iter().filter(|w| w.kind == WarningKind::MeasureCountMismatch).collect();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].line, 11);
+        
+        // The grid follows the time signature of the score, \time 3/4 here; the measures of the
+        // staff in 4/4 that start inside a grid measure are reported
+        let test_content = r#"\version "2.24.0"
+\score {
+  <<
+    \new Staff { \time 3/4 c'2. d'2. e'2. f'2. }
+    \new Staff { c1 d1 e1 f1 }
+  >>
+}"#;
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse measure grid");
+        let starts: Vec<_> = parsed.scores().next().unwrap().measure_grid.iter().map(|g| (g.start, g.length)).collect();
+        assert_eq!(starts, vec![(0.0, 0.75), (0.75, 0.75), (1.5, 0.75), (2.25, 0.75), (3.0, 0.75), (3.75, 0.75)]);
+        let grid: Vec<_> = parsed.staves[0].measures.iter().map(|m| m.grid).collect();
+        assert_eq!(grid, vec![Some(0), Some(1), Some(2), Some(3)]);
+        let grid: Vec<_> = parsed.staves[1].measures.iter().map(|m| m.grid).collect();
+        assert_eq!(grid, vec![Some(0), None, None, Some(4)]);
+        let off_grid: Vec<_> = parsed.warnings.iter().filter(|w| w.kind == WarningKind::MeasureOffGrid).map(|w| (w.line, w.column)).collect();
+        assert_eq!(off_grid, vec![(5, 21), (5, 24)]);
+    }
+
+    #[test]
+    fn test_vertical_slice() {
+        use slice::{vertical_slice, SliceMode, SliceQuery, TimeUnit};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  <<
+    \new Staff { \tempo 4 = 60 \relative c'' { c2~ c4 \grace { d8 } e4 | \repeat volta 2 { f2 g } } }
+    \new Staff { \relative c { c1 | \repeat volta 2 { g1 } } }
+  >>
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse vertical slice");
+        let music = ApiParsedMusic::from(parsed);
+        let query = |from: f64, to: Option<f64>| SliceQuery {
+            from, to, unit: TimeUnit::Wholes, mode: SliceMode::Overlap, measures: None, unroll_repeats: false,
+        };
+        let heads = |query: SliceQuery| -> Vec<(usize, String, i32, f64, f64, bool)> {
+            vertical_slice(&music, &query).into_iter()
+                .map(|n| (n.staff, n.pitches[0].0.clone(), n.pitches[0].1, n.onset, n.end, n.grace))
+                .collect()
+        };
+        
+        // The tied c'' sounds until beat 4, where the grace note and e'' come in
+        assert_eq!(heads(query(0.5, Some(0.5))), vec![
+            (0, "c".to_string(), 5, 0.0, 0.75, false), (1, "c".to_string(), 3, 0.0, 1.0, false),
+        ]);
+        assert_eq!(heads(query(0.75, Some(0.75))), vec![
+            (1, "c".to_string(), 3, 0.0, 1.0, false), (0, "d".to_string(), 5, 0.75, 0.75, true), (0, "e".to_string(), 5, 0.75, 1.0, false),
+        ]);
+        let chain = vertical_slice(&music, &query(0.5, Some(0.5)));
+        assert_eq!(chain[0].notes.len(), 2);
+        // A tied note is not attacked again
+        assert!(heads(SliceQuery { mode: SliceMode::Onset, ..query(0.5, Some(0.5)) }).is_empty());
+        
+        // The second time through the repeat only exists with repeats unrolled
+        let second_pass = SliceQuery { mode: SliceMode::Onset, ..query(2.0, Some(3.0)) };
+        assert!(heads(second_pass.clone()).is_empty());
+        assert_eq!(heads(SliceQuery { unroll_repeats: true, ..second_pass }), vec![
+            (0, "f".to_string(), 5, 2.0, 2.5, false), (1, "g".to_string(), 2, 2.0, 3.0, false), (0, "g".to_string(), 5, 2.5, 3.0, false),
+        ]);
+        
+        // At a quarter = 60 a bar lasts 4 seconds; measures index the score's grid
+        let in_bar_two = SliceQuery { unit: TimeUnit::Seconds, measures: Some(1..2), ..query(0.0, None) };
+        assert_eq!(heads(in_bar_two), vec![
+            (0, "f".to_string(), 5, 4.0, 6.0, false), (1, "g".to_string(), 2, 4.0, 8.0, false), (0, "g".to_string(), 5, 6.0, 8.0, false),
+        ]);
+    }
+
+    #[test]
+    fn test_nested_tuplets() {
+        use lilypond_parser::{TupletGroup, WarningKind};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff {
+    \time 2/4
+    \tuplet 3/2 { c'8 \tuplet 3/2 { d'16 e' f' } g'8 } \times 2/3 { a'8 b' c'' } |
+    \tuplet 3/2 4 { c'8 d' e' f' g' a' } |
+    \tupletSpan 4 \tuplet 3/2 { c'8 d' e' f' g' a' } |
+  }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse tuplets");
+        let notes: Vec<_> = parsed.staves[0].base.notes.iter().filter(|n| lilypond_parser::is_timed_note(n)).collect();
+        let group = |id, numerator, denominator, start, end| TupletGroup { id, numerator, denominator, start, end };
+        
+        // The inner triplet is inside the outer bracket and takes the combined ratio
+        assert_eq!(notes[1].tuplets, vec![group(2, 3, 2, false, false), group(1, 3, 2, true, false)]);
+        assert_eq!(notes[3].tuplets, vec![group(2, 3, 2, false, false), group(1, 3, 2, false, true)]);
+        assert_eq!(notes[4].tuplets, vec![group(2, 3, 2, false, true)]);
+        assert_eq!(notes[1].tuplet_fraction.as_deref(), Some("9/4"));
+        // \times 2/3 is \tuplet 3/2
+        assert_eq!(notes[5].tuplets, vec![group(3, 3, 2, true, false)]);
+        
+        // A span of a quarter note splits six eighths into two brackets
+        let brackets = |range: std::ops::Range<usize>| -> Vec<(u32, bool, bool)> {
+            notes[range].iter().map(|n| (n.tuplets[0].id, n.tuplets[0].start, n.tuplets[0].end)).collect()
+        };
+        let expected = |first| vec![
+            (first, true, false), (first, false, false), (first, false, true),
+            (first + 1, true, false), (first + 1, false, false), (first + 1, false, true),
+        ];
+        assert_eq!(brackets(8..14), expected(4));
+        assert_eq!(brackets(14..20), expected(6));
+        
+        // Every measure is full with the combined ratios
+        assert_eq!(parsed.staves[0].measures.len(), 3);
+        assert!(!parsed.warnings.iter().any(|w| matches!(w.kind, WarningKind::MeasureUnderfull | WarningKind::MeasureOverfull | WarningKind::BarCheckFailed)));
+    }
+
+    #[test]
+    fn test_grace_note_timing() {
+        use lilypond_parser::GraceKind;
+        use performance::{performance_timeline, PerformanceOptions};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff { \tempo 4 = 60 c'2 \acciaccatura d'8 e'2 | \appoggiatura { f'8 } g'2. \appoggiatura f'8 e'4 | \slashedGrace a'16 b'1 }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse grace notes");
+        let graces: Vec<_> = parsed.staves[0].base.notes.iter()
+            .filter(|n| n.grace.is_some())
+            .map(|n| (n.pitch.clone(), n.grace, n.grace_slash))
+            .collect();
+        assert_eq!(graces, vec![
+            ("d".to_string(), Some(GraceKind::Acciaccatura), true),
+            ("f".to_string(), Some(GraceKind::Appoggiatura), false),
+            ("f".to_string(), Some(GraceKind::Appoggiatura), false),
+            ("a".to_string(), Some(GraceKind::Grace), true),
+        ]);
+        
+        // In milliseconds at a quarter = 60: acciaccaturas take a thirty-second from the note before,
+        // appoggiaturas half of the main note, or two thirds of a dotted one
+        let timeline = performance_timeline(&ApiParsedMusic::from(parsed), &PerformanceOptions::default());
+        let played: Vec<(u8, i64, i64)> = timeline.notes.iter()
+            .map(|n| (n.keys[0], (n.start * 1000.0).round() as i64, (n.duration * 1000.0).round() as i64))
+            .collect();
+        assert_eq!(played, vec![
+            (60, 0, 1875), (62, 1875, 125), (64, 2000, 2000),
+            (65, 4000, 2000), (67, 6000, 1000), (65, 7000, 500), (64, 7500, 375),
+            (69, 7875, 125), (71, 8000, 4000),
+        ]);
+        assert_eq!(timeline.duration, 12.0);
+    }
+
+    #[test]
+    fn test_ornament_realisation() {
+        use lilypond_parser::Ornament;
+        use ornaments::OrnamentStart;
+        use performance::{performance_timeline, PerformanceOptions};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff { \key g \major \tempo 4 = 60 g'4-\mordent e'4\prall c''4\turn ^\markup \sharp b'8\trill r8 | d'1\prallprall }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse ornaments");
+        let ornaments: Vec<_> = parsed.staves[0].base.notes.iter().filter_map(|n| n.ornament).collect();
+        assert_eq!(ornaments, vec![Ornament::Mordent, Ornament::Prall, Ornament::Turn, Ornament::Trill, Ornament::PrallPrall]);
+        
+        // In milliseconds at a quarter = 60, with quick notes of a thirty-second: in G major the note
+        // below g' is fis', and the sharp written with the turn makes its upper note dis''
+        let music = ApiParsedMusic::from(parsed);
+        let played = |options: &PerformanceOptions| -> Vec<(u8, i64, i64)> {
+            performance_timeline(&music, options).notes.iter()
+                .filter(|n| n.ornament && n.start < 4.0)
+                .map(|n| (n.keys[0], (n.start * 1000.0).round() as i64, (n.duration * 1000.0).round() as i64))
+                .collect()
+        };
+        assert_eq!(played(&PerformanceOptions::default()), vec![
+            (67, 0, 125), (66, 125, 125), (67, 250, 750),
+            (66, 1000, 125), (64, 1125, 125), (66, 1250, 125), (64, 1375, 625),
+            (75, 2000, 125), (72, 2125, 125), (71, 2250, 125), (72, 2375, 625),
+            (72, 3000, 125), (71, 3125, 125), (72, 3250, 125), (71, 3375, 125),
+        ]);
+        
+        // Starting on the written note, at a sixteenth
+        let options = PerformanceOptions { ornament_start: OrnamentStart::Main, ornament_speed: 1.0 / 16.0, ..Default::default() };
+        assert_eq!(played(&options)[3..6], [(64, 1000, 250), (66, 1250, 250), (64, 1500, 500)]);
+        assert_eq!(played(&options)[10..], [(71, 3000, 167), (72, 3167, 167), (71, 3333, 167)]);
+        
+        // English note names: fs is F sharp, bf B flat, in the notes and in the ornament's key
+        let test_content = r#"\version "2.24.0"
+\language "english"
+\score { \new Staff { \key d \major \tempo 4 = 60 fs'4 gs'4 cs''4 bf'4 | fs''4\mordent e-flat'4 dss'4 aff'4 } }"#;
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse English note names");
+        assert!(parsed.warnings.is_empty());
+        let timeline = performance_timeline(&ApiParsedMusic::from(parsed), &PerformanceOptions::default());
+        let keys: Vec<u8> = timeline.notes.iter().map(|n| n.keys[0]).collect();
+        assert_eq!(keys, vec![66, 68, 73, 70, 78, 76, 78, 63, 64, 67]);
+    }
+
+    #[test]
+    fn test_expressive_performance() {
+        use expression::ExpressionOptions;
+        use lilypond_parser::{Articulation, Fermata, Hairpin};
+        use performance::{performance_timeline, PerformanceOptions};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new Staff { \tempo 4 = 60 c'4\p\< d' e' f'\f | g'4-. a'-- b'( c''-> | d''2)\fermata r2 }
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse expressive marks");
+        let notes = &parsed.staves[0].base.notes;
+        let c = notes.iter().find(|n| n.pitch == "c" && n.octave == 4).unwrap();
+        assert_eq!((c.dynamic.as_deref(), c.hairpins.clone()), (Some("p"), vec![Hairpin::Crescendo]));
+        let last = notes.iter().find(|n| n.pitch == "d" && n.octave == 5).unwrap();
+        assert!(last.slur_end);
+        assert_eq!(last.fermata, Some(Fermata::Normal));
+        assert!(notes.iter().any(|n| n.articulations == vec![Articulation::Staccato]));
+        
+        // In milliseconds at a quarter = 60, velocities in hundredths: the crescendo rises from p to f,
+        // the staccato is halved, the slur overlaps and shortens its last note, which is held twice as long
+        let music = ApiParsedMusic::from(parsed);
+        let played = |options: &PerformanceOptions| -> Vec<(u8, i64, i64, i64)> {
+            performance_timeline(&music, options).notes.iter()
+                .map(|n| (n.keys[0], (n.start * 1000.0).round() as i64, (n.duration * 1000.0).round() as i64, (n.velocity * 100.0).round() as i64))
+                .collect()
+        };
+        assert_eq!(played(&PerformanceOptions::default()), vec![
+            (60, 0, 1000, 42), (62, 1000, 1000, 53), (64, 2000, 1000, 65), (65, 3000, 1000, 76),
+            (67, 4000, 500, 76), (69, 5000, 1000, 76), (71, 6000, 1030, 76), (72, 7000, 1030, 91),
+            (74, 8000, 3200, 76),
+        ]);
+        assert_eq!(performance_timeline(&music, &PerformanceOptions::default()).duration, 12.0);
+        
+        // Without dynamics, staccato or fermatas
+        let options = PerformanceOptions {
+            expression: ExpressionOptions { dynamics: false, staccato: 1.0, fermata: 1.0, ..Default::default() },
+            ..Default::default()
+        };
+        assert_eq!(played(&options)[..5], [(60, 0, 1000, 64), (62, 1000, 1000, 64), (64, 2000, 1000, 64), (65, 3000, 1000, 64), (67, 4000, 1000, 64)]);
+        assert_eq!(played(&options)[8], (74, 8000, 1600, 64));
+    }
+
+    #[test]
+    fn test_piano_pedals() {
+        use lilypond_parser::PedalEvent;
+        use pedals::Pedal;
+        use performance::{performance_timeline, PerformanceOptions};
+        
+        let test_content = r#"\version "2.24.0"
+\score {
+  \new PianoStaff <<
+    \new Staff { \tempo 4 = 60 e'4 g' c''2 | d''1 }
+    \new Staff { \clef bass c4\sustainOn e g c'\sustainOff\sustainOn | g,1\sustainOff\unaCorda }
+  >>
+}"#;
+        
+        let parsed = lilypond_parser::parse_lilypond(test_content).expect("Failed to parse pedals");
+        let pedals: Vec<_> = parsed.staves[1].base.notes.iter().filter(|n| !n.pedals.is_empty()).map(|n| n.pedals.clone()).collect();
+        assert_eq!(pedals, vec![
+            vec![PedalEvent::SustainOn],
+            vec![PedalEvent::SustainOff, PedalEvent::SustainOn],
+            vec![PedalEvent::SustainOff, PedalEvent::UnaCorda],
+        ]);
+        
+        // In milliseconds at a quarter = 60: the pedal of the left hand also holds the right hand,
+        // and goes down a little after each change so that the notes before it are let go
+        let timeline = performance_timeline(&ApiParsedMusic::from(parsed), &PerformanceOptions::default());
+        let changes: Vec<(i64, Pedal, bool)> = timeline.pedals.iter()
+            .map(|c| ((c.time * 1000.0).round() as i64, c.pedal, c.down))
+            .collect();
+        assert_eq!(changes, vec![
+            (50, Pedal::Sustain, true), (3000, Pedal::Sustain, false), (3050, Pedal::Sustain, true),
+            (4000, Pedal::Sustain, false), (4000, Pedal::UnaCorda, true),
+        ]);
+        assert!(timeline.pedals.iter().all(|c| c.staves == (0..2)));
+        let played: Vec<(u8, i64, i64, bool)> = timeline.notes.iter()
+            .map(|n| (n.keys[0], (n.start * 1000.0).round() as i64, (n.sounding * 1000.0).round() as i64, n.soft))
+            .collect();
+        assert_eq!(played, vec![
+            (64, 0, 3000, false), (48, 0, 3000, false),
+            (67, 1000, 2000, false), (52, 1000, 2000, false),
+            (72, 2000, 2000, false), (55, 2000, 1000, false),
+            (60, 3000, 1000, false),
+            (74, 4000, 4000, true), (43, 4000, 4000, true),
+        ]);
+    }
+}
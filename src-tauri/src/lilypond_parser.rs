@@ -1,7 +1,15 @@
 use pest::Parser;
 use pest_derive::Parser;
 use serde::{Serialize, Deserialize};
-use std::{collections::HashMap};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use crate::markup::{flatten_markup, parse_markup, Markup, StyledText};
+use crate::music_function::MusicFunction;
+use crate::properties::{PropertyAction, PropertyEvent};
+use crate::scheme::{SchemeEnv, SchemeError, SchemeValue};
+use crate::performance::Timeline;
+use crate::playback::performance_order;
+use crate::pitch::{assign_accidentals, key_signature_name, parse_note_name, transpose_key_name, transpose_pitch, PrintedAccidental};
 
 #[derive(Parser)]
 #[grammar = "lilypond.pest"]
@@ -42,7 +50,7 @@ pub enum ScriptDirection {
 pub enum ScriptContent {
     Fingering(u32),           // Fingering number like -1, -2, ^3
     Text(String),             // String literal like ^"text"
-    Markup(String),           // Markup expression like ^\markup { "text" }
+    Markup(Markup),           // Markup expression like ^\markup { "text" }
     Articulation(String),     // Articulation mark like ^., ^-, etc.
     Empty,                    // Just direction without content
 }
@@ -54,7 +62,137 @@ pub struct ScriptAttachment {
     pub content: ScriptContent,
 }
 
+// Byte range of a construct in the source file
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub struct SourceSpan {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl SourceSpan {
+    pub fn from_pair(pair: &pest::iterators::Pair<Rule>) -> Self {
+        let span = pair.as_span();
+        Self { start: span.start(), end: span.end() }
+    }
+}
+
+// Bar check attached to the note it follows: `|` (bar_number None) or `\barNumberCheck #n`
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BarCheck {
+    pub bar_number: Option<u32>,
+    pub span: SourceSpan,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum WarningKind {
+    BarCheckFailed,
+    BarNumberCheckFailed,
+    MeasureUnderfull,
+    MeasureOverfull,
+    OctaveCheckFailed,
+    UndefinedVariable,
+    UnknownCommand,
+    CircularVariable,
+    MusicFunctionError,
+    InvalidScheme,
+    UndefinedStaff,
+    MeasureCountMismatch,
+    MeasureOffGrid,
+}
+
+// Non-fatal problem found while parsing or organizing the music
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParseWarning {
+    pub kind: WarningKind,
+    pub message: String,
+    pub span: Option<SourceSpan>,
+    pub line: usize,    // 1-based, filled in from span once parsing is done
+    pub column: usize,  // 1-based, filled in from span once parsing is done
+}
+
+impl ParseWarning {
+    pub fn new(kind: WarningKind, message: String, span: Option<SourceSpan>) -> Self {
+        Self { kind, message, span, line: 0, column: 0 }
+    }
+}
+
+// Where playback stops after a D.C./D.S. jump
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum JumpEnd {
+    End,   // play to the end (plain D.C. / D.S.)
+    Fine,  // al Fine
+    Coda,  // al Coda: take the To Coda jump on the way
+}
+
+// Navigation marks for playback order
+// Segno and Coda mark the note a jump lands on, the others take effect after the note
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum NavigationMark {
+    Segno,
+    Coda,
+    ToCoda,
+    Fine,
+    DaCapo(JumpEnd),
+    DalSegno(JumpEnd),
+}
+
+impl NavigationMark {
+    // True for Segno and Coda, which belong to the note that follows them in the source
+    pub fn is_target(&self) -> bool {
+        matches!(self, NavigationMark::Segno | NavigationMark::Coda)
+    }
+
+    // Recognize common text marks: "D.C. al Fine", "D.S. al Coda", "To Coda", "Fine", ...
+    pub fn from_text(text: &str) -> Option<Self> {
+        let text = text.trim().to_lowercase();
+        let end = if text.contains("al fine") {
+            JumpEnd::Fine
+        } else if text.contains("al coda") {
+            JumpEnd::Coda
+        } else {
+            JumpEnd::End
+        };
+        if text.starts_with("d.s.") || text.starts_with("d. s.") || text.starts_with("dal segno") {
+            Some(NavigationMark::DalSegno(end))
+        } else if text.starts_with("d.c.") || text.starts_with("d. c.") || text.starts_with("da capo") {
+            Some(NavigationMark::DaCapo(end))
+        } else if text.starts_with("to coda") || text == "al coda" {
+            Some(NavigationMark::ToCoda)
+        } else if text == "fine" {
+            Some(NavigationMark::Fine)
+        } else if text == "coda" {
+            Some(NavigationMark::Coda)
+        } else if text == "segno" {
+            Some(NavigationMark::Segno)
+        } else {
+            None
+        }
+    }
+
+    // Mark written as markup: the segno or coda glyph, or the text
+    pub fn from_markup(markup: &Markup) -> Option<Self> {
+        // \musicglyph #"scripts.segno", or \segno since LilyPond 2.24
+        let sign = match markup {
+            Markup::Command { name, markup: None, .. } => Some(name.as_str()),
+            _ => markup.glyphs().first().map(|glyph| glyph.trim_start_matches("scripts.")),
+        };
+        match sign {
+            Some("segno") => Some(NavigationMark::Segno),
+            Some("coda") | Some("varcoda") => Some(NavigationMark::Coda),
+            _ => Self::from_text(&markup.to_styled_text().text),
+        }
+    }
+}
+
+// Tremolo strokes on a note; the value is the note value of the strokes (16 = sixteenths)
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Tremolo {
+    Stem(u32),   // c4:16, or \repeat tremolo over a single note
+    Start(u32),  // First note of a two-note measured tremolo (\repeat tremolo 8 { c16 d })
+    End(u32),    // Second note of a two-note measured tremolo
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct LilyPondNote {
     pub pitch: String,
     pub duration: String,
@@ -82,7 +220,212 @@ pub struct LilyPondNote {
     #[serde(default)]
     pub alternative_index: Vec<i32>,  // Alternative index (for alternative endings)
     #[serde(default)]
-    pub tuplet_fraction: Option<String>,  // Tuplet fraction (e.g., "3/2" for triplet, "5/4" for quintuplet)
+    pub tuplet_fraction: Option<String>,  // Tuplet fraction (e.g., "3/2" for triplet, "5/4" for quintuplet); for nested tuplets, the product of their ratios (e.g., "9/4")
+    #[serde(default)]
+    pub span: Option<SourceSpan>,  // Source location of the note (None for generated markers)
+    #[serde(default)]
+    pub bar_checks: Vec<BarCheck>,  // Bar checks written right after this note
+    #[serde(default)]
+    pub barline: Option<BarlineType>,  // Explicit barline (\bar "...") written right after this note
+    #[serde(default)]
+    pub navigation: Vec<NavigationMark>,  // Segno/Coda/D.C./D.S./Fine marks (see NavigationMark)
+    #[serde(default)]
+    pub tremolo: Option<Tremolo>,  // Stem or measured tremolo
+    #[serde(default)]
+    pub percent_repeat: Option<u32>,  // Pass number (2, 3, ...) of notes repeated by \repeat percent
+    #[serde(default)]
+    pub accidentals: Vec<Option<PrintedAccidental>>,  // Printed accidental per note head (pitch, then chord_notes)
+    #[serde(default)]
+    pub properties: Vec<PropertyEvent>,  // \set, \override ... written just before this note
+    #[serde(default)]
+    pub stem_direction: Option<StemDirection>,  // From \voiceOne, \stemUp, Stem.direction ...; None is automatic
+    #[serde(default)]
+    pub voice: Option<u32>,  // Part number inside << { } \\ { } >> (1 is the main voice)
+    #[serde(default)]
+    pub spacer: bool,  // Spacer rest (s, or padding of a temporary voice): takes time, prints nothing
+    #[serde(default)]
+    pub staff_change: Option<String>,  // \change Staff = "name" (or \autoChange) written just before this note
+    #[serde(default)]
+    pub display_staff: Option<usize>,  // Staff the note is drawn on when moved off its own (index among its score's staves)
+    #[serde(default)]
+    pub tuplets: Vec<TupletGroup>,  // Tuplet brackets around the note, outermost first
+    #[serde(default)]
+    pub grace: Option<GraceKind>,  // Set on grace notes (note_type Grace)
+    #[serde(default)]
+    pub grace_slash: bool,  // Slash through the stem (\acciaccatura, \slashedGrace)
+    #[serde(default)]
+    pub ornament: Option<Ornament>,  // \trill, \mordent, \turn ... written on the note (see ornaments.rs for how they are played)
+    #[serde(default)]
+    pub articulations: Vec<Articulation>,  // \staccato, -. , \accent, -> ... in written order
+    #[serde(default)]
+    pub fermata: Option<Fermata>,
+    #[serde(default)]
+    pub dynamic: Option<String>,  // Dynamic mark written on the note, without backslash: "p", "mf", "sfz" ...
+    #[serde(default)]
+    pub hairpins: Vec<Hairpin>,  // \<, \> and \! written after the note
+    #[serde(default)]
+    pub slur_start: bool,  // A slur starts on this note: c4( (unlike group_start, not set by ties)
+    #[serde(default)]
+    pub slur_end: bool,  // A slur ends on this note: c4)
+    #[serde(default)]
+    pub pedals: Vec<PedalEvent>,  // \sustainOn, \sustainOff ... written on the note, in written order
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum StemDirection {
+    Up,
+    Down,
+}
+
+/// Kind of grace note; they are played differently (see performance.rs)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GraceKind {
+    Grace,         // \grace, or \slashedGrace with a slash
+    Acciaccatura,  // \acciaccatura: slashed and slurred, played quickly before the beat
+    Appoggiatura,  // \appoggiatura: slurred, takes part of the main note's time on the beat
+}
+
+/// Ornament written on a note or chord
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Ornament {
+    Trill,         // \trill
+    Prall,         // \prall: short trill
+    PrallPrall,    // \prallprall: longer short trill
+    PrallMordent,  // \prallmordent
+    PrallUp,       // \prallup
+    PrallDown,     // \pralldown
+    UpPrall,       // \upprall: short trill from below
+    DownPrall,     // \downprall: short trill from above
+    LinePrall,     // \lineprall
+    Mordent,       // \mordent: with the note below
+    UpMordent,     // \upmordent
+    DownMordent,   // \downmordent
+    Turn,          // \turn
+    ReverseTurn,   // \reverseturn
+    SlashTurn,     // \slashturn
+    HaydnTurn,     // \haydnturn
+}
+
+impl Ornament {
+    /// The ornament of a command name without its backslash, e.g. "prallprall"
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "trill" => Ornament::Trill,
+            "prall" => Ornament::Prall,
+            "prallprall" => Ornament::PrallPrall,
+            "prallmordent" => Ornament::PrallMordent,
+            "prallup" => Ornament::PrallUp,
+            "pralldown" => Ornament::PrallDown,
+            "upprall" => Ornament::UpPrall,
+            "downprall" => Ornament::DownPrall,
+            "lineprall" => Ornament::LinePrall,
+            "mordent" => Ornament::Mordent,
+            "upmordent" => Ornament::UpMordent,
+            "downmordent" => Ornament::DownMordent,
+            "turn" => Ornament::Turn,
+            "reverseturn" => Ornament::ReverseTurn,
+            "slashturn" => Ornament::SlashTurn,
+            "haydnturn" => Ornament::HaydnTurn,
+            _ => return None,
+        })
+    }
+}
+
+/// Articulation written on a note, as a command or a shorthand (-. -> -- ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Articulation {
+    Accent,         // \accent, ->
+    Marcato,        // \marcato, -^
+    Staccato,       // \staccato, -.
+    Staccatissimo,  // \staccatissimo, -!
+    Tenuto,         // \tenuto, --
+    Portato,        // \portato, -_
+    Espressivo,     // \espressivo
+}
+
+impl Articulation {
+    /// The articulation of a command name without its backslash ("staccato"), or of a shorthand (".")
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "accent" | ">" => Articulation::Accent,
+            "marcato" | "^" => Articulation::Marcato,
+            "staccato" | "." => Articulation::Staccato,
+            "staccatissimo" | "!" => Articulation::Staccatissimo,
+            "tenuto" | "-" => Articulation::Tenuto,
+            "portato" | "_" => Articulation::Portato,
+            "espressivo" => Articulation::Espressivo,
+            _ => return None,
+        })
+    }
+}
+
+/// Fermata written on a note or rest, from the shortest to the longest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fermata {
+    VeryShort,   // \veryshortfermata
+    Short,       // \shortfermata, \henzeshortfermata
+    Normal,      // \fermata
+    Long,        // \longfermata, \henzelongfermata
+    VeryLong,    // \verylongfermata
+}
+
+impl Fermata {
+    /// The fermata of a command name without its backslash, e.g. "longfermata"
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "veryshortfermata" => Fermata::VeryShort,
+            "shortfermata" | "henzeshortfermata" => Fermata::Short,
+            "fermata" => Fermata::Normal,
+            "longfermata" | "henzelongfermata" => Fermata::Long,
+            "verylongfermata" => Fermata::VeryLong,
+            _ => return None,
+        })
+    }
+}
+
+/// Piano pedal pressed or released at the start of a note
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PedalEvent {
+    SustainOn,
+    SustainOff,
+    SostenutoOn,
+    SostenutoOff,
+    UnaCorda,
+    TreCorde,
+}
+
+impl PedalEvent {
+    /// The pedal event of a command name without its backslash, e.g. "sustainOn"
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sustainOn" => PedalEvent::SustainOn,
+            "sustainOff" => PedalEvent::SustainOff,
+            "sostenutoOn" => PedalEvent::SostenutoOn,
+            "sostenutoOff" => PedalEvent::SostenutoOff,
+            "unaCorda" => PedalEvent::UnaCorda,
+            "treCorde" => PedalEvent::TreCorde,
+            _ => return None,
+        })
+    }
+}
+
+/// Hairpin event written after a note: \< and \> start a crescendo or decrescendo, \! ends it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hairpin {
+    Crescendo,
+    Decrescendo,
+    Stop,
+}
+
+/// One tuplet bracket a note is in: \tuplet 3/2 { ... } (or \times 2/3 { ... }) is 3 notes in the time of 2.
+/// A note inside nested tuplets has one group per bracket, outermost first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TupletGroup {
+    pub id: u32,           // Same for every note of one bracket
+    pub numerator: u32,    // 3 in \tuplet 3/2
+    pub denominator: u32,  // 2 in \tuplet 3/2
+    pub start: bool,       // First note of the bracket
+    pub end: bool,         // Last note of the bracket
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -118,22 +461,250 @@ pub struct MusicContainerBase {
     pub time_signature: Option<String>,
     pub key_signature: Option<String>,
     pub notes: Vec<LilyPondNote>,
+    #[serde(default)]
+    pub instrument: InstrumentNames,
+}
+
+/// Labels at the left of a staff or group: `instrumentName` on the first system,
+/// `shortInstrumentName` on the following ones. Markup is kept as its text
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentNames {
+    pub name: Option<String>,
+    pub short_name: Option<String>,
+}
+
+impl InstrumentNames {
+    // Names set later replace earlier ones
+    fn merge(&mut self, later: InstrumentNames) {
+        if later.name.is_some() {
+            self.name = later.name;
+        }
+        if later.short_name.is_some() {
+            self.short_name = later.short_name;
+        }
+    }
+
+    // Sets instrumentName or shortInstrumentName; false for any other property
+    fn set(&mut self, property: &str, text: String) -> bool {
+        match property {
+            "instrumentName" => self.name = Some(text),
+            "shortInstrumentName" => self.short_name = Some(text),
+            _ => return false,
+        }
+        true
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub base: MusicContainerBase,
     pub lyric: Option<Lyric>,
+    pub own_pitch_mode: bool,  // True if the music was entered in \relative, \fixed or \absolute
+    pub value: VariableValue,
+    pub properties: Vec<PropertyEvent>,  // Set after its last note, for the music that follows it
+}
+
+// What a variable holds. Music keeps its notes in Variable.base, lyrics in Variable.lyric
+#[derive(Debug, Clone)]
+pub enum VariableValue {
+    Music,
+    // << >> of staves, parsed where it is defined: the staves, their named voices and staff groups
+    SimultaneousMusic { staves: Vec<Staff>, voices: HashMap<String, (usize, usize)>, groups: Vec<StaffGroup> },
+    Lyrics,
+    Markup(StyledText),           // Flattened text of the markup
+    Number(f64),
+    String(String),
+    ContextModification(String),  // Body of \with { ... }
+    MusicFunction(MusicFunction), // define-music-function with a #{ #} template body
+    SchemeValue(SchemeValue),     // Scheme expression evaluated to data
+    Scheme(String),               // Scheme expression outside what we can evaluate
+    Command(String),              // \set, \override or a function call, kept as written
 }
+
+impl VariableValue {
+    // The variable as seen from Scheme, e.g. #(* 2 myNumber)
+    fn to_scheme(&self) -> Option<SchemeValue> {
+        match self {
+            VariableValue::Number(n) if n.fract() == 0.0 => Some(SchemeValue::Integer(*n as i64)),
+            VariableValue::Number(n) => Some(SchemeValue::Real(*n)),
+            VariableValue::String(text) => Some(SchemeValue::String(text.clone())),
+            VariableValue::SchemeValue(value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+// Barline drawn at the end of a measure
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub enum BarlineType {
+    #[default]
+    Single,          // |
+    Double,          // ||
+    Final,           // |.
+    StartRepeat,     // .|:
+    EndRepeat,       // :|.
+    EndStartRepeat,  // :..:
+    Dashed,          // !
+    Invisible,       // ""
+}
+
+impl BarlineType {
+    // Map a LilyPond bar string (the argument of \bar) to a barline type
+    pub fn from_lilypond(bar: &str) -> Self {
+        let starts_repeat = bar.ends_with(':');
+        let ends_repeat = bar.starts_with(':');
+        match bar {
+            "" => BarlineType::Invisible,
+            "|" => BarlineType::Single,
+            "||" | ".|" | ".." => BarlineType::Double,
+            "|." | "|.|" | "." => BarlineType::Final,
+            "!" | ";" => BarlineType::Dashed,
+            _ if starts_repeat && ends_repeat => BarlineType::EndStartRepeat,
+            _ if starts_repeat => BarlineType::StartRepeat,
+            _ if ends_repeat => BarlineType::EndRepeat,
+            _ => BarlineType::Single,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Measure {
     pub notes: Vec<u32>,
+    #[serde(default)]
+    pub barline: BarlineType,  // Barline at the end of this measure
+    #[serde(default)]
+    pub grid: Option<usize>,  // Index into the score's measure grid; None if no grid measure starts with it
+}
+
+/// A measure of the grid shared by every staff of a score
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GridMeasure {
+    pub number: u32,  // Bar number (0 for a pickup)
+    pub start: f64,   // Moment of its first beat, in whole notes from the start of the score
+    pub length: f64,  // Written length in whole notes: the pickup or the time signature
 }
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Voice {
     pub base: MusicContainerBase,
     pub lyrics: Vec<Lyric>,
     pub measures: Vec<Measure>,
+    #[serde(default)]
+    pub performance_order: Vec<u32>,  // Note indices in playing order (repeats and jumps unrolled)
+    #[serde(default)]
+    pub number: Option<u32>,  // Voice number of << { } \\ { } >> passages (1 continues the music around them)
+}
+
+/// Value of a \header field
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum HeaderValue {
+    Text(String),
+    Markup(StyledText),
+    Boolean(bool), // ##f hides the field, including one inherited from an outer header
+}
+
+impl HeaderValue {
+    /// The value as plain text; None for ##t/##f
+    pub fn plain_text(&self) -> Option<String> {
+        match self {
+            HeaderValue::Text(text) => Some(text.clone()),
+            HeaderValue::Markup(markup) => Some(markup.text.clone()),
+            HeaderValue::Boolean(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeaderField {
+    pub name: String,
+    pub value: HeaderValue,
+}
+
+/// Fields of a \header block in the order they were written
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub fields: Vec<HeaderField>,
+}
+
+impl Header {
+    pub fn get(&self, name: &str) -> Option<&HeaderValue> {
+        self.fields.iter().find(|field| field.name == name).map(|field| &field.value)
+    }
+
+    /// Plain text of a field ("title", "composer", "piece", ...)
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.get(name).and_then(HeaderValue::plain_text)
+    }
+
+    // A field written again replaces the earlier value in its place
+    fn set(&mut self, name: String, value: HeaderValue) {
+        match self.fields.iter_mut().find(|field| field.name == name) {
+            Some(field) => field.value = value,
+            None => self.fields.push(HeaderField { name, value }),
+        }
+    }
+
+    // This header's fields, followed by those of the enclosing header it does not set
+    fn over(&self, outer: &Header) -> Header {
+        let mut merged = self.clone();
+        for field in &outer.fields {
+            if self.get(&field.name).is_none() {
+                merged.fields.push(field.clone());
+            }
+        }
+        merged
+    }
+}
+
+/// One \score of the file: a movement with its own header, settings and staves
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Score {
+    pub header: Header,  // Its own fields over those of its book part, book and file
+    pub tempo: Option<String>,  // \tempo "Allegro"
+    pub tempo_bpm: Option<f64>,
+    pub key_signature: Option<String>,
+    pub time_signature: Option<String>,
+    pub partial: Option<String>,
+    pub staves: Range<usize>,  // Indices into ParsedMusic.staves
+    #[serde(default)]
+    pub staff_groups: Vec<StaffGroup>,  // Outermost groups; their ranges also index ParsedMusic.staves
+    #[serde(default)]
+    pub measure_grid: Vec<GridMeasure>,  // Measures shared by its staves, filled in by organize_measures
+}
+
+/// A \bookpart, or the scores written directly in a \book
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookPart {
+    pub header: Header,
+    pub scores: Vec<Score>,
+    #[serde(skip)]
+    implicit: bool, // Not written as \bookpart: later scores outside a \bookpart join it
+}
+
+/// A \book. Scores and book parts outside any \book form one implicit book, as in LilyPond
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Book {
+    pub header: Header,
+    pub parts: Vec<BookPart>,
+}
+
+impl Book {
+    fn add_score(&mut self, score: Score) {
+        match self.parts.last_mut() {
+            Some(part) if part.implicit => part.scores.push(score),
+            _ => self.parts.push(BookPart { scores: vec![score], implicit: true, ..Default::default() }),
+        }
+    }
+}
+
+/// A score as listed for choosing what to show; `index` counts scores in file order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Movement {
+    pub index: usize,
+    pub book: usize,
+    pub part: usize,
+    pub title: Option<String>,
+    pub staff_count: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -142,6 +713,8 @@ pub struct Staff {
     pub base: MusicContainerBase,
     pub voices: Vec<Voice>,
     pub measures: Vec<Measure>,
+    #[serde(default)]
+    pub performance_order: Vec<u32>,  // Note indices in playing order (repeats and jumps unrolled)
 }
 
 impl Staff {
@@ -153,9 +726,11 @@ impl Staff {
                 time_signature: None,
                 key_signature: None,
                 notes: Vec::new(),
+                instrument: InstrumentNames::default(),
             },
             voices: Vec::new(),
             measures: Vec::new(),
+            performance_order: Vec::new(),
         }
     }
 }
@@ -166,6 +741,66 @@ impl Default for Staff {
     }
 }
 
+/// Context that groups staves
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StaffGroupType {
+    PianoStaff,
+    GrandStaff,
+    ChoirStaff,
+    StaffGroup,
+}
+
+/// How the staves of a group are joined at the start of each system
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GroupDelimiter {
+    Brace,
+    Bracket,
+    Square,
+    Bar,   // Only the span bar line
+}
+
+impl StaffGroupType {
+    fn from_lilypond(name: &str) -> Option<Self> {
+        match name {
+            "PianoStaff" => Some(StaffGroupType::PianoStaff),
+            "GrandStaff" => Some(StaffGroupType::GrandStaff),
+            "ChoirStaff" => Some(StaffGroupType::ChoirStaff),
+            "StaffGroup" => Some(StaffGroupType::StaffGroup),
+            _ => None,
+        }
+    }
+
+    fn delimiter(self) -> GroupDelimiter {
+        match self {
+            StaffGroupType::PianoStaff | StaffGroupType::GrandStaff => GroupDelimiter::Brace,
+            StaffGroupType::ChoirStaff | StaffGroupType::StaffGroup => GroupDelimiter::Bracket,
+        }
+    }
+}
+
+/// A \new PianoStaff, GrandStaff, ChoirStaff or StaffGroup with the groups nested in it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaffGroup {
+    pub group_type: StaffGroupType,
+    pub name: Option<String>,
+    pub delimiter: GroupDelimiter,  // From the group type, or systemStartDelimiter in \with
+    pub instrument: InstrumentNames,
+    pub staves: Range<usize>,       // Indices into the staves, including those of nested groups
+    pub groups: Vec<StaffGroup>,
+}
+
+impl StaffGroup {
+    // The same group for staves that moved by `offset` places
+    fn shifted(&self, offset: isize) -> StaffGroup {
+        let shift = |i: usize| (i as isize + offset) as usize;
+        StaffGroup {
+            staves: shift(self.staves.start)..shift(self.staves.end),
+            groups: self.groups.iter().map(|group| group.shifted(offset)).collect(),
+            ..self.clone()
+        }
+    }
+}
+
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ParsedMusic {
@@ -175,13 +810,49 @@ pub struct ParsedMusic {
     pub key_signature: Option<String>,
     pub time_signature: Option<String>,
     pub partial: Option<String>,  // Pickup measure duration (e.g., "8", "4", "16")
+    #[serde(default)]
+    pub tempo_bpm: Option<f64>,  // Quarter notes per minute, from \tempo 4 = 120 or tempoWholesPerMinute
+    #[serde(default)]
+    pub global_staff_size: Option<f64>,  // #(set-global-staff-size 18)
     pub language: Option<String>,
-    pub staves: Vec<Staff>,
+    #[serde(default)]
+    pub header: Header,  // Top-level \header, inherited by every book
+    #[serde(default)]
+    pub books: Vec<Book>,
+    pub staves: Vec<Staff>,  // Staves of every score; each Score refers to its own range
+    #[serde(default)]
+    pub staff_groups: Vec<StaffGroup>,  // Outermost staff groups of every score
+    #[serde(default)]
+    pub measure_grid: Vec<GridMeasure>,  // Measure grid of the staves when the file has no \score
     pub music_mode: Option<MusicMode>,
+    #[serde(default)]
+    pub warnings: Vec<ParseWarning>,
     #[serde(skip)]
     pub variables: HashMap<String, Variable>,
     #[serde(skip)]
+    pub defined_names: HashSet<String>, // Every variable the file defines somewhere
+    #[serde(skip)]
     pub voices: HashMap<String, (usize, usize)>, // (staff_index, voice_index)
+    #[serde(skip)]
+    pub pending_navigation: Vec<NavigationMark>, // \segnoMark etc. waiting for the next note
+    #[serde(skip)]
+    pub open_groups: Vec<StaffGroup>, // Staff groups being parsed, innermost last
+    #[serde(skip)]
+    pub pending_instrument: InstrumentNames, // \set Staff.instrumentName waiting for the staff or variable it is in
+    #[serde(skip)]
+    pub pending_properties: Vec<PropertyEvent>, // \set, \override ... waiting for the next note
+    #[serde(skip)]
+    pub pending_staff_change: Option<String>, // \change Staff waiting for the next note
+    #[serde(skip)]
+    pub tuplet_span: Option<f64>, // \tupletSpan in whole notes, for \tuplet without its own duration
+    #[serde(skip)]
+    pub tuplet_count: u32, // Tuplet brackets made so far, for their ids
+    #[serde(skip)]
+    pub expansion_depth: usize, // Music function calls being expanded, to stop runaway recursion
+    #[serde(skip)]
+    pub scheme: SchemeEnv, // Names defined with #(define ...)
+    #[serde(skip)]
+    toplevel_book: Option<usize>, // Index in books of the implicit book, once there is one
 }
 
 // API-friendly version for frontend (with simplified music_mode as string)
@@ -193,14 +864,28 @@ pub struct ApiParsedMusic {
     pub key_signature: Option<String>,
     pub time_signature: Option<String>,
     pub partial: Option<String>,  // Pickup measure duration (e.g., "8", "4", "16")
+    pub tempo_bpm: Option<f64>,  // Quarter notes per minute
+    pub global_staff_size: Option<f64>,
     pub language: Option<String>,
+    pub header: Header,
     pub staves: Vec<Staff>,
+    pub staff_groups: Vec<StaffGroup>,
+    pub measure_grid: Vec<GridMeasure>,
+    #[serde(default)]
+    pub timeline: Timeline,  // The music as played; filled in by the parse commands
     pub music_mode: Option<String>, // Simplified as string for frontend
+    pub warnings: Vec<ParseWarning>,
+    pub movements: Vec<Movement>,
+    pub movement: Option<usize>, // Index of the movement shown, None when the file has no \score
 }
 
-// Convert from internal ParsedMusic to API-friendly version
+// Convert from internal ParsedMusic to API-friendly version: the first movement, as older callers
+// expect a single score
 impl From<ParsedMusic> for ApiParsedMusic {
     fn from(parsed: ParsedMusic) -> Self {
+        if parsed.scores().next().is_some() {
+            return Self::movement(parsed, 0).expect("the first movement exists");
+        }
         Self {
             title: parsed.title,
             composer: parsed.composer,
@@ -208,14 +893,88 @@ impl From<ParsedMusic> for ApiParsedMusic {
             key_signature: parsed.key_signature,
             time_signature: parsed.time_signature,
             partial: parsed.partial,
+            tempo_bpm: parsed.tempo_bpm,
+            global_staff_size: parsed.global_staff_size,
             language: parsed.language,
+            header: parsed.header,
             staves: parsed.staves,
+            staff_groups: parsed.staff_groups,
+            measure_grid: parsed.measure_grid,
+            timeline: Timeline::default(),
             music_mode: parsed.music_mode.map(|mode| format!("{:?}", mode)),
+            warnings: parsed.warnings,
+            movements: Vec::new(),
+            movement: None,
         }
     }
 }
 
+impl ApiParsedMusic {
+    /// Single-score view of one movement (see `ParsedMusic::movements`)
+    pub fn movement(mut parsed: ParsedMusic, index: usize) -> Result<Self, String> {
+        let movements = parsed.movements();
+        let score = parsed.scores().nth(index).cloned()
+            .ok_or_else(|| format!("Movement {} does not exist; the file has {}", index + 1, movements.len()))?;
+        let staves = parsed.staves.drain(score.staves.clone()).collect();
+        Ok(Self {
+            title: score.header.text("title"),
+            composer: score.header.text("composer"),
+            tempo: score.tempo.or_else(|| score.header.text("tempo")),
+            key_signature: score.key_signature,
+            time_signature: score.time_signature,
+            partial: score.partial,
+            tempo_bpm: score.tempo_bpm,
+            global_staff_size: parsed.global_staff_size,
+            language: parsed.language,
+            header: score.header,
+            staves,
+            // Ranges relative to the movement's own staves
+            staff_groups: score.staff_groups.iter().map(|group| group.shifted(-(score.staves.start as isize))).collect(),
+            measure_grid: score.measure_grid,
+            timeline: Timeline::default(),
+            music_mode: parsed.music_mode.map(|mode| format!("{:?}", mode)),
+            warnings: parsed.warnings,
+            movements,
+            movement: Some(index),
+        })
+    }
+}
+
 impl ParsedMusic {
+    /// Every \score of the file, in order
+    pub fn scores(&self) -> impl Iterator<Item = &Score> {
+        self.books.iter().flat_map(|book| book.parts.iter()).flat_map(|part| part.scores.iter())
+    }
+
+    /// The scores of the file as movements to choose from
+    pub fn movements(&self) -> Vec<Movement> {
+        let mut movements = Vec::new();
+        for (book_index, book) in self.books.iter().enumerate() {
+            for (part_index, part) in book.parts.iter().enumerate() {
+                for score in &part.scores {
+                    movements.push(Movement {
+                        index: movements.len(),
+                        book: book_index,
+                        part: part_index,
+                        // A movement is usually named by its piece, the book by its title
+                        title: score.header.text("piece").or_else(|| score.header.text("title")),
+                        staff_count: score.staves.len(),
+                    });
+                }
+            }
+        }
+        movements
+    }
+
+    // The book that top-level scores and book parts go into
+    fn toplevel_book(&mut self) -> &mut Book {
+        let index = *self.toplevel_book.get_or_insert_with(|| {
+            self.books.push(Book::default());
+            self.books.len() - 1
+        });
+        &mut self.books[index]
+    }
+
     pub fn new() -> Self {
         Self {
             title: None,
@@ -224,11 +983,29 @@ impl ParsedMusic {
             key_signature: None,
             time_signature: None,
             partial: None,
-            language: Some("english".to_string()),
+            tempo_bpm: None,
+            global_staff_size: None,
+            language: None,  // No \language: LilyPond's default, nederlands
+            header: Header::default(),
+            books: Vec::new(),
             staves: Vec::new(),
             music_mode: None,
+            warnings: Vec::new(),
             variables: HashMap::new(),
+            defined_names: HashSet::new(),
             voices: HashMap::new(),
+            pending_navigation: Vec::new(),
+            open_groups: Vec::new(),
+            pending_instrument: InstrumentNames::default(),
+            pending_properties: Vec::new(),
+            pending_staff_change: None,
+            tuplet_span: None,
+            tuplet_count: 0,
+            staff_groups: Vec::new(),
+            measure_grid: Vec::new(),
+            expansion_depth: 0,
+            scheme: SchemeEnv::new(),
+            toplevel_book: None,
         }
     }
 }
@@ -250,23 +1027,86 @@ pub fn parse_lilypond(content: &str) -> Result<ParsedMusic, String> {
         }
     }
 
+    inherit_headers(&mut parsed);
+    keep_last_score_settings(&mut parsed);
+
     // 组织音符成小节
     organize_measures(&mut parsed)?;
 
+    locate_warnings(content, &mut parsed.warnings);
+
     Ok(parsed)
 }
 
+// Fill in 1-based line/column of each warning from its byte span
+fn locate_warnings(content: &str, warnings: &mut [ParseWarning]) {
+    for warning in warnings.iter_mut() {
+        if let Some(span) = warning.span {
+            let before = &content[..span.start.min(content.len())];
+            warning.line = before.matches('\n').count() + 1;
+            warning.column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        }
+    }
+}
+
 fn parse_lilypond_file(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
-    for inner_pair in pair.into_inner() {
-        match inner_pair.as_rule() {
-            Rule::top_level_item => parse_top_level_item(inner_pair, parsed)?,
-            Rule::EOI => break,
-            _ => {}
+    let items: Vec<_> = pair.into_inner().filter(|p| p.as_rule() == Rule::top_level_item).collect();
+    parsed.defined_names.extend(items.iter().filter_map(defined_variable));
+    // Items already parsed because an earlier item referenced the variable they define
+    let mut done = HashSet::new();
+    for index in 0..items.len() {
+        if done.contains(&index) {
+            continue;
+        }
+        parse_forward_definitions(index, &items, parsed, &mut done, &mut Vec::new())?;
+        parse_top_level_item(items[index].clone(), parsed)?;
+        done.insert(index);
+    }
+    Ok(())
+}
+
+// Forward references: a variable used before it is defined gets its next definition in the
+// file parsed first. Definitions that need each other are reported as circular
+fn parse_forward_definitions(
+    index: usize,
+    items: &[pest::iterators::Pair<Rule>],
+    parsed: &mut ParsedMusic,
+    done: &mut HashSet<usize>,
+    resolving: &mut Vec<usize>,
+) -> Result<(), String> {
+    for name in referenced_variables(&items[index]) {
+        if parsed.variables.contains_key(&name) {
+            continue;
+        }
+        let Some(definition) = (index + 1..items.len())
+            .find(|&i| !done.contains(&i) && defined_variable(&items[i]).as_deref() == Some(name.as_str())) else {
+            continue;
+        };
+        if resolving.contains(&definition) {
+            parsed.warnings.push(ParseWarning::new(
+                WarningKind::CircularVariable,
+                format!("Variable \\{} refers to itself", name),
+                Some(SourceSpan::from_pair(&items[definition])),
+            ));
+            continue;
         }
+        resolving.push(definition);
+        parse_forward_definitions(definition, items, parsed, done, resolving)?;
+        parse_top_level_item(items[definition].clone(), parsed)?;
+        done.insert(definition);
+        resolving.pop();
     }
     Ok(())
 }
 
+// Name of the variable defined by a top-level item, if it is a definition
+fn defined_variable(item: &pest::iterators::Pair<Rule>) -> Option<String> {
+    let definition = item.clone().into_inner().find(|p| p.as_rule() == Rule::variable_definition)?;
+    definition.into_inner()
+        .find(|p| p.as_rule() == Rule::variable_name)
+        .map(|p| p.as_str().to_string())
+}
+
 fn parse_top_level_item(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
@@ -286,16 +1126,20 @@ fn parse_top_level_item(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMu
                 }
             },
             Rule::header => {
-                parse_header(inner_pair, parsed)?;
+                let header = parse_outer_header(inner_pair, parsed)?;
+                parsed.header = header.over(&parsed.header);
             },
             Rule::score => {
-                parse_score(inner_pair, parsed)?;
+                let score = parse_score(inner_pair, parsed)?;
+                parsed.toplevel_book().add_score(score);
             },
             Rule::book => {
-                parse_book(inner_pair, parsed)?;
+                let book = parse_book(inner_pair, parsed)?;
+                parsed.books.push(book);
             },
             Rule::bookpart => {
-                parse_bookpart(inner_pair, parsed)?;
+                let part = parse_bookpart(inner_pair, parsed)?;
+                parsed.toplevel_book().parts.push(part);
             },
             Rule::music_mode => {
                 // parse_music_mode_without_staff(inner_pair, parsed)?;
@@ -304,7 +1148,8 @@ fn parse_top_level_item(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMu
                 // Skip simple commands for now
             },
             Rule::scheme_code => {
-                // Skip scheme code for now
+                // #(define ...), #(set-global-staff-size 18) etc.
+                eval_scheme(&inner_pair, parsed);
             },
             Rule::include_directive => {
                 // Skip include directives for now
@@ -326,9 +1171,23 @@ fn parse_top_level_item(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMu
     Ok(())
 }
 
-fn parse_score(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+fn parse_score(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<Score, String> {
+    // Definitions inside the score are local to it
+    let outer_variables = parsed.variables.clone();
+    // So are \tempo, \key, \time and \partial. A score starts from the values set outside
+    // any score, e.g. by a \partial inside a variable, not from those of the score before it
+    let outer_tempo = parsed.tempo.clone();
+    let outer_tempo_bpm = parsed.tempo_bpm;
+    let outer_key = parsed.key_signature.clone();
+    let outer_time = parsed.time_signature.clone();
+    let outer_partial = parsed.partial.clone();
+    let first_staff = parsed.staves.len();
+    let first_group = parsed.staff_groups.len();
+    let mut header = Header::default();
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
+            Rule::variable_definition => parse_variable_definition(inner_pair, parsed)?,
+            Rule::header => header = parse_header(inner_pair, parsed)?.over(&header),
             Rule::score_content => parse_score_content(inner_pair, parsed)?,
             Rule::layout_block => {
                 // Skip layout block
@@ -339,7 +1198,24 @@ fn parse_score(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> R
             _ => {}
         }
     }
-    Ok(())
+    parsed.variables = outer_variables;
+    let score = Score {
+        header,
+        tempo: parsed.tempo.clone(),
+        tempo_bpm: parsed.tempo_bpm,
+        key_signature: parsed.key_signature.clone(),
+        time_signature: parsed.time_signature.clone(),
+        partial: parsed.partial.clone(),
+        staves: first_staff..parsed.staves.len(),
+        staff_groups: parsed.staff_groups[first_group..].to_vec(),
+        measure_grid: Vec::new(),
+    };
+    parsed.tempo = outer_tempo;
+    parsed.tempo_bpm = outer_tempo_bpm;
+    parsed.key_signature = outer_key;
+    parsed.time_signature = outer_time;
+    parsed.partial = outer_partial;
+    Ok(score)
 }
 
 fn parse_score_content(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
@@ -348,9 +1224,8 @@ fn parse_score_content(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMus
             Rule::simultaneous_music => {
                 parse_simultaneous_music(inner_pair, parsed)?;
             },
-            Rule::piano_staff => {
-                parse_piano_staff(inner_pair, parsed)?;
-            },
+            Rule::staff_group => parse_staff_group(inner_pair, parsed)?,
+            Rule::variable_reference => parse_staff_variable(inner_pair, parsed)?,
             Rule::staff => {
                 // Create a new staff and parse into it
                 let staff = Staff::new(None);
@@ -370,7 +1245,8 @@ fn parse_score_content(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMus
                 // Skip simple commands for now
             },
             Rule::scheme_code => {
-                // Skip scheme code for now
+                // #(define ...), #(set-global-staff-size 18) etc.
+                eval_scheme(&inner_pair, parsed);
             },
             Rule::include_directive => {
                 // Skip include directives for now
@@ -391,6 +1267,7 @@ fn parse_simultaneous_music(pair: pest::iterators::Pair<Rule>, parsed: &mut Pars
     // Parse parallel staves (<<...>>)
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
+            Rule::staff_group => parse_staff_group(inner_pair, parsed)?,
             Rule::staff => {
                 // Create a new staff and parse into it
                 let staff = Staff::new(None);
@@ -423,17 +1300,48 @@ fn parse_simultaneous_music(pair: pest::iterators::Pair<Rule>, parsed: &mut Pars
                 // Parse \new NullVoice context - used for structural information
                 parse_new_nullvoice(inner_pair, parsed)?;
             },
+            Rule::variable_reference => parse_staff_variable(inner_pair, parsed)?,
             _ => {}
         }
     }
     Ok(())
 }
 
-fn parse_header(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+// \name where staves are expected: the staves of stored << >> music, or a staff
+// of its own for other music
+fn parse_staff_variable(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+    let Some(variable) = lookup_variable(&pair, parsed) else {
+        return Ok(());
+    };
+    match variable.value {
+        VariableValue::SimultaneousMusic { staves, voices, groups } => {
+            let offset = parsed.staves.len();
+            for (name, (staff_idx, voice_idx)) in voices {
+                parsed.voices.insert(name, (staff_idx + offset, voice_idx));
+            }
+            let groups = groups.iter().map(|group| group.shifted(offset as isize));
+            match parsed.open_groups.last_mut() {
+                Some(parent) => parent.groups.extend(groups),
+                None => parsed.staff_groups.extend(groups),
+            }
+            parsed.staves.extend(staves);
+        },
+        VariableValue::Music => {
+            let mut staff = Staff::new(None);
+            staff.base = MusicContainerBase { name: None, ..variable.base };
+            parsed.staves.push(staff);
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_header(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<Header, String> {
+    let mut header = Header::default();
     for inner_pair in pair.into_inner() {
         if inner_pair.as_rule() == Rule::header_item {
             let mut key = String::new();
-            let mut value = String::new();
+            let mut value = None;
             
             for item_pair in inner_pair.into_inner() {
                 match item_pair.as_rule() {
@@ -441,96 +1349,219 @@ fn parse_header(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) ->
                     Rule::header_value => {
                         // Parse different types of header values
                         for value_pair in item_pair.into_inner() {
-                            match value_pair.as_rule() {
+                            value = match value_pair.as_rule() {
                                 Rule::string_literal => {
                                     let s = value_pair.as_str();
                                     // Remove quotes
-                                    value = s[1..s.len()-1].to_string();
+                                    Some(HeaderValue::Text(s[1..s.len()-1].to_string()))
                                 },
                                 Rule::boolean_literal => {
-                                    // For boolean values like ##f or ##t, store as-is
-                                    value = value_pair.as_str().to_string();
+                                    // ##f hides the field (e.g. the default tagline)
+                                    Some(HeaderValue::Boolean(value_pair.as_str() == "##t"))
                                 },
                                 Rule::markup_expression => {
-                                    // For markup expressions, store the raw text
-                                    value = value_pair.as_str().to_string();
+                                    Some(HeaderValue::Markup(flatten_markup(value_pair.as_str())))
                                 },
                                 Rule::scheme_code => {
-                                    // For scheme code, store the raw text
-                                    value = value_pair.as_str().to_string();
+                                    // For scheme code, store the value, or the raw text if it cannot be evaluated
+                                    match eval_scheme(&value_pair, parsed) {
+                                        Some(SchemeValue::Boolean(b)) => Some(HeaderValue::Boolean(b)),
+                                        Some(result) => Some(HeaderValue::Text(result.display())),
+                                        None => Some(HeaderValue::Text(value_pair.as_str().to_string())),
+                                    }
                                 },
                                 Rule::identifier => {
                                     // For identifiers (variable references), store as-is
-                                    value = value_pair.as_str().to_string();
+                                    Some(HeaderValue::Text(value_pair.as_str().to_string()))
                                 },
-                                _ => {}
-                            }
+                                Rule::variable_reference => {
+                                    // title = \myTitle: the text of a string, markup or number variable
+                                    match lookup_variable(&value_pair, parsed).map(|v| v.value) {
+                                        Some(VariableValue::String(text)) => Some(HeaderValue::Text(text)),
+                                        Some(VariableValue::Markup(markup)) => Some(HeaderValue::Markup(markup)),
+                                        Some(VariableValue::Number(number)) => Some(HeaderValue::Text(number.to_string())),
+                                        Some(VariableValue::SchemeValue(SchemeValue::Boolean(b))) => Some(HeaderValue::Boolean(b)),
+                                        Some(VariableValue::SchemeValue(result)) => Some(HeaderValue::Text(result.display())),
+                                        _ => None,
+                                    }
+                                },
+                                _ => value,
+                            };
                         }
                     }
                     _ => {}
                 }
             }
             
-            match key.as_str() {
-                "title" => {
-                    parsed.title = Some(value);
-                },
-                "composer" => {
-                    parsed.composer = Some(value);
-                },
-                "tempo" => {
-                    parsed.tempo = Some(value);
-                },
-                _ => {
-                    // Ignore other header fields like subsubtitle, instrumentName, etc.
-                }
+            // Every field is kept: subtitle, poet, opus, piece, copyright, tagline ...
+            if let Some(value) = value {
+                header.set(key, value);
             }
         }
     }
-    Ok(())
+    Ok(header)
 }
 
-fn parse_book(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
-    for inner_pair in pair.into_inner() {
-        match inner_pair.as_rule() {
-            Rule::book_item => parse_book_item(inner_pair, parsed)?,
+fn parse_book(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<Book, String> {
+    // Definitions inside the book are local to it
+    let outer_variables = parsed.variables.clone();
+    let mut book = Book::default();
+    for item in pair.into_inner().filter(|p| p.as_rule() == Rule::book_item).flat_map(|p| p.into_inner()) {
+        match item.as_rule() {
+            Rule::score => {
+                let score = parse_score(item, parsed)?;
+                book.add_score(score);
+            },
+            Rule::bookpart => book.parts.push(parse_bookpart(item, parsed)?),
+            Rule::header => book.header = parse_outer_header(item, parsed)?.over(&book.header),
+            Rule::variable_definition => parse_variable_definition(item, parsed)?,
             _ => {}
         }
     }
-    Ok(())
+    parsed.variables = outer_variables;
+    Ok(book)
 }
 
-fn parse_bookpart(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
-    for inner_pair in pair.into_inner() {
-        match inner_pair.as_rule() {
-            Rule::book_item => parse_book_item(inner_pair, parsed)?,
+fn parse_bookpart(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<BookPart, String> {
+    // Definitions inside the book part are local to it
+    let outer_variables = parsed.variables.clone();
+    let mut part = BookPart::default();
+    for item in pair.into_inner().filter(|p| p.as_rule() == Rule::book_item).flat_map(|p| p.into_inner()) {
+        match item.as_rule() {
+            Rule::score => part.scores.push(parse_score(item, parsed)?),
+            Rule::header => part.header = parse_outer_header(item, parsed)?.over(&part.header),
+            Rule::variable_definition => parse_variable_definition(item, parsed)?,
             _ => {}
         }
     }
-    Ok(())
+    parsed.variables = outer_variables;
+    Ok(part)
 }
 
-fn parse_book_item(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
-    for inner_pair in pair.into_inner() {
-        match inner_pair.as_rule() {
-            Rule::score => parse_score(inner_pair, parsed)?,
-            Rule::header => parse_header(inner_pair, parsed)?,
-            _ => {}
+// \header outside a \score. The file-wide title, composer and tempo still show the last one
+// seen, as they did before books were kept apart
+fn parse_outer_header(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<Header, String> {
+    let header = parse_header(pair, parsed)?;
+    parsed.title = header.text("title").or(parsed.title.take());
+    parsed.composer = header.text("composer").or(parsed.composer.take());
+    parsed.tempo = header.text("tempo").or(parsed.tempo.take());
+    Ok(header)
+}
+
+// The file-wide tempo, key, time and partial keep the last value set in any score, as they did
+// before scores were kept apart
+fn keep_last_score_settings(parsed: &mut ParsedMusic) {
+    let scores: Vec<Score> = parsed.scores().cloned().collect();
+    for score in scores {
+        parsed.tempo = score.tempo.or(parsed.tempo.take());
+        parsed.tempo_bpm = score.tempo_bpm.or(parsed.tempo_bpm);
+        parsed.key_signature = score.key_signature.or(parsed.key_signature.take());
+        parsed.time_signature = score.time_signature.or(parsed.time_signature.take());
+        parsed.partial = score.partial.or(parsed.partial.take());
+    }
+}
+
+// Unset header fields are inherited: a score's from its book part, a book part's from its book
+// and a book's from the top-level \header
+fn inherit_headers(parsed: &mut ParsedMusic) {
+    for book in parsed.books.iter_mut() {
+        book.header = book.header.over(&parsed.header);
+        for part in book.parts.iter_mut() {
+            part.header = part.header.over(&book.header);
+            for score in part.scores.iter_mut() {
+                score.header = score.header.over(&part.header);
+            }
         }
     }
-    Ok(())
 }
 
-fn parse_piano_staff(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
-    // Parse PianoStaff which contains simultaneous music
+// \new PianoStaff << ... >>, and likewise GrandStaff, ChoirStaff and StaffGroup: the staves are
+// parsed as usual, the group records which of them it joins and the groups nested in it
+fn parse_staff_group(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+    let first_staff = parsed.staves.len();
+    let mut group = StaffGroup {
+        group_type: StaffGroupType::PianoStaff,
+        name: None,
+        delimiter: GroupDelimiter::Brace,
+        instrument: InstrumentNames::default(),
+        staves: first_staff..first_staff,
+        groups: Vec::new(),
+    };
+    let mut music = None;
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
-            Rule::simultaneous_music => {
-                parse_simultaneous_music(inner_pair, parsed)?;
+            Rule::staff_group_type => {
+                if let Some(group_type) = StaffGroupType::from_lilypond(inner_pair.as_str()) {
+                    group.group_type = group_type;
+                    group.delimiter = group_type.delimiter();
+                }
             },
+            Rule::identifier => group.name = Some(inner_pair.as_str().to_string()),
+            Rule::string_literal => group.name = Some(inner_pair.as_str().trim_matches('"').to_string()),
+            Rule::context_modification => {
+                let context = format!("{:?}", group.group_type);
+                for event in context_properties(inner_pair, &context, parsed) {
+                    match (event.property.as_str(), event.text()) {
+                        ("systemStartDelimiter", Some(delimiter)) => {
+                            group.delimiter = match delimiter {
+                                "SystemStartBrace" => GroupDelimiter::Brace,
+                                "SystemStartBracket" => GroupDelimiter::Bracket,
+                                "SystemStartSquare" => GroupDelimiter::Square,
+                                "SystemStartBar" => GroupDelimiter::Bar,
+                                _ => group.delimiter,
+                            };
+                        },
+                        (property, Some(text)) => {
+                            group.instrument.set(property, text.to_string());
+                        },
+                        _ => {}
+                    }
+                    parsed.pending_properties.push(event);
+                }
+            },
+            Rule::simultaneous_music | Rule::auto_change | Rule::variable_reference => music = Some(inner_pair),
             _ => {}
         }
     }
+
+    parsed.open_groups.push(group);
+    let result = match music {
+        Some(music) if music.as_rule() == Rule::simultaneous_music => parse_simultaneous_music(music, parsed),
+        Some(music) if music.as_rule() == Rule::auto_change => parse_auto_change_staves(music, parsed),
+        Some(music) => parse_staff_variable(music, parsed),
+        None => Ok(()),
+    };
+    let mut group = parsed.open_groups.pop().expect("the group pushed above");
+    result?;
+    group.staves = first_staff..parsed.staves.len();
+    match parsed.open_groups.last_mut() {
+        Some(parent) => parent.groups.push(group),
+        None => parsed.staff_groups.push(group),
+    }
+    Ok(())
+}
+
+// \new PianoStaff \autoChange { ... }: the music goes on a staff "up", and a staff "down" in the
+// bass clef is added for the notes below the split pitch
+fn parse_auto_change_staves(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+    let mut notes = Vec::new();
+    let mut last_duration = "4".to_string();
+    let mut last_octave = 0;
+    let mut last_pitch = String::new();
+    parse_auto_change(pair, &mut notes, parsed, &mut last_duration, &mut last_octave, &mut last_pitch, OctaveMode::Absolute)?;
+    parsed.pending_properties.clear();
+    parsed.pending_staff_change = None;
+
+    let mut lower = Staff::new(Some("down".to_string()));
+    lower.base.clef = Some("bass".to_string());
+    lower.base.notes = notes.iter()
+        .filter(|n| !matches!(n.note_type, NoteType::Grace | NoteType::Clef))
+        .map(as_spacer)
+        .collect();
+    let mut upper = Staff::new(Some("up".to_string()));
+    upper.base.notes = notes;
+    parsed.staves.push(upper);
+    parsed.staves.push(lower);
     Ok(())
 }
 
@@ -552,6 +1583,15 @@ fn parse_staff(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> R
                     staff.base.name = Some(s[1..s.len()-1].to_string());
                 }
             },
+            Rule::context_modification => {
+                // \new Staff \with { instrumentName = "Violin" }
+                for event in context_properties(inner_pair, "Staff", parsed) {
+                    if let (Some(staff), Some(text)) = (parsed.staves.last_mut(), event.text()) {
+                        staff.base.instrument.set(&event.property, text.to_string());
+                    }
+                    parsed.pending_properties.push(event);
+                }
+            },
             Rule::staff_body => {
                 staff_body_pair = Some(inner_pair);
             },
@@ -604,6 +1644,12 @@ fn parse_staff_body(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic,
             _ => {}
         }
     }
+    // \set Staff.instrumentName in the music, or in a variable used in it
+    base.instrument.merge(std::mem::take(&mut parsed.pending_instrument));
+    // Property commands and \change Staff after the last note change nothing
+    parsed.pending_properties.clear();
+    parsed.pending_staff_change = None;
+    parsed.tuplet_span = None;
     // Find the first note with is_clef=true and set staff.clef
     for note in &base.notes {
         if note.note_type == NoteType::Clef {
@@ -621,6 +1667,8 @@ fn parse_variable_definition(pair: pest::iterators::Pair<Rule>, parsed: &mut Par
     let mut var_time: Option<String> = None;
     let mut var_key: Option<String> = None;
     let mut var_lyric: Option<Lyric> = None;
+    let mut own_pitch_mode = false;
+    let mut value = VariableValue::Music;
     
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
@@ -628,25 +1676,69 @@ fn parse_variable_definition(pair: pest::iterators::Pair<Rule>, parsed: &mut Par
                 var_name = inner_pair.as_str().to_string();
             },
             Rule::variable_value => {
-                // If it's a music_mode, extract notes from it
                 for value_pair in inner_pair.into_inner() {
                     match value_pair.as_rule() {
                         Rule::music_mode => {
                             println!("[DEBUG] Variable {} contains music_mode ", var_name);
-                            parse_music_mode(value_pair.clone(),&mut var_notes, parsed)?;
+                            own_pitch_mode = true;
+                            parse_music_mode(value_pair.clone(), &mut var_notes, parsed, &mut String::from("4"))?;
                         },
                         Rule::lyricmode => {
                             var_lyric = parse_lyric_mode(value_pair.clone())?;
+                            value = VariableValue::Lyrics;
+                        },
+                        Rule::simultaneous_music => {
+                            // Parsed now, with the variables in scope here, into staves of its own
+                            let outer_staves = std::mem::take(&mut parsed.staves);
+                            let outer_voices = std::mem::take(&mut parsed.voices);
+                            let outer_groups = std::mem::take(&mut parsed.staff_groups);
+                            let result = parse_simultaneous_music(value_pair.clone(), parsed);
+                            let staves = std::mem::replace(&mut parsed.staves, outer_staves);
+                            let voices = std::mem::replace(&mut parsed.voices, outer_voices);
+                            let groups = std::mem::replace(&mut parsed.staff_groups, outer_groups);
+                            result?;
+                            value = VariableValue::SimultaneousMusic { staves, voices, groups };
+                        },
+                        Rule::markup_expression => {
+                            value = VariableValue::Markup(flatten_markup(value_pair.as_str()));
+                        },
+                        Rule::string_literal => {
+                            value = VariableValue::String(value_pair.as_str().trim_matches('"').to_string());
+                        },
+                        Rule::real | Rule::unsigned => {
+                            value = VariableValue::Number(value_pair.as_str().parse().unwrap_or(0.0));
+                        },
+                        Rule::context_modification => {
+                            let body = value_pair.into_inner().next().map(|p| p.as_str().trim().to_string()).unwrap_or_default();
+                            value = VariableValue::ContextModification(body);
                         },
                         Rule::scheme_code => {
-                            // Parse Scheme code (e.g., #(make-span-event 'SustainEvent STOP))
-                            // For now, we just acknowledge it exists but don't process it further
-                            println!("[DEBUG] Variable {} contains scheme_code: {}", var_name, value_pair.as_str());
+                            // Parse Scheme code (e.g., #(make-span-event 'SustainEvent STOP));
+                            // music functions with a #{ #} body can be expanded where they are called
+                            let code = value_pair.as_str();
+                            value = match MusicFunction::from_scheme(code) {
+                                Some(function) => VariableValue::MusicFunction(function),
+                                None => match eval_scheme(&value_pair, parsed) {
+                                    Some(result) => VariableValue::SchemeValue(result),
+                                    None => VariableValue::Scheme(code.to_string()),
+                                },
+                            };
+                        },
+                        Rule::custom_function_call | Rule::set_command | Rule::override_command => {
+                            // Custom function calls like \dynamictext "dimin." and property commands
+                            value = VariableValue::Command(value_pair.as_str().to_string());
                         },
-                        Rule::custom_function_call => {
-                            // Custom function calls like \dynamictext "dimin."
-                            // For now, we just acknowledge it exists but don't process it further
-                            println!("[DEBUG] Variable {} contains custom_function_call: {}", var_name, value_pair.as_str());
+                        Rule::variable_reference => {
+                            // name = \other: a copy of the other variable
+                            if let Some(other) = lookup_variable(&value_pair, parsed) {
+                                if !var_name.is_empty() {
+                                    parsed.variables.insert(var_name.clone(), Variable {
+                                        base: MusicContainerBase { name: Some(var_name.clone()), ..other.base },
+                                        ..other
+                                    });
+                                }
+                            }
+                            return Ok(());
                         },
                         Rule::bare_music_block => {
                             // Parse bare music block (e.g., { notes })
@@ -656,7 +1748,10 @@ fn parse_variable_definition(pair: pest::iterators::Pair<Rule>, parsed: &mut Par
                                     let mut last_duration = String::from("4");
                                     let mut last_octave = 3i32;
                                     let mut last_pitch = String::new();
-                                    parse_basic_music_item(block_pair, &mut var_notes, parsed, &mut last_duration, &mut last_octave, &mut last_pitch, OctaveMode::Absolute)?;
+                                    // Stored with absolute octaves; see variable_notes for how they are used
+                                    for item in block_pair.into_inner() {
+                                        parse_basic_music_item(item, &mut var_notes, parsed, &mut last_duration, &mut last_octave, &mut last_pitch, OctaveMode::Absolute)?;
+                                    }
                                 }
                             }
                         },
@@ -699,8 +1794,12 @@ fn parse_variable_definition(pair: pest::iterators::Pair<Rule>, parsed: &mut Par
                 clef: var_clef.clone(),
                 time_signature: var_time.clone(),
                 key_signature: var_key.clone(),
+                instrument: std::mem::take(&mut parsed.pending_instrument),
             },
             lyric: var_lyric.clone(),
+            own_pitch_mode,
+            value,
+            properties: std::mem::take(&mut parsed.pending_properties),
         };
         parsed.variables.insert(var_name.clone(), variable);
     }
@@ -737,7 +1836,10 @@ fn parse_staff_directive(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedM
                 parse_key_signature(inner_pair, parsed)?;
                 base.key_signature = parsed.key_signature.clone();
             },
-            Rule::time_signature => parse_time_signature(inner_pair, parsed)?,
+            Rule::time_signature => {
+                parse_time_signature(inner_pair, parsed)?;
+                base.time_signature = parsed.time_signature.clone();
+            },
             Rule::tempo => parse_tempo(inner_pair, parsed)?,
             Rule::partial => parse_partial(inner_pair, parsed)?,
             _ => {}
@@ -758,8 +1860,9 @@ fn parse_key_signature(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMus
         }
     }
     
-    // Convert LilyPond format to VexFlow format
-    let vexflow_key = convert_key_signature(&note, &mode);
+    // Convert LilyPond format to VexFlow format; the tonic may be written in any note-name language
+    let tonic = get_standard_pitch(&note, parsed.language.as_deref());
+    let vexflow_key = key_signature_name(&tonic, &mode).unwrap_or_else(|| "C".to_string());
     println!("[parse_key_signature] Key signature: {:?}", vexflow_key);
     parsed.key_signature = Some(vexflow_key);
     Ok(())
@@ -784,6 +1887,7 @@ fn parse_time_signature(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMu
 }
 
 fn parse_tempo(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Result<(), String> {
+    let mut beat = None;
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
             Rule::string_literal => {
@@ -791,6 +1895,21 @@ fn parse_tempo(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> R
                 let tempo = s[1..s.len()-1].to_string(); // Remove quotes
                 parsed.tempo = Some(tempo);
             },
+            Rule::markup_expression => {
+                // \tempo \markup { \italic "Allegro" } keeps the text on one line
+                let text = flatten_markup(inner_pair.as_str()).text.replace('\n', " ");
+                parsed.tempo = Some(text);
+            },
+            Rule::duration => {
+                let (duration, dots) = parse_duration(inner_pair)?;
+                beat = Some(duration_to_fraction(&duration, &dots)?);
+            },
+            Rule::unsigned => {
+                // \tempo 4. = 60: beats per minute, stored as quarter notes per minute
+                if let (Some(beat), Ok(per_minute)) = (beat, inner_pair.as_str().parse::<f64>()) {
+                    parsed.tempo_bpm = Some(per_minute * beat * 4.0);
+                }
+            },
             _ => {}
         }
     }
@@ -882,12 +2001,8 @@ fn parse_new_lyrics(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic)
                 lyric = parse_lyric_mode(inner_pair)?;
             },
             Rule::variable_reference => {
-                let ref_str = inner_pair.as_str();
-                if ref_str.starts_with("\\") {
-                    let var_name = ref_str[1..].to_string();
-                    if let Some(variable) = parsed.variables.get(&var_name) {
-                        lyric = variable.lyric.clone();                       
-                    }
+                if let Some(variable) = lookup_variable(&inner_pair, parsed) {
+                    lyric = variable.lyric;
                 }
             },
             _ => {}
@@ -1017,6 +2132,12 @@ fn parse_lyric_sequence(pair: pest::iterators::Pair<Rule>,
                                         }
                                         lyrics_text.push_str(basic_item.as_str());
                                     },
+                                    Rule::markup_expression => {
+                                        if !lyrics_text.is_empty() {
+                                            lyrics_text.push(' ');
+                                        }
+                                        lyrics_text.push_str(&markup_syllable(basic_item.as_str()));
+                                    },
                                     Rule::set_command | Rule::override_command => {
                                         // Ignore commands
                                     },
@@ -1038,6 +2159,11 @@ fn parse_lyric_sequence(pair: pest::iterators::Pair<Rule>,
     }
 }
 
+// A markup used as a lyric is one syllable: its spaces become the _ of "a_b"
+fn markup_syllable(markup: &str) -> String {
+    flatten_markup(markup).text.split_whitespace().collect::<Vec<_>>().join("_")
+}
+
 fn parse_lyric_mode(pair: pest::iterators::Pair<Rule>) -> Result<Option<Lyric>, String> {
     let mut lyrics_text = String::new();
     
@@ -1055,6 +2181,12 @@ fn parse_lyric_mode(pair: pest::iterators::Pair<Rule>) -> Result<Option<Lyric>,
                                         // 普通歌词文本
                                         lyrics_text.push_str(lyric_item.as_str());
                                     },
+                                    Rule::markup_expression => {
+                                        // markup 歌词：整段文字作为一个音节
+                                        lyrics_text.push(' ');
+                                        lyrics_text.push_str(&markup_syllable(lyric_item.as_str()));
+                                        lyrics_text.push(' ');
+                                    },
                                     Rule::set_command | Rule::override_command => {
                                         // 命令 - 可以选择忽略或处理
                                         // 这里选择忽略，只保留歌词文本
@@ -1100,7 +2232,7 @@ fn parse_music_sequence(pair: pest::iterators::Pair<Rule>,
                         },
                         Rule::music_mode => {
                             // Handle music_mode if needed
-                            parse_music_mode(item, notes, parsed)?;
+                            parse_music_mode(item, notes, parsed, last_duration)?;
                         },
                         Rule::new_voice => {
                             // Handle \new Voice = "name" { ... } or \new Voice = "name" \variableName
@@ -1145,11 +2277,13 @@ enum OctaveMode {
     Absolute,
 }
 
+// Each \fixed, \relative or \absolute block keeps its own octave reference, so a block nested
+// in another one (or stored in a variable) resolves its pitches by its own mode only
 fn parse_music_mode(pair: pest::iterators::Pair<Rule>, 
     notes: &mut Vec<LilyPondNote>, 
-    parsed: &mut ParsedMusic) -> Result<(), String> {    
+    parsed: &mut ParsedMusic,
+    last_duration: &mut String) -> Result<(), String> {    
     let _input_str = pair.as_str();
-    let mut last_duration = "4".to_string();
     let mode_type = pair.as_rule();
     
 
@@ -1189,7 +2323,7 @@ fn parse_music_mode(pair: pest::iterators::Pair<Rule>,
                         for seq_item in mode_item.into_inner() {
                             match seq_item.as_rule() {
                                 Rule::basic_music_item => {
-                                    parse_basic_music_item(seq_item,  notes, parsed, &mut last_duration,  &mut fixed_ref_octave, &mut last_pitch, OctaveMode::Fixed)?;
+                                    parse_basic_music_item(seq_item,  notes, parsed, last_duration,  &mut fixed_ref_octave, &mut last_pitch, OctaveMode::Fixed)?;
                                 },
                                 _ => {}
                             }
@@ -1217,7 +2351,7 @@ fn parse_music_mode(pair: pest::iterators::Pair<Rule>,
                         for seq_item in mode_item.into_inner() {
                             match seq_item.as_rule() {
                                 Rule::basic_music_item => {
-                                    parse_basic_music_item(seq_item,  notes, parsed, &mut last_duration,  &mut relative_ref_octave, &mut last_pitch, OctaveMode::Relative)?;
+                                    parse_basic_music_item(seq_item,  notes, parsed, last_duration,  &mut relative_ref_octave, &mut last_pitch, OctaveMode::Relative)?;
                                 },
                                 _ => {}
                             }
@@ -1241,7 +2375,7 @@ fn parse_music_mode(pair: pest::iterators::Pair<Rule>,
                         for seq_item in mode_item.into_inner() {
                             match seq_item.as_rule() {
                                 Rule::basic_music_item => {
-                                    parse_basic_music_item(seq_item,  notes, parsed, &mut last_duration,  &mut last_octave, &mut last_pitch, OctaveMode::Absolute)?;
+                                    parse_basic_music_item(seq_item,  notes, parsed, last_duration,  &mut last_octave, &mut last_pitch, OctaveMode::Absolute)?;
                                 },
                                 _ => {}
                             }
@@ -1271,6 +2405,8 @@ fn parse_repeat_volta(
     let mut repeat_times = 2u32; // Default to 2 if not specified
     let mut main_notes = Vec::new();
     let mut alternative_sections: Vec<Vec<LilyPondNote>> = Vec::new();
+    // Passes given with \volta for each alternative (empty when not written)
+    let mut alternative_voltas: Vec<Vec<i32>> = Vec::new();
     let mut repeat_type: String = String::new();
 
     for repeat_part in pair.into_inner() {
@@ -1284,7 +2420,7 @@ fn parse_repeat_volta(
                     repeat_times = times.max(1);
                 }
             },
-            Rule::basic_music_sequence => {
+            Rule::repeat_body => {
                 // Parse the main repeated music section
                 for seq_item in repeat_part.into_inner() {
                     if seq_item.as_rule() == Rule::basic_music_item {
@@ -1297,8 +2433,13 @@ fn parse_repeat_volta(
                 for alt_choice in repeat_part.into_inner() {
                     if alt_choice.as_rule() == Rule::alternative_choice {
                         let mut alt_notes = Vec::new();
+                        let mut voltas = Vec::new();
                         for alt_seq in alt_choice.into_inner() {
-                            if alt_seq.as_rule() == Rule::basic_music_sequence {
+                            if alt_seq.as_rule() == Rule::volta_numbers {
+                                voltas = alt_seq.into_inner()
+                                    .filter_map(|n| n.as_str().parse::<i32>().ok())
+                                    .collect();
+                            } else if alt_seq.as_rule() == Rule::basic_music_sequence {
                                 for seq_item in alt_seq.into_inner() {
                                     if seq_item.as_rule() == Rule::basic_music_item {
                                         parse_basic_music_item(seq_item, &mut alt_notes, parsed, last_duration, last_octave, last_pitch, mode)?;
@@ -1307,36 +2448,22 @@ fn parse_repeat_volta(
                             }
                         }
                         alternative_sections.push(alt_notes);
+                        alternative_voltas.push(voltas);
                     }
                 }
             },
             _ => {}
         }
     }
+    let alternative_passes = alternative_passes(repeat_times, &alternative_voltas);
     
     match repeat_type.as_str() {
         "volta" => {
             // Insert repeat start marker
             let repeat_start_note = LilyPondNote {
-                pitch: String::new(),
-                duration: String::new(),
-                octave: 0,
-                dots: String::new(),
-                chord_notes: Vec::new(),
-                clef: None,
-                time_sig: None,
-                key_sig: None,
-                ottava: None,
-                arpeggio: false,
                 repeat_times: Some(repeat_times),
                 note_type: NoteType::RepeatStart,
-                group_start: false,
-                group_end: false,
-                has_slur: false,
-                script_attachments: Vec::new(),
-                accidental_modifier: None,
-                alternative_index: Vec::new(),
-                tuplet_fraction: None,
+                ..Default::default()
             };
             notes.push(repeat_start_note);
 
@@ -1345,25 +2472,9 @@ fn parse_repeat_volta(
             for (alt_index, alt_notes) in alternative_sections.iter().enumerate() {
                 // Insert alternative marker
                 let alt_note = LilyPondNote {
-                    pitch: String::new(),
-                    duration: String::new(),
-                    octave: 0,
-                    dots: String::new(),
-                    chord_notes: Vec::new(),
-                    clef: None,
-                    time_sig: None,
-                    key_sig: None,
-                    ottava: None,
-                    arpeggio: false,
-                    repeat_times: None,
                     note_type: NoteType::AlternativeStart,
-                    group_start: false,
-                    group_end: false,
-                    has_slur: false,
-                    script_attachments: Vec::new(),
-                    accidental_modifier: None,
-                    alternative_index: vec![alt_index as i32 + 1],  // 1-based index
-                    tuplet_fraction: None,
+                    alternative_index: alternative_passes[alt_index].clone(),  // 1-based passes
+                    ..Default::default()
                 };
                 notes.push(alt_note);
                 
@@ -1371,25 +2482,8 @@ fn parse_repeat_volta(
                 
                 // Insert repeat end marker if not the last alternative
                 let repeat_end_note = LilyPondNote {
-                    pitch: String::new(),
-                    duration: String::new(),
-                    octave: 0,
-                    dots: String::new(),
-                    chord_notes: Vec::new(),
-                    clef: None,
-                    time_sig: None,
-                    key_sig: None,
-                    ottava: None,
-                    arpeggio: false,
-                    repeat_times: None,
                     note_type: NoteType::AlternativeEnd,
-                    group_start: false,
-                    group_end: false,
-                    has_slur: false,
-                    script_attachments: Vec::new(),
-                    accidental_modifier: None,
-                    alternative_index: Vec::new(),
-                    tuplet_fraction: None,
+                    ..Default::default()
                 };
                 notes.push(repeat_end_note);
             }
@@ -1397,46 +2491,76 @@ fn parse_repeat_volta(
             
                 // Insert final repeat end marker
                 let final_repeat_end = LilyPondNote {
-                    pitch: String::new(),
-                    duration: String::new(),
-                    octave: 0,
-                    dots: String::new(),
-                    chord_notes: Vec::new(),
-                    clef: None,
-                    time_sig: None,
-                    key_sig: None,
-                    ottava: None,
-                    arpeggio: false,
-                    repeat_times: None,
                     note_type: NoteType::RepeatEnd,
-                    group_start: false,
-                    group_end: false,
-                    has_slur: false,
-                    script_attachments: Vec::new(),
-                    accidental_modifier: None,
-                    tuplet_fraction: None,
-                    alternative_index: Vec::new(),
+                    ..Default::default()
                 };
                 notes.push(final_repeat_end);
         },
+        "segno" => {
+            // \repeat segno: a segno at the start of the body and a D.S. after it.
+            // With alternatives the body ends with To Coda, the D.S. comes after the
+            // first alternatives (al Coda) and the last alternative is the coda
+            if let Some(first) = main_notes.iter_mut().find(|n| is_timed_note(n)) {
+                first.navigation.insert(0, NavigationMark::Segno);
+            }
+            match alternative_sections.split_last_mut() {
+                None => {
+                    if let Some(last) = main_notes.last_mut() {
+                        last.navigation.push(NavigationMark::DalSegno(JumpEnd::End));
+                    }
+                    notes.extend(main_notes);
+                },
+                Some((coda_notes, first_alternatives)) => {
+                    if let Some(last) = main_notes.last_mut() {
+                        last.navigation.push(NavigationMark::ToCoda);
+                    }
+                    notes.extend(main_notes);
+                    for alt_notes in first_alternatives.iter() {
+                        notes.extend(alt_notes.clone());
+                    }
+                    if let Some(last) = notes.last_mut() {
+                        last.navigation.push(NavigationMark::DalSegno(JumpEnd::Coda));
+                    }
+                    if let Some(first) = coda_notes.iter_mut().find(|n| is_timed_note(n)) {
+                        first.navigation.insert(0, NavigationMark::Coda);
+                    }
+                    notes.extend(coda_notes.clone());
+                },
+            }
+        },
         "unfold" => {
-            // With alternatives: play main section (repeat_times - alternatives.len()) times,
-            // then play main section + each alternative
-            let num_alternatives = alternative_sections.len() as u32;
-            let common_repeats = if repeat_times > num_alternatives {
-                repeat_times - num_alternatives
-            } else {
-                0
-            };
-
-            let mut index = 0;
-            // Play each alternative ending
-            for i in 0..repeat_times {
+            // Write the main section once per pass, followed by the alternative for that pass
+            for pass in 1..=repeat_times as i32 {
                 notes.extend(main_notes.clone());
-                if num_alternatives > 0 {
-                    notes.extend(alternative_sections[index].clone());
-                    if i >= common_repeats {
-                        index += 1;
+                if let Some(alt_index) = alternative_passes.iter().position(|passes| passes.contains(&pass)) {
+                    notes.extend(alternative_sections[alt_index].clone());
+                }
+            }
+        },
+        "percent" => {
+            // \repeat percent: the music is played repeat_times times and drawn once,
+            // followed by percent (measure-repeat) signs. The copies stay in the note list
+            // so that measures and playback include them; percent_repeat tells renderers
+            // to draw a sign instead of the notes
+            for pass in 1..=repeat_times {
+                let mut copy = main_notes.clone();
+                if pass > 1 {
+                    for note in copy.iter_mut() {
+                        note.percent_repeat = Some(pass);
+                    }
+                }
+                notes.extend(copy);
+            }
+        },
+        "tremolo" => {
+            // \repeat tremolo: a measured tremolo over one or two notes
+            match measured_tremolo(&main_notes, repeat_times) {
+                Some(tremolo_notes) => notes.extend(tremolo_notes),
+                None => {
+                    // Not a valid tremolo (more than two notes, or a length that cannot be written):
+                    // play the music unfolded
+                    for _ in 0..repeat_times {
+                        notes.extend(main_notes.clone());
                     }
                 }
             }
@@ -1453,6 +2577,77 @@ fn parse_repeat_volta(
     Ok(())
 }
 
+// \repeat tremolo n { c16 d16 }: each note lasts n times its written value and
+// keeps that value as its tremolo strokes. Returns None unless the body has one or two notes
+fn measured_tremolo(body: &[LilyPondNote], times: u32) -> Option<Vec<LilyPondNote>> {
+    let timed: Vec<usize> = body.iter().enumerate()
+        .filter(|(_, n)| is_timed_note(n))
+        .map(|(i, _)| i)
+        .collect();
+    if timed.is_empty() || timed.len() > 2 {
+        return None;
+    }
+
+    let mut result = body.to_vec();
+    for (position, &i) in timed.iter().enumerate() {
+        let note = &mut result[i];
+        let strokes = note.duration.parse::<u32>().unwrap_or(8);
+        let total = duration_to_fraction(&note.duration, &note.dots).ok()? * times as f64;
+        let (duration, dots) = fraction_to_duration(total)?;
+        note.duration = duration;
+        note.dots = dots;
+        note.tremolo = Some(match (timed.len(), position) {
+            (1, _) => Tremolo::Stem(strokes),
+            (_, 0) => Tremolo::Start(strokes),
+            _ => Tremolo::End(strokes),
+        });
+    }
+    Some(result)
+}
+
+// Written duration and dots for a length in whole notes (e.g. 0.375 -> ("4", "."))
+fn fraction_to_duration(value: f64) -> Option<(String, String)> {
+    for duration in ["1", "2", "4", "8", "16", "32", "64"] {
+        for dots in ["", ".", ".."] {
+            if let Ok(fraction) = duration_to_fraction(duration, dots) {
+                if (fraction - value).abs() < 0.0001 {
+                    return Some((duration.to_string(), dots.to_string()));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Stem tremolo written on a note or chord (c4:16, <c e>2:32); a bare ':' means eighths
+fn stem_tremolo(pair: &pest::iterators::Pair<Rule>) -> Option<Tremolo> {
+    pair.clone().into_inner()
+        .find(|p| p.as_rule() == Rule::stem_tremolo)
+        .map(|p| Tremolo::Stem(p.into_inner().next()
+            .and_then(|d| d.as_str().parse::<u32>().ok())
+            .unwrap_or(8)))
+}
+
+// Passes (1-based) on which each alternative is played.
+// Alternatives with \volta keep their numbers; otherwise, as in LilyPond, the first
+// alternative takes the extra passes when there are fewer alternatives than passes
+fn alternative_passes(repeat_times: u32, voltas: &[Vec<i32>]) -> Vec<Vec<i32>> {
+    let times = repeat_times as i32;
+    let count = voltas.len() as i32;
+    voltas.iter().enumerate().map(|(i, written)| {
+        let i = i as i32;
+        if !written.is_empty() {
+            written.clone()
+        } else if count >= times {
+            vec![i + 1]
+        } else if i == 0 {
+            (1..=times - count + 1).collect()
+        } else {
+            vec![times - count + 1 + i]
+        }
+    }).collect()
+}
+
 fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>, 
     notes: &mut Vec<LilyPondNote>,     
     parsed: &mut ParsedMusic,
@@ -1486,25 +2681,9 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 // Create a special clef marker note
                 if !clef_value.is_empty() {
                     let clef_note = LilyPondNote {
-                        pitch: String::new(),
-                        duration: String::new(),
-                        octave: 0,
-                        dots: String::new(),
-                        chord_notes: Vec::new(),
                         clef: Some(clef_value.clone()),
-                        time_sig: None,
-                        key_sig: None,
-                        ottava: None,
-                        arpeggio: false,
-                        repeat_times: None,
                         note_type: NoteType::Clef,
-                        group_start: false,
-                        group_end: false,
-                        has_slur: false,
-                        script_attachments: Vec::new(),
-                        tuplet_fraction: None,
-                        accidental_modifier: None,
-                        alternative_index: Vec::new(),
+                        ..Default::default()
                     };
                     notes.push(clef_note);
                     
@@ -1525,25 +2704,9 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 // Create a special time signature marker note
                 if !time_sig_value.is_empty() {
                     let time_note = LilyPondNote {
-                        pitch: String::new(),
-                        duration: String::new(),
-                        octave: 0,
-                        dots: String::new(),
-                        chord_notes: Vec::new(),
-                        clef: None,
                         time_sig: Some(time_sig_value.clone()),
-                        key_sig: None,
-                        ottava: None,
-                        arpeggio: false,
-                        repeat_times: None,
                         note_type: NoteType::Time,
-                        group_start: false,
-                        group_end: false,
-                        has_slur: false,
-                        tuplet_fraction: None,
-                        script_attachments: Vec::new(),
-                        accidental_modifier: None,
-                        alternative_index: Vec::new(),
+                        ..Default::default()
                     };
                     notes.push(time_note);
                     println!("[parse_basic_music_item] Created time signature note: {}", time_sig_value);
@@ -1552,25 +2715,9 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
             Rule::key_signature => {
                 parse_key_signature(inner_pair, parsed)?;
                 let key_note = LilyPondNote {
-                        pitch: String::new(),
-                        duration: String::new(),
-                        octave: 0,
-                        dots: String::new(),
-                        chord_notes: Vec::new(),
-                        clef: None,
-                        time_sig: None,
                         key_sig: parsed.key_signature.clone(),
-                        ottava: None,
-                        arpeggio: false,
-                        repeat_times: None,
                         note_type: NoteType::Key,
-                        group_start: false,
-                        group_end: false,
-                        tuplet_fraction: None,
-                        has_slur: false,
-                        script_attachments: Vec::new(),
-                        accidental_modifier: None,
-                        alternative_index: Vec::new(),
+                        ..Default::default()
                     };
                     notes.push(key_note);
             },
@@ -1592,25 +2739,9 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 // Create a special ottava marker note
                 if let Some(ottava_val) = ottava_value {
                     let ottava_note = LilyPondNote {
-                        pitch: String::new(),
-                        duration: String::new(),
-                        octave: 0,
-                        dots: String::new(),
-                        chord_notes: Vec::new(),
-                        clef: None,
-                        time_sig: None,
-                        key_sig: None,
                         ottava: Some(ottava_val),
-                        arpeggio: false,
-                        repeat_times: None,
                         note_type: NoteType::Ottava,
-                        group_start: false,
-                        tuplet_fraction: None,
-                        group_end: false,
-                        has_slur: false,
-                        script_attachments: Vec::new(),
-                        accidental_modifier: None,
-                        alternative_index: Vec::new(),
+                        ..Default::default()
                     };
                     notes.push(ottava_note);
                     println!("[parse_basic_music_item] Created ottava note with value: {}", ottava_val);
@@ -1621,7 +2752,7 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 parse_partial(inner_pair, parsed)?;
             },
             Rule::musical_note => {
-                let (note_item, multiplier) = parse_musical_note_with_multiplier(inner_pair, parsed, last_duration, last_octave, last_pitch, mode)?;
+                let (note_item, multiplier) = parse_musical_note_with_multiplier(inner_pair.clone(), parsed, last_duration, last_octave, last_pitch, mode)?;
                 let mut note = note_item;
                 
                 // If the previous note has has_slur=true (from ~), mark this note as group_end
@@ -1630,26 +2761,38 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 if should_end_group {
                     note.group_end = true;
                 }
+                if let Some(tremolo) = stem_tremolo(&inner_pair) {
+                    note.tremolo = Some(tremolo);
+                }
                 
                 // Handle multiplier - repeat the note N times
+                let first = notes.len();
                 for _ in 0..multiplier {
                     notes.push(note.clone());
                 }
+                attach_navigation(&mut notes[first..], &inner_pair, parsed);
+                attach_properties(&mut notes[first..], parsed);
             },
             Rule::rest => {
-                let (rest, multiplier) = parse_rest_with_multiplier(inner_pair, last_duration)?;
+                let (rest, multiplier) = parse_rest_with_multiplier(inner_pair.clone(), last_duration)?;
                 // Handle multiplier - repeat the rest N times
+                let first = notes.len();
                 for _ in 0..multiplier {
                     notes.push(rest.clone());
                 }
+                attach_navigation(&mut notes[first..], &inner_pair, parsed);
+                attach_properties(&mut notes[first..], parsed);
             },
             Rule::multi_measure_rest => {
                 // Multi-measure rest 'R' - similar to regular rest but for multiple measures
-                let (multi_rest, multiplier) = parse_multi_measure_rest_with_multiplier(inner_pair, last_duration)?;
+                let (multi_rest, multiplier) = parse_multi_measure_rest_with_multiplier(inner_pair.clone(), last_duration)?;
                 // Handle multiplier - repeat the rest N times
+                let first = notes.len();
                 for _ in 0..multiplier {
                     notes.push(multi_rest.clone());
                 }
+                attach_navigation(&mut notes[first..], &inner_pair, parsed);
+                attach_properties(&mut notes[first..], parsed);
             },
             Rule::chord_repetition => {
                 // Chord repetition 'q' - repeats the last chord with new duration/attachments
@@ -1659,47 +2802,200 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                     .cloned();
                 
                 if let Some(last_chord) = last_chord {
-                    let (chord_rep, multiplier) = parse_chord_repetition_with_multiplier(inner_pair, last_duration, last_chord)?;
+                    let (mut chord_rep, multiplier) = parse_chord_repetition_with_multiplier(inner_pair.clone(), last_duration, last_chord)?;
+                    // Navigation marks and tremolo belong to the original chord, not to its repetitions
+                    chord_rep.navigation.clear();
+                    chord_rep.tremolo = stem_tremolo(&inner_pair);
                     // Handle multiplier - repeat the chord N times
+                    let first = notes.len();
                     for _ in 0..multiplier {
                         notes.push(chord_rep.clone());
                     }
+                    attach_navigation(&mut notes[first..], &inner_pair, parsed);
+                    attach_properties(&mut notes[first..], parsed);
                 } else {
                     return Err("Chord repetition 'q' used but no previous chord found".to_string());
                 }
             },
+            Rule::polyphony => {
+                // << { upper } \\ { lower } >>: every part is numbered as its voice and split off in
+                // organize_measures. Each part starts from the pitch before <<, and the music after
+                // >> goes on from the end of the first part
+                let (start_octave, start_pitch) = (*last_octave, last_pitch.clone());
+                let mut end = None;
+                for (index, part) in inner_pair.into_inner().enumerate() {
+                    let (mut octave, mut pitch) = (start_octave, start_pitch.clone());
+                    let first = notes.len();
+                    for item in part.into_inner() {
+                        match item.as_rule() {
+                            Rule::basic_music_sequence => {
+                                for seq_item in item.into_inner() {
+                                    parse_basic_music_item(seq_item, notes, parsed, last_duration, &mut octave, &mut pitch, mode)?;
+                                }
+                            },
+                            Rule::basic_music_item => {
+                                parse_basic_music_item(item, notes, parsed, last_duration, &mut octave, &mut pitch, mode)?;
+                            },
+                            _ => {}
+                        }
+                    }
+                    // Parts nested in a part keep their own numbers
+                    for note in notes[first..].iter_mut() {
+                        note.voice.get_or_insert(index as u32 + 1);
+                    }
+                    end.get_or_insert((octave, pitch));
+                }
+                if let Some((octave, pitch)) = end {
+                    *last_octave = octave;
+                    *last_pitch = pitch;
+                }
+            },
             Rule::angle_brackets => {
-                let (chord, multiplier): (LilyPondNote, u32) = parse_chord_with_multiplier(inner_pair, parsed, last_duration, last_octave, last_pitch, mode)?;
+                let (mut chord, multiplier): (LilyPondNote, u32) = parse_chord_with_multiplier(inner_pair.clone(), parsed, last_duration, last_octave, last_pitch, mode)?;
+                chord.tremolo = stem_tremolo(&inner_pair);
                 // Handle multiplier - repeat the chord N times
+                let first = notes.len();
                 for _ in 0..multiplier {
                     notes.push(chord.clone());
                 }
+                attach_navigation(&mut notes[first..], &inner_pair, parsed);
+                attach_properties(&mut notes[first..], parsed);
+            },
+            Rule::bar_line => {
+                // Bar check: the previous note must end exactly on a barline.
+                // Checked later in organize_notes_into_measures
+                if let Some(last_note) = notes.last_mut() {
+                    last_note.bar_checks.push(BarCheck {
+                        bar_number: None,
+                        span: SourceSpan::from_pair(&inner_pair),
+                    });
+                }
+            },
+            Rule::double_bar | Rule::final_bar => {
+                // `||` and `|.` are bar checks that also set the barline type
+                let barline = BarlineType::from_lilypond(inner_pair.as_str());
+                if let Some(last_note) = notes.last_mut() {
+                    last_note.bar_checks.push(BarCheck {
+                        bar_number: None,
+                        span: SourceSpan::from_pair(&inner_pair),
+                    });
+                    last_note.barline = Some(barline);
+                }
+            },
+            Rule::bar_command => {
+                // \bar "..." sets the barline after the previous note
+                let bar = inner_pair.into_inner()
+                    .find(|p| p.as_rule() == Rule::string_literal)
+                    .map(|p| p.as_str().trim_matches('"').to_string())
+                    .unwrap_or_default();
+                if let Some(last_note) = notes.last_mut() {
+                    last_note.barline = Some(BarlineType::from_lilypond(&bar));
+                }
+            },
+            Rule::mark_command => {
+                // \mark "D.C. al Fine", \mark \segno, \jump "D.S." ...
+                let mut mark = None;
+                for arg in inner_pair.into_inner() {
+                    mark = match arg.as_rule() {
+                        Rule::repeatsign => navigation_from_sign(arg.as_str()),
+                        Rule::string_literal => NavigationMark::from_text(arg.as_str().trim_matches('"')),
+                        Rule::markup_expression => NavigationMark::from_markup(&parse_markup(arg.as_str())),
+                        _ => mark,
+                    };
+                }
+                if let Some(mark) = mark {
+                    add_navigation_mark(notes, mark, parsed);
+                }
+            },
+            Rule::navigation_command => {
+                // \segnoMark, \codaMark and \fine
+                let keyword = inner_pair.into_inner()
+                    .find(|p| p.as_rule() == Rule::navigation_keyword)
+                    .map(|p| p.as_str().to_string())
+                    .unwrap_or_default();
+                let mark = match keyword.as_str() {
+                    "\\segnoMark" => Some(NavigationMark::Segno),
+                    "\\codaMark" => Some(NavigationMark::Coda),
+                    "\\fine" => Some(NavigationMark::Fine),
+                    _ => None,
+                };
+                if let Some(mark) = mark {
+                    add_navigation_mark(notes, mark, parsed);
+                }
+            },
+            Rule::bar_number_check => {
+                // \barNumberCheck #n: the bar starting after the previous note must be bar n
+                let span = SourceSpan::from_pair(&inner_pair);
+                let bar_number = inner_pair.into_inner()
+                    .find(|p| p.as_rule() == Rule::unsigned)
+                    .and_then(|p| p.as_str().parse::<u32>().ok());
+                if let (Some(bar_number), Some(last_note)) = (bar_number, notes.last_mut()) {
+                    last_note.bar_checks.push(BarCheck {
+                        bar_number: Some(bar_number),
+                        span,
+                    });
+                }
+            },
+            Rule::octave_check_command => {
+                // \octaveCheck c': the control pitch, taken relative to the previous note, must
+                // land in its written octave; afterwards it is the reference for the next note
+                if matches!(mode, OctaveMode::Relative) {
+                    let span = SourceSpan::from_pair(&inner_pair);
+                    let mut pitch = String::new();
+                    let mut octave = 3;
+                    for arg in inner_pair.into_inner() {
+                        match arg.as_rule() {
+                            Rule::note_name => pitch = get_standard_pitch(arg.as_str(), parsed.language.as_deref()),
+                            Rule::octave_modifier => octave = calculate_octave(arg.as_str()),
+                            _ => {}
+                        }
+                    }
+                    let resolved = calculate_relative_octave(&pitch, 3, last_octave, last_pitch);
+                    if resolved != octave {
+                        parsed.warnings.push(ParseWarning::new(
+                            WarningKind::OctaveCheckFailed,
+                            format!("Octave check failed: expected {}, found {}", pitch_with_octave(&pitch, octave), pitch_with_octave(&pitch, resolved)),
+                            Some(span),
+                        ));
+                    }
+                    *last_octave = octave;
+                    *last_pitch = pitch;
+                }
             },
             Rule::slur_start => {
                 // Mark the previous note as group_start (the note before the opening parenthesis)
                 if let Some(last_note) = notes.last_mut() {
                     last_note.group_start = true;
+                    last_note.slur_start = true;
+                    for pair in inner_pair.into_inner() {
+                        add_post_event(last_note, pair);
+                    }
                 }
             },
             Rule::slur_end => {
                 // Mark the previous note as group_end (the note before the closing parenthesis)
                 if let Some(last_note) = notes.last_mut() {
                     last_note.group_end = true;
+                    last_note.slur_end = true;
+                    for pair in inner_pair.into_inner() {
+                        add_post_event(last_note, pair);
+                    }
                 }
             },
             Rule::variable_reference => {
-                // Extract variable name from \variableName
-                let ref_str = inner_pair.as_str();
-                if ref_str.starts_with("\\") {
-                    let var_name = ref_str[1..].to_string();
-                    if let Some(variable) = parsed.variables.get(&var_name) {
-                        notes.extend(variable.base.notes.clone());                        
-                    }
-                }
+                parse_variable_reference(inner_pair, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
             },
             Rule::modal_transpose => {
                 parse_modal_transpose(inner_pair, notes, last_duration, parsed)?;
             },
+            Rule::music_mode => {
+                // A nested \relative/\fixed/\absolute block: resolved by its own mode, and it
+                // does not move the octave reference of the music around it
+                parse_music_mode(inner_pair, notes, parsed, last_duration)?;
+            },
+            Rule::transpose => {
+                parse_transpose(inner_pair, notes, parsed, last_duration, *last_octave, last_pitch)?;
+            },
             
             Rule::arpeggio => {
                 // Handle \arpeggio - mark the last note as having an arpeggio
@@ -1713,59 +3009,82 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
                 parse_repeat_volta(inner_pair, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
             },
             
-            Rule::grace_notes | Rule::acciaccatura_notes | Rule::appoggiatura_notes => {
-                // Handle grace notes, acciaccatura, and appoggiatura
+            Rule::grace_notes | Rule::acciaccatura_notes | Rule::appoggiatura_notes | Rule::slashed_grace_notes => {
+                // Handle grace notes, acciaccatura, appoggiatura and slashed grace notes
                 // These are ornamental notes that appear before the main note
-                // Mark all notes within grace blocks with note_type = Grace
+                // Mark all notes within grace blocks with note_type = Grace, keeping which kind they are
+                let (kind, slash) = match inner_pair.as_rule() {
+                    Rule::acciaccatura_notes => (GraceKind::Acciaccatura, true),
+                    Rule::appoggiatura_notes => (GraceKind::Appoggiatura, false),
+                    Rule::slashed_grace_notes => (GraceKind::Grace, true),
+                    _ => (GraceKind::Grace, false),
+                };
+                let first = notes.len();
                 for grace_part in inner_pair.into_inner() {
-                    if grace_part.as_rule() == Rule::basic_music_sequence {
-                        for seq_item in grace_part.into_inner() {
-                            if seq_item.as_rule() == Rule::basic_music_item {
-                                parse_basic_music_item(seq_item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
-                                
-                                // Mark the last added note as a grace note
-                                if let Some(last_note) = notes.last_mut() {
-                                    last_note.note_type = NoteType::Grace;
+                    match grace_part.as_rule() {
+                        Rule::basic_music_sequence => {
+                            for seq_item in grace_part.into_inner() {
+                                if seq_item.as_rule() == Rule::basic_music_item {
+                                    parse_basic_music_item(seq_item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
                                 }
                             }
-                        }
+                        },
+                        Rule::basic_music_item => {
+                            parse_basic_music_item(grace_part, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+                        },
+                        _ => {}
                     }
                 }
+                for note in notes[first..].iter_mut().filter(|n| is_timed_note(n)) {
+                    note.note_type = NoteType::Grace;
+                    note.grace = Some(kind);
+                    note.grace_slash = slash;
+                }
             },
             
             Rule::tuplet => {
-                // Handle \tuplet fraction { music }
-                // Parse the music sequence inside the tuplet
-                let mut tuplet_fraction: Option<String> = None;
+                // Handle \tuplet fraction { music } and \times fraction { music }, or a variable's music
+                // The music inside is parsed first, so nested tuplets already have their brackets
+                let first = notes.len();
+                let mut ratio = (1, 1);
+                let mut span = parsed.tuplet_span;
                 for tuplet_part in inner_pair.into_inner() {
                     match tuplet_part.as_rule() {
-                        Rule::tuplet_fraction => {
-                            // Parse the fraction (e.g., "3/2" for triplet)
-                            tuplet_fraction = Some(tuplet_part.as_str().to_string());
+                        Rule::tuplet_fraction | Rule::times_fraction => {
+                            // "3/2" for a triplet; \times writes it as "2/3"
+                            let (a, b) = tuplet_part.as_str().split_once('/').unwrap_or(("1", "1"));
+                            let (a, b) = (a.trim().parse::<u32>().unwrap_or(1), b.trim().parse::<u32>().unwrap_or(1));
+                            ratio = if tuplet_part.as_rule() == Rule::times_fraction { (b, a) } else { (a, b) };
+                        },
+                        Rule::duration => {
+                            let (duration, dots) = parse_duration(tuplet_part)?;
+                            span = duration_to_fraction(&duration, &dots).ok();
                         },
                         Rule::basic_music_sequence => {
                             for seq_item in tuplet_part.into_inner() {
                                 if seq_item.as_rule() == Rule::basic_music_item {
                                     parse_basic_music_item(seq_item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
-                                    // Mark all notes added in this tuplet block with the tuplet_fraction
-                                    if let Some(ref fraction) = tuplet_fraction {
-                                        if let Some(last_note) = notes.last_mut() {
-                                            last_note.tuplet_fraction = Some(fraction.clone());
-                                        }
-                                    }
                                 }
                             }
                         },
+                        Rule::variable_reference => {
+                            parse_variable_reference(tuplet_part, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+                        },
                         _ => {}
                     }
                 }
-                
-                
+                add_tuplet_groups(&mut notes[first..], ratio, span, parsed);
             },
             
             Rule::tuplet_span => {
-                // Ignore \tupletSpan command for now
-                // This sets the duration for automatic tuplet grouping
+                // \tupletSpan 4: later \tuplet brackets are split every quarter note
+                parsed.tuplet_span = match inner_pair.into_inner().find(|p| p.as_rule() == Rule::duration) {
+                    Some(duration) => {
+                        let (duration, dots) = parse_duration(duration)?;
+                        duration_to_fraction(&duration, &dots).ok()
+                    },
+                    None => None,
+                };
             },
             
             Rule::omit_command => {
@@ -1774,15 +3093,31 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
             },
             
             Rule::crescendo_start | Rule::decrescendo_start | Rule::dynamic_stop => {
-                // Ignore dynamic markings - they are visual annotations for crescendo/decrescendo
+                // Hairpins belong to the previous note
                 // \< = crescendo start, \> = decrescendo start, \! = dynamic stop
+                if let Some(last_note) = notes.last_mut() {
+                    last_note.hairpins.push(hairpin(&inner_pair));
+                }
             },
             
+            Rule::set_command | Rule::unset_command | Rule::override_command | Rule::revert_command | Rule::once_command => {
+                parse_property_command(inner_pair, parsed);
+            },
+            Rule::voice_command => {
+                parsed.pending_properties.push(stem_preset_event(&inner_pair));
+            },
+            Rule::change_staff => {
+                if let Some(name) = inner_pair.into_inner().next() {
+                    parsed.pending_staff_change = Some(name.as_str().trim_matches('"').to_string());
+                }
+            },
+            Rule::auto_change => {
+                parse_auto_change(inner_pair, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+            },
             Rule::custom_function_call => {
-                // Ignore custom function calls like \dynamictext "cresc."
-                // These are user-defined event functions that add visual annotations
-                // Example: \dynamictext "cresc." or \myOttava #1
-                println!("[parse_basic_music_item] Ignoring custom function call: {}", inner_pair.as_str());
+                // Music functions with a #{ #} template are expanded in place. Other calls keep
+                // their music arguments; event functions like \dynamictext "cresc." are ignored
+                parse_function_call(inner_pair, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
             },
             
             _ => {}
@@ -1791,6 +3126,92 @@ fn parse_basic_music_item(pair: pest::iterators::Pair<Rule>,
     Ok(())
 }
 
+// Navigation mark for a \segno / \coda / \varcoda sign
+fn navigation_from_sign(sign: &str) -> Option<NavigationMark> {
+    match sign {
+        "\\segno" => Some(NavigationMark::Segno),
+        "\\coda" | "\\varcoda" => Some(NavigationMark::Coda),
+        _ => None,
+    }
+}
+
+// Record a navigation mark written between notes (\mark, \segnoMark, ...)
+// Segno and Coda wait for the next note, the others go on the previous one
+fn add_navigation_mark(notes: &mut [LilyPondNote], mark: NavigationMark, parsed: &mut ParsedMusic) {
+    if mark.is_target() {
+        parsed.pending_navigation.push(mark);
+    } else if let Some(last_note) = notes.last_mut() {
+        last_note.navigation.push(mark);
+    }
+}
+
+// Put the timed notes of a \tuplet into brackets of ratio `(numerator, denominator)`, outside the
+// brackets they already have. With a span (\tuplet 3/2 4, or \tupletSpan), a new bracket starts
+// each time the notes have taken that much time
+fn add_tuplet_groups(notes: &mut [LilyPondNote], (numerator, denominator): (u32, u32), span: Option<f64>, parsed: &mut ParsedMusic) {
+    let scale = denominator as f64 / numerator as f64;
+    let mut elapsed = 0.0;
+    let mut current: Option<usize> = None; // Last note of the open bracket
+    for index in 0..notes.len() {
+        if !is_timed_note(&notes[index]) {
+            continue;
+        }
+        let length = note_fraction(&notes[index]).unwrap_or(0.0) * scale;
+        let full = span.is_some_and(|span| elapsed > span - 1e-9);
+        if current.is_none() || full {
+            if let Some(last) = current {
+                notes[last].tuplets[0].end = true;
+            }
+            parsed.tuplet_count += 1;
+            elapsed = 0.0;
+        }
+        let note = &mut notes[index];
+        note.tuplets.insert(0, TupletGroup { id: parsed.tuplet_count, numerator, denominator, start: elapsed == 0.0, end: false });
+        let (n, d) = note.tuplets.iter().fold((1, 1), |(n, d), group| (n * group.numerator, d * group.denominator));
+        note.tuplet_fraction = Some(format!("{}/{}", n, d));
+        elapsed += length;
+        current = Some(index);
+    }
+    if let Some(last) = current {
+        notes[last].tuplets[0].end = true;
+    }
+}
+
+// Property commands and \change Staff waiting for a note go on the first of the new notes
+fn attach_properties(notes: &mut [LilyPondNote], parsed: &mut ParsedMusic) {
+    if let Some(first) = notes.first_mut() {
+        first.properties.splice(0..0, parsed.pending_properties.drain(..));
+        if let Some(staff) = parsed.pending_staff_change.take() {
+            first.staff_change = Some(staff);
+        }
+    }
+}
+
+// Attach navigation marks to a newly parsed note (copies holds the note repeated by its multiplier):
+// marks waiting from \segnoMark etc., \segno/\coda signs on the note and text such as ^"D.C. al Fine"
+fn attach_navigation(copies: &mut [LilyPondNote], pair: &pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) {
+    if copies.is_empty() {
+        return;
+    }
+    let mut marks: Vec<NavigationMark> = parsed.pending_navigation.drain(..).collect();
+    marks.extend(pair.clone().into_inner().flatten()
+        .filter(|p| p.as_rule() == Rule::repeatsign)
+        .filter_map(|p| navigation_from_sign(p.as_str())));
+    marks.extend(copies[0].script_attachments.iter().filter_map(|script| match &script.content {
+        ScriptContent::Text(text) => NavigationMark::from_text(text),
+        ScriptContent::Markup(markup) => NavigationMark::from_markup(markup),
+        _ => None,
+    }));
+
+    // Targets mark the first copy, jumps happen after the last one
+    for mark in marks {
+        let target = if mark.is_target() { copies.first_mut() } else { copies.last_mut() };
+        if let Some(note) = target {
+            note.navigation.push(mark);
+        }
+    }
+}
+
 // Helper function to parse musical note with multiplier support
 fn parse_musical_note_with_multiplier(pair: pest::iterators::Pair<Rule>, 
     parsed: &mut ParsedMusic,
@@ -1842,9 +3263,17 @@ pub fn get_standard_pitch(pitch: &str, language: Option<&str>) -> String {
                 _ => pitch.to_string(),
             }
         },
-        Some("english") | None => {
-            // English/default: no conversion needed, already standard
-            pitch.to_string()
+        Some("english") => {
+            // English: s or -sharp for a sharp, f or -flat for a flat (fs = fis, bf = bes, css = cisis)
+            let Some((letter, suffix)) = pitch.get(..1).zip(pitch.get(1..)) else { return pitch.to_string() };
+            let suffix = match suffix {
+                "s" | "-sharp" => "is",
+                "ss" | "x" | "-sharpsharp" => "isis",
+                "f" | "-flat" => "es",
+                "ff" | "-flatflat" => "eses",
+                _ => suffix,
+            };
+            format!("{}{}", letter, suffix)
         },
         _ => {
             // For other languages, return as-is for now
@@ -1866,8 +3295,10 @@ fn parse_musical_note(pair: pest::iterators::Pair<Rule>,
     let mut duration = last_duration.clone();
     let mut dots = String::new();
     let mut has_slur = false;
-    let mut script_attachments = Vec::new();
     let mut accidental_modifier = None;
+    let mut octave_check = None;
+    let mut post_events = Vec::new();
+    let span = SourceSpan::from_pair(&pair);
     
     for inner_pair in pair.into_inner() {
         // println!("[DEBUG] parse_musical_note - Inner rule: {:?}, content: {}", inner_pair.as_rule(), inner_pair.as_str());
@@ -1883,6 +3314,10 @@ fn parse_musical_note(pair: pest::iterators::Pair<Rule>,
             Rule::accidental_modifier => {
                 accidental_modifier = Some(inner_pair.as_str().to_string());
             },
+            Rule::octave_check => {
+                let marks = inner_pair.into_inner().next().map(|p| p.as_str().to_string()).unwrap_or_default();
+                octave_check = Some(calculate_octave(&marks));
+            },
             Rule::duration => {
                 let (dur, d) = parse_duration(inner_pair)?;
                 duration = dur;
@@ -1896,23 +3331,18 @@ fn parse_musical_note(pair: pest::iterators::Pair<Rule>,
                 // Ignore repeat tie marks (\repeatTie) for now
                 // These indicate that a note is tied to the previous occurrence
             },
-            Rule::script_attachment => {
-                // Parse script attachments (^, _, - with optional text/markup)
-                // These are used for articulation marks, fingering (-1, -2, etc.), and text positioning
-                // Examples: ^"text", _markup, -1 (fingering), ^. (staccato above)
-                if let Ok(attachment) = parse_script_attachment(inner_pair) {
-                    script_attachments.push(attachment);
-                }
-            },
-            Rule::mark_attach_sign => {
-                // Ignore mark attachment signs for now
-                // These include: articulation commands (\accent, \staccato, etc.),
-                // ornaments (\trill, \mordent, etc.), fermatas, repeat signs (\segno, \coda),
-                // instrument signs (\upbow, \downbow, etc.), and dynamics (\pp, \ff, etc.)
+            Rule::script_attachment | Rule::mark_attach_sign => {
+                // Script attachments (^, _, - with optional text/markup) and marks, added to the
+                // note once it is built (see add_post_event)
+                // Examples: ^"text", _markup, -1 (fingering), ^. (staccato above), \trill, -\accent, \p
+                post_events.push(inner_pair);
             },
             Rule::multiplier => {
                 // Ignore multiplier - it's handled by parse_musical_note_with_multiplier
             },
+            Rule::stem_tremolo => {
+                // Handled in parse_basic_music_item
+            },
             _ => {
                 println!("[DEBUG] parse_musical_note - Unhandled inner rule: {:?}", inner_pair.as_rule());
             }
@@ -1950,6 +3380,17 @@ fn parse_musical_note(pair: pest::iterators::Pair<Rule>,
         },
         OctaveMode::Relative => {            
             octave = calculate_relative_octave(&pitch, octave, last_octave, last_pitch);
+            // Octave check (c='): warn and correct the octave if it came out differently
+            if let Some(expected) = octave_check {
+                if expected != octave {
+                    parsed.warnings.push(ParseWarning::new(
+                        WarningKind::OctaveCheckFailed,
+                        format!("Octave check failed: expected {}, found {}", pitch_with_octave(&pitch, expected), pitch_with_octave(&pitch, octave)),
+                        Some(span),
+                    ));
+                    octave = expected;
+                }
+            }
             *last_octave = octave;
             *last_pitch = pitch.clone();
             
@@ -1961,27 +3402,76 @@ fn parse_musical_note(pair: pest::iterators::Pair<Rule>,
     
     // println!("[DEBUG] parse_musical_note - Final result: pitch={}, octave_marks={}, octave={}, duration={}, dots={}", pitch, octave_marks, octave, duration, dots);
     
-    Ok(LilyPondNote {
+    let mut note = LilyPondNote {
         pitch,
         duration,
         octave,
         dots,
-        chord_notes: Vec::new(),
-        clef: None,
-        time_sig: None,
-        key_sig: None,
-        ottava: None,
-        arpeggio: false,
-        repeat_times: None,
-        note_type: NoteType::Default,
         group_start: has_slur,  // If this note has ~, it starts a slur
-        group_end: false,
         has_slur,  // Mark if this note has ~ marker
-        script_attachments,  // Store parsed script attachments
         accidental_modifier,  // Store accidental modifier if present
-        alternative_index: Vec::new(),
-        tuplet_fraction: None,
-    })
+        span: Some(span),
+        ..Default::default()
+    };
+    for pair in post_events {
+        add_post_event(&mut note, pair);
+    }
+    Ok(note)
+}
+
+// The name (without backslash) of a mark such as \trill or \accent, written on its own or
+// after a direction (-\trill, ^\accent)
+fn attached_mark(pair: &pest::iterators::Pair<Rule>) -> Option<String> {
+    let sign = if pair.as_rule() == Rule::mark_attach_sign {
+        pair.clone()
+    } else {
+        pair.clone().into_inner().find(|p| p.as_rule() == Rule::mark_attach_sign)?
+    };
+    let mark = sign.into_inner().next()?;
+    Some(mark.as_str().trim_start_matches('\\').to_string())
+}
+
+// Records a mark on a note: an ornament, articulation, fermata, dynamic or pedal, by its command
+// name without backslash ("trill", "staccato", "fermata", "mf", "sustainOn") or articulation
+// shorthand (".", ">")
+fn add_mark(note: &mut LilyPondNote, mark: &str) {
+    if let Some(ornament) = Ornament::from_name(mark) {
+        note.ornament = Some(ornament);
+    } else if let Some(articulation) = Articulation::from_name(mark) {
+        note.articulations.push(articulation);
+    } else if let Some(fermata) = Fermata::from_name(mark) {
+        note.fermata = Some(fermata);
+    } else if DYNAMICS.contains(&mark) {
+        note.dynamic = Some(mark.to_string());
+    } else if let Some(pedal) = PedalEvent::from_name(mark) {
+        note.pedals.push(pedal);
+    }
+}
+
+// Dynamic marks, as in the dynamic rule of the grammar
+const DYNAMICS: [&str; 19] = [
+    "ppppp", "pppp", "ppp", "pp", "p", "mp", "mf", "fffff", "ffff", "fff", "ff", "fp", "f", "sfz", "sff", "sf", "spp", "sp", "rfz",
+];
+
+// The hairpin event of \<, \> or \!
+fn hairpin(pair: &pest::iterators::Pair<Rule>) -> Hairpin {
+    match pair.as_rule() {
+        Rule::crescendo_start => Hairpin::Crescendo,
+        Rule::decrescendo_start => Hairpin::Decrescendo,
+        _ => Hairpin::Stop,
+    }
+}
+
+// A script attachment or mark written after a note (c4-.\p), or after a slur parenthesis (c4)-.)
+fn add_post_event(note: &mut LilyPondNote, pair: pest::iterators::Pair<Rule>) {
+    if let Some(mark) = attached_mark(&pair) {
+        add_mark(note, &mark);
+    } else if let Ok(attachment) = parse_script_attachment(pair) {
+        if let ScriptContent::Articulation(sign) = &attachment.content {
+            add_mark(note, sign);
+        }
+        note.script_attachments.push(attachment);
+    }
 }
 
 // Helper function to parse rest with multiplier support
@@ -2013,10 +3503,14 @@ fn parse_rest_with_multiplier(pair: pest::iterators::Pair<Rule>, last_duration:
 fn parse_rest(pair: pest::iterators::Pair<Rule>, last_duration: &mut String) -> Result<LilyPondNote, String> {
     let mut duration = last_duration.clone();
     let mut dots = String::new();
+    let span = SourceSpan::from_pair(&pair);
+    let mut spacer = false;
+    let mut hairpins = Vec::new();
+    let mut post_events = Vec::new();
     
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
-            Rule::rest_name => {}, // We know it's a rest
+            Rule::rest_name => spacer = inner_pair.as_str() == "s",
             Rule::duration => {
                 let (dur, d) = parse_duration(inner_pair)?;
                 duration = dur;
@@ -2027,33 +3521,30 @@ fn parse_rest(pair: pest::iterators::Pair<Rule>, last_duration: &mut String) ->
                 // Ignore multiplier - it's handled by parse_rest_with_multiplier
             },
             Rule::crescendo_start | Rule::decrescendo_start | Rule::dynamic_stop => {
-                // Ignore dynamic markings - they are visual annotations
+                hairpins.push(hairpin(&inner_pair));
+            },
+            Rule::script_attachment | Rule::mark_attach_sign => {
+                post_events.push(inner_pair);
             },
             _ => {}
         }
     }
     
-    Ok(LilyPondNote {
+    let mut rest = LilyPondNote {
         pitch: "r".to_string(), // Rest
         duration,
         octave: 0, // Rests don't have octaves
         dots,
-        chord_notes: Vec::new(),
-        clef: None,
-        time_sig: None,
-        key_sig: None,
-        ottava: None,
-        arpeggio: false,
-        repeat_times: None,
         note_type: NoteType::Rest,
-        group_start: false,
-        group_end: false,
-        has_slur: false,
-        script_attachments: Vec::new(),
-        accidental_modifier: None,
-        alternative_index: Vec::new(),
-        tuplet_fraction: None,
-    })
+        hairpins,
+        spacer,
+        span: Some(span),
+        ..Default::default()
+    };
+    for pair in post_events {
+        add_post_event(&mut rest, pair);
+    }
+    Ok(rest)
 }
 
 // Helper function to parse multi-measure rest with multiplier support
@@ -2085,6 +3576,7 @@ fn parse_multi_measure_rest_with_multiplier(pair: pest::iterators::Pair<Rule>, l
 fn parse_multi_measure_rest(pair: pest::iterators::Pair<Rule>, last_duration: &mut String) -> Result<LilyPondNote, String> {
     let mut duration = last_duration.clone();
     let mut dots = String::new();
+    let span = SourceSpan::from_pair(&pair);
     
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
@@ -2107,21 +3599,9 @@ fn parse_multi_measure_rest(pair: pest::iterators::Pair<Rule>, last_duration: &m
         duration,
         octave: 0, // Rests don't have octaves
         dots,
-        chord_notes: Vec::new(),
-        clef: None,
-        time_sig: None,
-        key_sig: None,
-        ottava: None,
-        arpeggio: false,
-        repeat_times: None,
         note_type: NoteType::Rest,
-        group_start: false,
-        group_end: false,
-        has_slur: false,
-        script_attachments: Vec::new(),
-        accidental_modifier: None,
-        alternative_index: Vec::new(),
-        tuplet_fraction: None,
+        span: Some(span),
+        ..Default::default()
     })
 }
 
@@ -2154,7 +3634,7 @@ fn parse_chord_repetition_with_multiplier(pair: pest::iterators::Pair<Rule>, las
 fn parse_chord_repetition(pair: pest::iterators::Pair<Rule>, last_duration: &mut String, last_chord: LilyPondNote) -> Result<LilyPondNote, String> {
     let mut duration = last_duration.clone();
     let mut dots = String::new();
-    let mut script_attachments = Vec::new();
+    let mut post_events = Vec::new();
     
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
@@ -2164,20 +3644,16 @@ fn parse_chord_repetition(pair: pest::iterators::Pair<Rule>, last_duration: &mut
                 dots = d;
                 *last_duration = duration.clone();
             },
-            Rule::script_attachment => {
-                // Parse script attachments (fingering like -1, -2, etc.)
-                let script = parse_script_attachment(inner_pair)?;
-                script_attachments.push(script);
-            },
-            Rule::mark_attach_sign => {
-                // Parse mark attachment sign (like -., ->, etc.)
-                // Mark attachment signs are parsed as script attachments
-                let script = parse_script_attachment(inner_pair)?;
-                script_attachments.push(script);
+            Rule::script_attachment | Rule::mark_attach_sign => {
+                // Script attachments (fingering like -1, -2, etc.) and marks (\trill, -\accent, \p ...)
+                post_events.push(inner_pair);
             },
             Rule::multiplier => {
                 // Ignore multiplier - it's handled by parse_chord_repetition_with_multiplier
             },
+            Rule::stem_tremolo => {
+                // Handled in parse_basic_music_item
+            },
             _ => {}
         }
     }
@@ -2186,9 +3662,20 @@ fn parse_chord_repetition(pair: pest::iterators::Pair<Rule>, last_duration: &mut
     let mut repeated_chord = last_chord.clone();
     repeated_chord.duration = duration;
     repeated_chord.dots = dots;
+    // q repeats the pitches, not the marks
+    repeated_chord.ornament = None;
+    repeated_chord.articulations.clear();
+    repeated_chord.fermata = None;
+    repeated_chord.dynamic = None;
+    repeated_chord.hairpins.clear();
+    repeated_chord.slur_start = false;
+    repeated_chord.slur_end = false;
+    repeated_chord.pedals.clear();
     
     // Combine existing script attachments with new ones
-    repeated_chord.script_attachments.extend(script_attachments);
+    for pair in post_events {
+        add_post_event(&mut repeated_chord, pair);
+    }
     
     Ok(repeated_chord)
 }
@@ -2230,13 +3717,15 @@ fn parse_chord(pair: pest::iterators::Pair<Rule>,
     last_octave: &mut i32,
     last_pitch: &mut String,
     mode: OctaveMode) -> Result<LilyPondNote, String> {
-    // New implementation using the grammar: angle_brackets = { "<" ~ musical_note+ ~ ">" ~ duration? ~ script_attachment* ~ mark_attach_sign? ~ multiplier? }
+    // New implementation using the grammar: angle_brackets = { "<" ~ musical_note+ ~ ">" ~ duration? ~ (script_attachment | mark_attach_sign)* ~ multiplier? }
     // We can now directly parse musical_note items from the grammar instead of manual string parsing
     
     let mut chord_notes: Vec<(String, i32)> = Vec::new();
     let mut duration = last_duration.clone();
     let mut dots = String::new();
     let mut first_note: Option<LilyPondNote> = None;
+    let mut post_events = Vec::new();
+    let span = SourceSpan::from_pair(&pair);
     
     // In LilyPond chords:
     // - In Fixed mode: all notes are relative to the fixed reference octave (not to previous notes)
@@ -2294,17 +3783,16 @@ fn parse_chord(pair: pest::iterators::Pair<Rule>,
                 dots = d;
                 *last_duration = duration.clone();
             },
-            Rule::script_attachment => {
-                // Ignore script attachments on the chord for now
-                // These apply to the entire chord (e.g., <c e g>^. for staccato on the whole chord)
-            },
-            Rule::mark_attach_sign => {
-                // Ignore mark attachment signs on the chord for now
-                // These apply to the entire chord
+            Rule::script_attachment | Rule::mark_attach_sign => {
+                // Script attachments and marks on the whole chord (e.g., <c e g>-. or <c e g>\trill)
+                post_events.push(inner_pair);
             },
             Rule::multiplier => {
                 // Ignore multiplier - it's handled by parse_chord_with_multiplier
             },
+            Rule::stem_tremolo => {
+                // Handled in parse_basic_music_item
+            },
             _ => {
                 println!("[DEBUG] parse_chord - Unhandled rule: {:?}", inner_pair.as_rule());
             }
@@ -2328,6 +3816,10 @@ fn parse_chord(pair: pest::iterators::Pair<Rule>,
         base_note.duration = duration;
         base_note.dots = dots;
         base_note.note_type = NoteType::Chord;
+        for pair in post_events {
+            add_post_event(&mut base_note, pair);
+        }
+        base_note.span = Some(span);
         Ok(base_note)
     } else {
         Err("Empty chord".to_string())
@@ -2370,8 +3862,7 @@ fn parse_script_attachment(pair: pest::iterators::Pair<Rule>) -> Result<ScriptAt
                             content = ScriptContent::Text(text);
                         },
                         Rule::markup_expression => {
-                            // Markup expression: store as-is for now
-                            content = ScriptContent::Markup(text_pair.as_str().to_string());
+                            content = ScriptContent::Markup(parse_markup(text_pair.as_str()));
                         },
                         Rule::articulation => {
                             // Articulation mark: ., -, >, etc.
@@ -2451,152 +3942,71 @@ fn calculate_octave(octave_marks: &str) -> i32 {
     })
 }
 
-fn convert_key_signature(note: &str, mode: &str) -> String {
-    match (note, mode) {
-        ("c", "\\major") => "C",
-        ("g", "\\major") => "G", 
-        ("d", "\\major") => "D",
-        ("a", "\\major") => "A",
-        ("e", "\\major") => "E",
-        ("b", "\\major") => "B",
-        ("fis", "\\major") => "F#",
-        ("cis", "\\major") => "C#",
-        ("f", "\\major") => "F",
-        ("bes", "\\major") => "Bb",
-        ("es", "\\major") => "Eb", 
-        ("aes", "\\major") => "Ab",
-        ("des", "\\major") => "Db",
-        ("ges", "\\major") => "Gb",
-        ("ces", "\\major") => "Cb",
-        ("a", "\\minor") => "Am",
-        ("e", "\\minor") => "Em",
-        ("b", "\\minor") => "Bm", 
-        ("fis", "\\minor") => "F#m",
-        ("cis", "\\minor") => "C#m",
-        ("gis", "\\minor") => "G#m",
-        ("dis", "\\minor") => "D#m",
-        ("ais", "\\minor") => "A#m",
-        ("d", "\\minor") => "Dm",
-        ("g", "\\minor") => "Gm",
-        ("c", "\\minor") => "Cm",
-        ("f", "\\minor") => "Fm",
-        ("bes", "\\minor") => "Bbm",
-        ("es", "\\minor") => "Ebm",
-        ("aes", "\\minor") => "Abm",
-        _ => "C", // Default to C major
-    }.to_string()
-}
-
-fn get_pitch_value(pitch: &str) -> i32 {
-    // Get the semitone value of a pitch within an octave (0-11)
-    // Support both standard and language-specific note names
-    let pitch_values = [
-        // Standard notes
-        ("c", 0), ("d", 2), ("e", 4), ("f", 5), ("g", 7), ("a", 9), ("b", 11),
-        // German: h = B natural, b = Bb
-        ("h", 11), 
-        // Sharps and flats (standard)
-        ("bis", 0), ("cis", 1), ("dis", 3), ("eis", 5), ("fis", 6), ("gis", 8), ("ais", 10),
-        ("ces", 11), ("des", 1), ("es", 3), ("ees", 3), ("fes", 4), ("ges", 6), ("as", 8), ("aes", 8), ("bes", 10),
-        // German h variants
-        ("his", 0), ("hes", 10),
-    ];
-    
-    pitch_values.iter()
-        .find(|(p, _)| pitch.starts_with(p))
-        .map(|(_, v)| *v)
-        .unwrap_or(0)
-}
-
 fn calculate_relative_octave(pitch: &str, specified_octave: i32, last_octave: &i32, last_pitch: &str) -> i32 {
-    // In LilyPond's relative mode, the octave is chosen such that the interval
-    // from the previous note is at most a perfect fourth (5 semitones).
-    // 
-    // IMPORTANT: If user explicitly specifies octave marks (' or ,), those take precedence!
+    // In LilyPond's relative mode, the octave is chosen by staff position, not by semitones:
+    // without octave marks the note is placed at most a fourth (three steps) away from the
+    // previous note, whatever the accidentals. So fis -> c goes down and b -> f goes down,
+    // although both are six semitones apart.
+    //
+    // Octave marks (' or ,) then move the note by whole octaves from that position.
     // For example: after "a", "c," means "go to c in a lower octave relative to a"
-    // 
-    // Strategy:
-    // 1. If specified_octave != 3 (i.e., user used ' or ,), use it as absolute override
-    // 2. Otherwise, choose octave based on minimizing interval from last note
-    
-    let current_pitch_value = get_pitch_value(pitch);
     
     // If this is the first note (no last_pitch), use the specified octave
     if last_pitch.is_empty() {
         return specified_octave;
     }
+    let (Some(current), Some(last)) = (parse_note_name(pitch), parse_note_name(last_pitch)) else {
+        return specified_octave;
+    };
     
-    // If user explicitly specified octave marks (specified_octave != 3, which is the default),
-    // those marks are RELATIVE to the last note's octave, not absolute
-    // For example: after a4, "c," means c in (a's octave - 1) = octave 3
-    if specified_octave != 3 {
-        // User specified explicit octave marks
-        // In relative mode, octave marks are adjustments relative to what would be chosen
-        let last_pitch_value = get_pitch_value(last_pitch);
-        let last_absolute_position = last_octave * 12 + last_pitch_value;
-        
-        // Find the "natural" octave (without marks) based on relative mode rules
-        let candidates = [
-            last_octave - 1,
-            *last_octave,
-            last_octave + 1,
-        ];
-        
-        let mut natural_octave = *last_octave;
-        let mut min_distance = i32::MAX;
-        
-        for &candidate_octave in &candidates {
-            let candidate_position = candidate_octave * 12 + current_pitch_value;
-            let distance = (candidate_position - last_absolute_position).abs();
-            
-            if distance < min_distance {
-                min_distance = distance;
-                natural_octave = candidate_octave;
-            } else if distance == min_distance && (candidate_octave - last_octave).abs() < (natural_octave - last_octave).abs() {
-                natural_octave = candidate_octave;
-            }
-        }
-        
-        // Apply the octave adjustment from the marks
-        // specified_octave - 3 gives the adjustment (e.g., c' = 4-3 = +1, c, = 2-3 = -1)
-        return natural_octave + (specified_octave - 3);
-    }
-    
-    // No explicit octave marks - use relative mode rules
-    let last_pitch_value = get_pitch_value(last_pitch);
-    let last_absolute_position = last_octave * 12 + last_pitch_value;
-    
-    let candidates = [
-        last_octave - 1,
-        *last_octave,
-        last_octave + 1,
-    ];
+    // Steps from the previous note to the same step in the previous note's octave
+    let steps = current.step as i32 - last.step as i32;
+    let natural_octave = if steps > 3 {
+        last_octave - 1
+    } else if steps < -3 {
+        last_octave + 1
+    } else {
+        *last_octave
+    };
     
-    let mut best_octave = *last_octave;
-    let mut min_distance = i32::MAX;
+    // specified_octave - 3 gives the adjustment (e.g., c' = 4-3 = +1, c, = 2-3 = -1)
+    natural_octave + (specified_octave - 3)
+}
+
+// Note name with LilyPond octave marks, e.g. ("c", 4) -> "c'"
+fn pitch_with_octave(pitch: &str, octave: i32) -> String {
+    let marks = if octave >= 3 { "'".repeat((octave - 3) as usize) } else { ",".repeat((3 - octave) as usize) };
+    format!("{}{}", pitch, marks)
+}
+
+fn parse_reference_octave(pair: pest::iterators::Pair<Rule>) -> Result<i32, String> {
+    let mut octave = 3;  // Base octave for c (middle C is c')
     
-    for &candidate_octave in &candidates {
-        let candidate_position = candidate_octave * 12 + current_pitch_value;
-        let distance = (candidate_position - last_absolute_position).abs();
-        
-        // Choose the octave with minimum distance
-        // In case of tie, prefer the one closer to last_octave (current default)
-        if distance < min_distance {
-            min_distance = distance;
-            best_octave = candidate_octave;
-        } else if distance == min_distance && (candidate_octave - last_octave).abs() < (best_octave - last_octave).abs() {
-            best_octave = candidate_octave;
+    for inner in pair.into_inner() {
+        match inner.as_rule() {
+            Rule::octave_modifier => {
+                let octave_str = inner.as_str();
+                let up_count = octave_str.matches('\'').count() as i32;
+                let down_count = octave_str.matches(',').count() as i32;
+                octave = 3 + up_count - down_count;
+            },
+            _ => {}
         }
     }
     
-    best_octave
+    Ok(octave)
 }
 
-fn parse_reference_octave(pair: pest::iterators::Pair<Rule>) -> Result<i32, String> {
+// Parse reference note and return (pitch, octave)
+fn parse_reference_note(pair: pest::iterators::Pair<Rule>, language: Option<&str>) -> Result<(String, i32), String> {
+    let mut pitch = String::from("c");  // Default to c
     let mut octave = 3;  // Base octave for c (middle C is c')
     
     for inner in pair.into_inner() {
         match inner.as_rule() {
+            Rule::note_name => {
+                pitch = get_standard_pitch(inner.as_str(), language);
+            },
             Rule::octave_modifier => {
                 let octave_str = inner.as_str();
                 let up_count = octave_str.matches('\'').count() as i32;
@@ -2606,32 +4016,482 @@ fn parse_reference_octave(pair: pest::iterators::Pair<Rule>) -> Result<i32, Stri
             _ => {}
         }
     }
-    
-    Ok(octave)
+    
+    Ok((pitch, octave))
+}
+
+// Looks up the variable named by a `\name` reference. A name the file defines but that is not
+// available here gets an UndefinedVariable warning; a name defined nowhere in the file is most
+// likely a LilyPond command the parser does not know, and only gets an UnknownCommand note
+fn lookup_variable(pair: &pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Option<Variable> {
+    lookup_variable_name(pair.as_str().trim_start_matches('\\'), SourceSpan::from_pair(pair), parsed)
+}
+
+fn lookup_variable_name(name: &str, span: SourceSpan, parsed: &mut ParsedMusic) -> Option<Variable> {
+    if let Some(variable) = parsed.variables.get(name) {
+        return Some(variable.clone());
+    }
+    let warning = if parsed.defined_names.contains(name) {
+        ParseWarning::new(WarningKind::UndefinedVariable, format!("Undefined variable \\{}", name), Some(span))
+    } else {
+        ParseWarning::new(WarningKind::UnknownCommand, format!("Unknown command \\{}, ignored", name), Some(span))
+    };
+    parsed.warnings.push(warning);
+    None
+}
+
+// \variableName in music: the variable's music, resolved for this place (see variable_notes);
+// a music function without parameters is called
+fn parse_variable_reference(
+    pair: pest::iterators::Pair<Rule>,
+    notes: &mut Vec<LilyPondNote>,
+    parsed: &mut ParsedMusic,
+    last_duration: &mut String,
+    last_octave: &mut i32,
+    last_pitch: &mut String,
+    mode: OctaveMode,
+) -> Result<(), String> {
+    let Some(variable) = lookup_variable(&pair, parsed) else { return Ok(()) };
+    if matches!(variable.value, VariableValue::MusicFunction(_)) {
+        return parse_function_call(pair, notes, parsed, last_duration, last_octave, last_pitch, mode);
+    }
+    insert_variable(variable, notes, parsed, last_octave, last_pitch, mode);
+    Ok(())
+}
+
+fn insert_variable(variable: Variable, notes: &mut Vec<LilyPondNote>, parsed: &mut ParsedMusic, last_octave: &mut i32, last_pitch: &mut String, mode: OctaveMode) {
+    let first = notes.len();
+    notes.extend(variable_notes(&variable, last_octave, last_pitch, mode));
+    attach_properties(&mut notes[first..], parsed);
+    parsed.pending_properties.extend(variable.properties);
+    parsed.pending_instrument.merge(variable.base.instrument);
+}
+
+// Names of all variables (and music functions) referenced inside a pair
+fn referenced_variables(pair: &pest::iterators::Pair<Rule>) -> Vec<String> {
+    pair.clone().into_inner().flatten()
+        .filter(|p| matches!(p.as_rule(), Rule::variable_reference | Rule::function_name))
+        .map(|p| p.as_str().trim_start_matches('\\').to_string())
+        .collect()
+}
+
+// Notes of a variable as used at this point of the music. Music entered in its own
+// \relative, \fixed or \absolute keeps its pitches and does not move the reference around it;
+// plain { } music was stored with absolute octaves and takes the mode of the place where it is used
+fn variable_notes(variable: &Variable, last_octave: &mut i32, last_pitch: &mut String, mode: OctaveMode) -> Vec<LilyPondNote> {
+    let mut notes = variable.base.notes.clone();
+    if variable.own_pitch_mode {
+        return notes;
+    }
+    for note in notes.iter_mut() {
+        if parse_note_name(&note.pitch).is_none() {
+            continue;
+        }
+        match mode {
+            OctaveMode::Relative => {
+                // Chord rule: each chord note follows the previous one, the next note follows the first
+                note.octave = calculate_relative_octave(&note.pitch, note.octave, last_octave, last_pitch);
+                let (mut chord_octave, mut chord_pitch) = (note.octave, note.pitch.clone());
+                for (pitch, octave) in note.chord_notes.iter_mut() {
+                    *octave = calculate_relative_octave(pitch, *octave, &chord_octave, &chord_pitch);
+                    chord_octave = *octave;
+                    chord_pitch = pitch.clone();
+                }
+                *last_octave = note.octave;
+                *last_pitch = note.pitch.clone();
+            },
+            OctaveMode::Fixed => {
+                // Octave marks count from the fixed reference octave
+                note.octave += *last_octave - 3;
+                for (_, octave) in note.chord_notes.iter_mut() {
+                    *octave += *last_octave - 3;
+                }
+            },
+            OctaveMode::Absolute => {}
+        }
+    }
+    notes
+}
+
+// Parse \transpose from to music. Pitches are resolved first and then moved by the interval
+// from -> to. \relative does not reach into \transpose: plain { } music inside is absolute unless
+// it has its own \relative, and the note after \transpose follows the pitch before it
+fn parse_transpose(
+    pair: pest::iterators::Pair<Rule>,
+    notes: &mut Vec<LilyPondNote>,
+    parsed: &mut ParsedMusic,
+    last_duration: &mut String,
+    last_octave: i32,
+    last_pitch: &str,
+) -> Result<(), String> {
+    let mut interval = Vec::new();
+    let mut music = Vec::new();
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::transpose_pitch => {
+                let (pitch, octave) = parse_reference_note(part, parsed.language.as_deref())?;
+                if let Some(pitch) = parse_note_name(&pitch) {
+                    interval.push((pitch, octave));
+                }
+            },
+            Rule::music_mode => parse_music_mode(part, &mut music, parsed, last_duration)?,
+            Rule::bare_music_block => {
+                let (mut octave, mut pitch) = (last_octave, last_pitch.to_string());
+                for block_pair in part.into_inner() {
+                    if block_pair.as_rule() == Rule::basic_music_sequence {
+                        for item in block_pair.into_inner() {
+                            parse_basic_music_item(item, &mut music, parsed, last_duration, &mut octave, &mut pitch, OctaveMode::Absolute)?;
+                        }
+                    }
+                }
+            },
+            Rule::variable_reference => {
+                if let Some(variable) = lookup_variable(&part, parsed) {
+                    let (mut octave, mut pitch) = (last_octave, last_pitch.to_string());
+                    music.extend(variable_notes(&variable, &mut octave, &mut pitch, OctaveMode::Absolute));
+                }
+            },
+            _ => {}
+        }
+    }
+    
+    if let [from, to] = interval[..] {
+        for note in music.iter_mut() {
+            if let Some((pitch, octave)) = transpose_pitch(&note.pitch, note.octave, from, to) {
+                note.pitch = pitch;
+                note.octave = octave;
+            }
+            for chord_note in note.chord_notes.iter_mut() {
+                if let Some(transposed) = transpose_pitch(&chord_note.0, chord_note.1, from, to) {
+                    *chord_note = transposed;
+                }
+            }
+            if let Some(key) = note.key_sig.as_deref().and_then(|key| transpose_key_name(key, from.0, to.0)) {
+                note.key_sig = Some(key);
+            }
+        }
+    }
+    notes.extend(music);
+    Ok(())
+}
+
+// Evaluates Scheme code with the file's definitions and variables in scope. Code outside the
+// supported subset is skipped as before; code that cannot work is reported
+fn eval_scheme(pair: &pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Option<SchemeValue> {
+    let variables = &parsed.variables;
+    let lookup = |name: &str| variables.get(name).and_then(|v| v.value.to_scheme());
+    let result = parsed.scheme.eval(pair.as_str(), &lookup);
+    if parsed.scheme.global_staff_size.is_some() {
+        parsed.global_staff_size = parsed.scheme.global_staff_size;
+    }
+    match result {
+        Ok(value) => Some(value),
+        Err(SchemeError::Unsupported(_)) => None,
+        Err(e) => {
+            parsed.warnings.push(ParseWarning::new(
+                WarningKind::InvalidScheme,
+                format!("{}: {}", pair.as_str(), e.message()),
+                Some(SourceSpan::from_pair(pair)),
+            ));
+            None
+        }
+    }
+}
+
+// Value of \set/\override as typed data: ##t, #(ly:make-moment 1/8), "text", 3, #'symbol ...
+fn property_value(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Option<SchemeValue> {
+    let value = pair.into_inner().next()?;
+    match value.as_rule() {
+        Rule::string_literal => Some(SchemeValue::String(value.as_str().trim_matches('"').to_string())),
+        Rule::identifier => Some(SchemeValue::Symbol(value.as_str().to_string())),
+        _ => eval_scheme(&value, parsed),
+    }
+}
+
+// \set, \unset, \override, \revert, and any of them after \once: recorded on the next note
+fn parse_property_command(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) {
+    let span = SourceSpan::from_pair(&pair);
+    let (command, once) = match pair.as_rule() {
+        Rule::once_command => match pair.into_inner().next() {
+            Some(command) => (command, true),
+            None => return,
+        },
+        _ => (pair, false),
+    };
+    let Some(mut event) = property_event(command, parsed) else { return };
+    event.once = once;
+    event.span = Some(span);
+    if event.action == PropertyAction::Set && !once {
+        apply_set_property(&event, parsed);
+    }
+    parsed.pending_properties.push(event);
+}
+
+// The property path and value of one command, e.g. \override Staff.Stem.direction = #UP
+fn property_event(command: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Option<PropertyEvent> {
+    let action = match command.as_rule() {
+        Rule::set_command => PropertyAction::Set,
+        Rule::unset_command => PropertyAction::Unset,
+        Rule::override_command => PropertyAction::Override,
+        Rule::revert_command => PropertyAction::Revert,
+        _ => return None,
+    };
+    let span = SourceSpan::from_pair(&command);
+    let mut path = Vec::new();
+    let mut value = None;
+    for part in command.into_inner() {
+        match part.as_rule() {
+            Rule::identifier => path.push(part.as_str().to_string()),
+            Rule::override_value | Rule::markup_expression => value = property_scheme_value(part, parsed),
+            _ => {}
+        }
+    }
+    Some(PropertyEvent::new(action, &path, value, Some(span)))
+}
+
+// \autoChange [pitch] music: every note from the split pitch (default c') up is shown on the
+// staff "up", every lower one on "down"; rests stay where the music is
+fn parse_auto_change(pair: pest::iterators::Pair<Rule>,
+    notes: &mut Vec<LilyPondNote>,
+    parsed: &mut ParsedMusic,
+    last_duration: &mut String,
+    last_octave: &mut i32,
+    last_pitch: &mut String,
+    mode: OctaveMode) -> Result<(), String> {
+    let first = notes.len();
+    let mut split = 4 * 7;
+    for item in pair.into_inner() {
+        match item.as_rule() {
+            Rule::auto_change_pitch => {
+                let (pitch, octave) = parse_reference_note(item, parsed.language.as_deref())?;
+                split = octave * 7 + parse_note_name(&pitch).map_or(0, |p| p.step as i32);
+            },
+            Rule::basic_music_sequence => {
+                for seq_item in item.into_inner() {
+                    parse_basic_music_item(seq_item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+                }
+            },
+            Rule::basic_music_item => {
+                parse_basic_music_item(item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+            },
+            _ => {}
+        }
+    }
+    let mut current = None;
+    for note in notes[first..].iter_mut() {
+        if !matches!(note.note_type, NoteType::Default | NoteType::Chord) || note.staff_change.is_some() {
+            continue;
+        }
+        let Some(pitch) = parse_note_name(&note.pitch) else { continue };
+        let staff = if note.octave * 7 + pitch.step as i32 >= split { "up" } else { "down" };
+        if current != Some(staff) {
+            current = Some(staff);
+            note.staff_change = Some(staff.to_string());
+        }
+    }
+    Ok(())
+}
+
+// \voiceOne and \stemUp turn stems up, \voiceTwo and \stemDown down; \oneVoice and \stemNeutral
+// go back to automatic stems. Kept as the Stem.direction override they stand for
+fn stem_preset_event(pair: &pest::iterators::Pair<Rule>) -> PropertyEvent {
+    let path = ["Stem".to_string(), "direction".to_string()];
+    let span = Some(SourceSpan::from_pair(pair));
+    match pair.as_str() {
+        "\\voiceOne" | "\\voiceThree" | "\\stemUp" => PropertyEvent::new(PropertyAction::Override, &path, Some(SchemeValue::Integer(1)), span),
+        "\\voiceTwo" | "\\voiceFour" | "\\stemDown" => PropertyEvent::new(PropertyAction::Override, &path, Some(SchemeValue::Integer(-1)), span),
+        _ => PropertyEvent::new(PropertyAction::Revert, &path, None, span),
+    }
+}
+
+// What a \set does besides being recorded
+fn apply_set_property(event: &PropertyEvent, parsed: &mut ParsedMusic) {
+    if event.property == "tempoWholesPerMinute" {
+        // \set Score.tempoWholesPerMinute = #(ly:make-moment 60 4): 15 whole notes, 60 quarters
+        if let Some(wholes) = event.value.as_ref().and_then(SchemeValue::as_f64) {
+            parsed.tempo_bpm = Some(wholes * 4.0);
+        }
+    }
+    if let (Some(context), Some(SchemeValue::String(text))) = (event.context.as_deref(), &event.value) {
+        // \set Staff.instrumentName = "Violin" names the staff it is in, which is only known
+        // once that staff (or the variable holding the music) is finished.
+        // \set PianoStaff.instrumentName names the innermost group of that type
+        if context == "Staff" {
+            parsed.pending_instrument.set(&event.property, text.clone());
+        } else if let Some(group_type) = StaffGroupType::from_lilypond(context) {
+            if let Some(group) = parsed.open_groups.iter_mut().rev().find(|g| g.group_type == group_type) {
+                group.instrument.set(&event.property, text.clone());
+            }
+        }
+    }
+}
+
+// Value of a context property as typed data: Scheme, a string, a markup (kept as its text)
+// or a variable
+fn property_scheme_value(pair: pest::iterators::Pair<Rule>, parsed: &mut ParsedMusic) -> Option<SchemeValue> {
+    match pair.as_rule() {
+        Rule::markup_expression => Some(SchemeValue::String(flatten_markup(pair.as_str()).text)),
+        Rule::string_literal => Some(SchemeValue::String(pair.as_str().trim_matches('"').to_string())),
+        Rule::property_reference => eval_scheme(&pair, parsed)
+            .or_else(|| Some(SchemeValue::Symbol(pair.as_str().trim_start_matches(['#', '\'']).to_string()))),
+        Rule::variable_reference => match lookup_variable(&pair, parsed)?.value {
+            VariableValue::Markup(markup) => Some(SchemeValue::String(markup.text)),
+            value => value.to_scheme(),
+        },
+        Rule::override_value => property_value(pair, parsed),
+        _ => None,
+    }
+}
+
+// The settings of \with { ... } for a context: property = value is a \set in that context,
+// \override and \revert apply to it unless they name another one
+fn context_properties(pair: pest::iterators::Pair<Rule>, context: &str, parsed: &mut ParsedMusic) -> Vec<PropertyEvent> {
+    let mut events = Vec::new();
+    for item in pair.into_inner().flat_map(|content| content.into_inner()) {
+        match item.as_rule() {
+            Rule::context_property => {
+                let span = SourceSpan::from_pair(&item);
+                let mut parts = item.into_inner();
+                if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                    let path = [context.to_string(), name.as_str().to_string()];
+                    let value = property_scheme_value(value, parsed);
+                    events.push(PropertyEvent::new(PropertyAction::Set, &path, value, Some(span)));
+                }
+            },
+            Rule::override_command | Rule::revert_command => {
+                if let Some(mut event) = property_event(item, parsed) {
+                    event.context.get_or_insert_with(|| context.to_string());
+                    events.push(event);
+                }
+            },
+            _ => {}
+        }
+    }
+    events
 }
 
-// Parse reference note and return (pitch, octave)
-fn parse_reference_note(pair: pest::iterators::Pair<Rule>, _language: Option<&str>) -> Result<(String, i32), String> {
-    let mut pitch = String::from("c");  // Default to c
-    let mut octave = 3;  // Base octave for c (middle C is c')
+// Nested music function calls deeper than this are reported instead of expanded
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+// \name arg...: calls a music function defined with a #{ #} template. The arguments are
+// substituted as written, the result is parsed as music in the current pitch mode, and
+// the notes and warnings it produces point at the call. `pair` is a custom_function_call, or
+// the variable_reference of a function called without arguments.
+// The function takes as many of the written arguments as it has parameters; the others are the
+// music that follows, where a variable may call a function in turn: \twice #2 { f } \twice { g }
+fn parse_function_call(
+    pair: pest::iterators::Pair<Rule>,
+    notes: &mut Vec<LilyPondNote>,
+    parsed: &mut ParsedMusic,
+    last_duration: &mut String,
+    last_octave: &mut i32,
+    last_pitch: &mut String,
+    mode: OctaveMode,
+) -> Result<(), String> {
+    let start = pair.as_span().start();
+    // \a \b is two pieces of music in a row: a name followed by a variable is a variable too
+    let mut is_variable = pair.as_rule() == Rule::variable_reference;
+    let mut head = None;
+    let mut rest = VecDeque::new();
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::function_name | Rule::variable_name => head = Some((part.as_str().to_string(), SourceSpan { start, end: part.as_span().end() })),
+            Rule::function_argument | Rule::bare_music_block | Rule::variable_reference => rest.push_back(part),
+            _ => {}
+        }
+    }
+    is_variable |= rest.front().is_some_and(|a| a.as_rule() == Rule::variable_reference);
     
-    for inner in pair.into_inner() {
-        match inner.as_rule() {
-            Rule::note_name => {
-                pitch = inner.as_str().to_string();
-                // Keep original pitch name, don't convert
+    while let Some((name, mut span)) = head.take() {
+        match parsed.variables.get(&name).map(|v| &v.value) {
+            Some(VariableValue::MusicFunction(function)) => {
+                let function = function.clone();
+                let args: Vec<_> = rest.drain(..function.params.len().min(rest.len())).collect();
+                // The call ends with its last argument, not with the whitespace after it
+                if let Some(last) = args.last() {
+                    span.end = last.as_span().end();
+                }
+                'call: {
+                    let problem = if args.len() != function.params.len() {
+                        Some(format!("\\{} takes {} arguments, {} given", name, function.params.len(), args.len()))
+                    } else if parsed.expansion_depth >= MAX_EXPANSION_DEPTH {
+                        Some(format!("\\{} is nested too deeply; is it calling itself?", name))
+                    } else {
+                        None
+                    };
+                    if let Some(message) = problem {
+                        parsed.warnings.push(ParseWarning::new(WarningKind::MusicFunctionError, message, Some(span)));
+                        break 'call;
+                    }
+                    
+                    let args: Vec<String> = args.iter().map(|a| a.as_str().to_string()).collect();
+                    let text = function.expand(&args);
+                    let expanded = match LilyPondParser::parse(Rule::expanded_music, &text) {
+                        Ok(pairs) => pairs,
+                        Err(e) => {
+                            parsed.warnings.push(ParseWarning::new(
+                                WarningKind::MusicFunctionError,
+                                format!("Cannot read the music of \\{}: {}", name, e.variant.message()),
+                                Some(span),
+                            ));
+                            break 'call;
+                        }
+                    };
+                    
+                    let first_note = notes.len();
+                    let first_warning = parsed.warnings.len();
+                    parsed.expansion_depth += 1;
+                    let mut result = Ok(());
+                    for item in expanded.flatten().filter(|p| p.as_rule() == Rule::basic_music_sequence).take(1).flat_map(|seq| seq.into_inner()) {
+                        result = parse_basic_music_item(item, notes, parsed, last_duration, last_octave, last_pitch, mode);
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                    parsed.expansion_depth -= 1;
+                    result?;
+                    
+                    // Spans inside the expanded text mean nothing in the file: point them at the call
+                    for note in notes[first_note..].iter_mut() {
+                        note.span = Some(span);
+                        for check in note.bar_checks.iter_mut() {
+                            check.span = span;
+                        }
+                        for event in note.properties.iter_mut() {
+                            event.span = Some(span);
+                        }
+                    }
+                    for warning in parsed.warnings[first_warning..].iter_mut() {
+                        warning.span = Some(span);
+                    }
+                }
             },
-            Rule::octave_modifier => {
-                let octave_str = inner.as_str();
-                let up_count = octave_str.matches('\'').count() as i32;
-                let down_count = octave_str.matches(',').count() as i32;
-                octave = 3 + up_count - down_count;
+            // Not a template function: the music written in its arguments is kept
+            _ if is_variable => {
+                if let Some(variable) = lookup_variable_name(&name, span, parsed) {
+                    insert_variable(variable, notes, parsed, last_octave, last_pitch, mode);
+                }
             },
-            _ => {}
+            _ => {},
+        }
+        
+        while let Some(arg) = rest.pop_front() {
+            match arg.as_rule() {
+                Rule::bare_music_block => {
+                    for item in arg.into_inner().flat_map(|seq| seq.into_inner()) {
+                        parse_basic_music_item(item, notes, parsed, last_duration, last_octave, last_pitch, mode)?;
+                    }
+                },
+                Rule::variable_reference => {
+                    head = Some((arg.as_str().trim_start_matches('\\').to_string(), SourceSpan::from_pair(&arg)));
+                    is_variable = true;
+                    break;
+                },
+                _ => {}
+            }
         }
     }
-    
-    Ok((pitch, octave))
+    Ok(())
 }
 
 // Parse modal transpose: \modalTranspose from_pitch to_pitch scale_ref music_expr
@@ -2791,9 +4651,12 @@ fn parse_new_voice(pair: pest::iterators::Pair<Rule>,
             time_signature: None,
             key_signature: None,
             notes: Vec::new(),
+            instrument: InstrumentNames::default(),
         },
         lyrics: Vec::new(),
         measures: Vec::new(),
+        performance_order: Vec::new(),
+        number: None,
     };
 
     for inner_pair in pair.into_inner() {
@@ -2820,12 +4683,12 @@ fn parse_new_voice(pair: pest::iterators::Pair<Rule>,
             },
             Rule::variable_reference => {
                 // Handle \variableName reference
-                let ref_str = inner_pair.as_str();
-                if ref_str.starts_with("\\") {
-                    let var_name = ref_str[1..].to_string();
-                    if let Some(variable) = parsed.variables.get(&var_name) {
-                        voice.base.notes.extend(variable.base.notes.clone());
-                    }
+                if let Some(variable) = lookup_variable(&inner_pair, parsed) {
+                    let first = voice.base.notes.len();
+                    voice.base.notes.extend(variable.base.notes);
+                    attach_properties(&mut voice.base.notes[first..], parsed);
+                    parsed.pending_properties.extend(variable.properties);
+                    parsed.pending_instrument.merge(variable.base.instrument);
                 }
             },
             _ => {}
@@ -2869,32 +4732,537 @@ fn parse_new_voice(pair: pest::iterators::Pair<Rule>,
 /// 将音符组织成小节
 /// 根据时间标记和音符时值，将 staff.notes 或 voice.notes 分组成小节
 /// 小节信息存放在 staff.measures 或 voice.measures 中，measure 包含该小节内音符的索引
+/// 同时计算展开反复和 D.C./D.S. 跳转后的演奏顺序（performance_order）
 fn organize_measures(parsed: &mut ParsedMusic) -> Result<(), String> {
+    // 每个 score 用自己的拍号、调号和弱起；不属于任何 score 的 staff 使用全局设置
+    let scores: Vec<Score> = parsed.scores().cloned().collect();
+    if scores.is_empty() {
+        // 没有 \score 时所有 staff 共用一个小节网格
+        parsed.measure_grid = organize_staves(&mut parsed.staves, &parsed.time_signature, &parsed.key_signature, &parsed.partial, &mut parsed.warnings)?;
+        return Ok(());
+    }
+    let mut organized = vec![false; parsed.staves.len()];
+    let mut grids = Vec::new();
+    for score in &scores {
+        let staves = &mut parsed.staves[score.staves.clone()];
+        grids.push(organize_staves(staves, &score.time_signature, &score.key_signature, &score.partial, &mut parsed.warnings)?);
+        organized[score.staves.clone()].fill(true);
+    }
+    let all_scores = parsed.books.iter_mut().flat_map(|book| book.parts.iter_mut()).flat_map(|part| part.scores.iter_mut());
+    for (score, grid) in all_scores.zip(grids) {
+        score.measure_grid = grid;
+    }
+    for (index, staff) in parsed.staves.iter_mut().enumerate() {
+        if !organized[index] {
+            organize_staves(std::slice::from_mut(staff), &parsed.time_signature, &parsed.key_signature, &parsed.partial, &mut parsed.warnings)?;
+        }
+    }
+    Ok(())
+}
+
+fn organize_staves(
+    staves: &mut [Staff],
+    time_signature: &Option<String>,
+    key_signature: &Option<String>,
+    partial: &Option<String>,
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<Vec<GridMeasure>, String> {
+    let mut layouts: Vec<MeasureLayout> = Vec::new();
+    // \change Staff 用名字找 staff
+    let names: Vec<Option<String>> = staves.iter().map(|s| s.base.name.clone()).collect();
     // 为每个 staff 组织小节
-    for staff in parsed.staves.iter_mut() {
+    for (index, staff) in staves.iter_mut().enumerate() {
+        // 先确定符干方向和显示的 staff，再把临时复调拆成 voice
+        assign_stem_directions(&mut staff.base.notes);
+        assign_display_staves(&mut staff.base.notes, index, &names, warnings);
+        for voice in staff.voices.iter_mut() {
+            assign_stem_directions(&mut voice.base.notes);
+            assign_display_staves(&mut voice.base.notes, index, &names, warnings);
+        }
+        split_polyphony(staff);
+        // 没有自己拍号的 staff/voice 使用 score 的拍号
+        let staff_time = staff.base.time_signature.clone().or_else(|| time_signature.clone());
+        let staff_key = staff.base.key_signature.clone().or_else(|| key_signature.clone());
         // 首先为 staff.notes 组织小节（如果没有 voice）
         if staff.voices.is_empty() {
-            organize_notes_into_measures(&staff.base.notes, &staff.base.time_signature, &parsed.partial, &mut staff.measures)?;
+            organize_notes_into_measures(&staff.base.notes, &staff_time, partial, &mut staff.measures, warnings)?;
+            staff.performance_order = performance_order(&staff.base.notes);
+            assign_accidentals(&mut staff.base.notes, &staff.measures, staff_key.as_deref());
+            layouts.push(MeasureLayout {
+                staff: index,
+                voice: None,
+                starts: measure_starts(&staff.base.notes, &staff.measures),
+                end: staff.base.notes.iter().map(timed_length).sum(),
+                meters: time_changes(&staff.base.notes, &staff.base.time_signature)?,
+            });
         } else {
             // 为每个 voice 组织小节
-            for voice in staff.voices.iter_mut() {
-                organize_notes_into_measures(&voice.base.notes, &voice.base.time_signature, &parsed.partial, &mut voice.measures)?;
+            for (voice_index, voice) in staff.voices.iter_mut().enumerate() {
+                let voice_time = voice.base.time_signature.clone().or_else(|| staff_time.clone());
+                organize_notes_into_measures(&voice.base.notes, &voice_time, partial, &mut voice.measures, warnings)?;
+                voice.performance_order = performance_order(&voice.base.notes);
+                let voice_key = voice.base.key_signature.clone().or_else(|| staff_key.clone());
+                assign_accidentals(&mut voice.base.notes, &voice.measures, voice_key.as_deref());
+                let own_time = voice.base.time_signature.clone().or_else(|| staff.base.time_signature.clone());
+                layouts.push(MeasureLayout {
+                    staff: index,
+                    voice: Some(voice_index),
+                    starts: measure_starts(&voice.base.notes, &voice.measures),
+                    end: voice.base.notes.iter().map(timed_length).sum(),
+                    meters: time_changes(&voice.base.notes, &own_time)?,
+                });
             }
         }
     }
-    Ok(())
+
+    let grid = measure_grid(&layouts, time_signature, partial)?;
+
+    // 每个小节指向网格中同一时刻开始的小节；不在网格小节线上开始的小节和小节数不同的 staff 或 voice 给出警告
+    for layout in &layouts {
+        let staff = &mut staves[layout.staff];
+        let (measures, notes) = match layout.voice {
+            Some(v) => {
+                let voice = &mut staff.voices[v];
+                (&mut voice.measures, &voice.base.notes)
+            }
+            None => (&mut staff.measures, &staff.base.notes),
+        };
+        let name = match layout.voice {
+            Some(v) => format!("Staff {}, voice {}", layout.staff + 1, v + 1),
+            None => format!("Staff {}", layout.staff + 1),
+        };
+        let mut next = 0;
+        for (number, (measure, &start)) in measures.iter_mut().zip(&layout.starts).enumerate() {
+            measure.grid = grid[next..].iter().position(|g| (g.start - start).abs() < 0.001).map(|i| next + i);
+            match measure.grid {
+                Some(index) => next = index + 1,
+                None => warnings.push(ParseWarning::new(
+                    WarningKind::MeasureOffGrid,
+                    format!("{}: measure {} starts at {} of the score, not on a barline of the score", name, number + 1, format_fraction(start)),
+                    measure.notes.iter().find_map(|&i| notes[i as usize].span),
+                )),
+            }
+        }
+        if measures.len() != grid.len() {
+            warnings.push(ParseWarning::new(
+                WarningKind::MeasureCountMismatch,
+                format!("{} has {} measures, the score has {}", name, measures.len(), grid.len()),
+                notes.iter().find_map(|n| n.span),
+            ));
+        }
+    }
+    Ok(grid)
+}
+
+/// 整个 score 共用的小节网格：从弱起开始，每个小节的长度由当时的拍号决定。
+/// 拍号变化来自所有 staff 和 voice（同一时刻以先出现的 staff 为准），没有的话用 score 的拍号；
+/// 所有 staff 和 voice 都在同一时刻开始新小节时（例如反复的房子结束时），网格也在那里开始新小节
+fn measure_grid(layouts: &[MeasureLayout], time_signature: &Option<String>, partial: &Option<String>) -> Result<Vec<GridMeasure>, String> {
+    // 同一时刻的拍号以先写出它的 staff 为准
+    let mut meters: Vec<(f64, f64)> = Vec::new();
+    for &(moment, length) in layouts.iter().flat_map(|layout| &layout.meters) {
+        if !meters.iter().any(|&(m, _)| (m - moment).abs() < 0.001) {
+            meters.push((moment, length));
+        }
+    }
+    if !meters.iter().any(|&(m, _)| m < 0.001) {
+        meters.push((0.0, parse_time_signature_fraction(time_signature)?));
+    }
+    meters.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let meter_at = |moment: f64| meters.iter().rev().find(|&&(m, _)| m < moment + 0.001).map_or(1.0, |&(_, length)| length);
+    // 所有 staff 和 voice 共有的小节线
+    let shared: Vec<f64> = layouts.first().map_or(Vec::new(), |first| first.starts.iter().copied()
+        .filter(|&start| layouts.iter().all(|l| l.starts.iter().any(|&s| (s - start).abs() < 0.001)))
+        .collect());
+    let end = layouts.iter().map(|l| l.end).fold(0.0, f64::max);
+    let last_start = layouts.iter().filter_map(|l| l.starts.last().copied()).fold(f64::NEG_INFINITY, f64::max);
+
+    let pickup = partial.as_deref().map_or(0.0, |p| duration_to_fraction(p, "").unwrap_or(0.0));
+    let first_bar_number: u32 = if pickup > 0.0 { 0 } else { 1 };
+    let mut grid = Vec::new();
+    let mut start = 0.0;
+    let mut length = if pickup > 0.0 { pickup } else { meter_at(0.0) };
+    while (start < end - 0.001 || start < last_start + 0.001) && length > 0.001 {
+        if let Some(&barline) = shared.iter().find(|&&b| b > start + 0.001 && b < start + length - 0.001) {
+            length = barline - start;
+        }
+        grid.push(GridMeasure { number: grid.len() as u32 + first_bar_number, start, length });
+        start += length;
+        length = meter_at(start);
+    }
+    Ok(grid)
+}
+
+/// 一个 staff 或 voice 的小节在时间上的位置
+struct MeasureLayout {
+    staff: usize,
+    voice: Option<usize>,
+    starts: Vec<f64>,          // 每个小节开始的时刻
+    end: f64,                  // 最后一个音符结束的时刻
+    meters: Vec<(f64, f64)>,   // 拍号：(开始的时刻, 小节长度)
+}
+
+/// 一个 staff 或 voice 写出的拍号：开头自己的拍号（如果有），然后是每个 \time 变化
+fn time_changes(notes: &[LilyPondNote], time_signature: &Option<String>) -> Result<Vec<(f64, f64)>, String> {
+    let mut changes = Vec::new();
+    if time_signature.is_some() {
+        changes.push((0.0, parse_time_signature_fraction(time_signature)?));
+    }
+    let mut time = 0.0;
+    for note in notes {
+        if note.note_type == NoteType::Time {
+            changes.push((time, parse_time_signature_fraction(&note.time_sig)?));
+        }
+        time += timed_length(note);
+    }
+    Ok(changes)
+}
+
+/// 每个小节开始的时刻（从乐曲开头算起的全音符数，按书写顺序，不展开反复）
+/// 以小节中第一个有时值的音符为准；合并过的小节中先出现的是它自己的音符
+pub(crate) fn measure_starts(notes: &[LilyPondNote], measures: &[Measure]) -> Vec<f64> {
+    let mut onsets = Vec::with_capacity(notes.len());
+    let mut time = 0.0;
+    for note in notes {
+        onsets.push(time);
+        time += timed_length(note);
+    }
+    measures.iter()
+        .map(|measure| {
+            let first = measure.notes.iter().find(|&&i| is_timed_note(&notes[i as usize])).or(measure.notes.first());
+            first.map_or(time, |&i| onsets[i as usize])
+        })
+        .collect()
+}
+
+/// 根据 Stem.direction（\voiceOne、\stemUp、\override Stem.direction 等）确定每个音符的符干方向
+/// << { } \\ { } >> 中的第 n 个声部开始时和 \voiceOne … \voiceFour 一样：奇数向上，偶数向下；
+/// 片段结束后回到片段之前的方向
+fn assign_stem_directions(notes: &mut [LilyPondNote]) {
+    let mut outer = None;
+    let mut inner = None;
+    let mut part = None;
+    for note in notes.iter_mut() {
+        if note.voice != part {
+            part = note.voice;
+            inner = part.map(|n| if n % 2 == 1 { StemDirection::Up } else { StemDirection::Down });
+        }
+        let current = if part.is_some() { &mut inner } else { &mut outer };
+        let mut once = None;
+        let events = note.properties.iter()
+            .filter(|e| e.property == "Stem.direction" && e.context.as_deref().is_none_or(|c| c == "Voice"));
+        for event in events {
+            let direction = match event.action {
+                PropertyAction::Set | PropertyAction::Override => match event.value.as_ref().and_then(SchemeValue::as_f64) {
+                    Some(value) if value > 0.0 => Some(StemDirection::Up),
+                    Some(value) if value < 0.0 => Some(StemDirection::Down),
+                    _ => None,
+                },
+                PropertyAction::Unset | PropertyAction::Revert => None,
+            };
+            if event.once {
+                once = Some(direction);
+            } else {
+                *current = direction;
+            }
+        }
+        if is_timed_note(note) {
+            note.stem_direction = once.unwrap_or(*current);
+        }
+    }
+}
+
+/// 根据 \change Staff 确定每个音符显示在哪个 staff 上（own 是音符所在 staff 的下标）
+/// 在自己的 staff 上时为 None；<< { } \\ { } >> 的声部从片段之前的 staff 开始，片段结束后回到那里
+fn assign_display_staves(notes: &mut [LilyPondNote], own: usize, names: &[Option<String>], warnings: &mut Vec<ParseWarning>) {
+    let mut outer = None;
+    let mut inner = None;
+    let mut part = None;
+    let mut unknown: Vec<String> = Vec::new();
+    for note in notes.iter_mut() {
+        if note.voice != part {
+            part = note.voice;
+            inner = outer;
+        }
+        let current = if part.is_some() { &mut inner } else { &mut outer };
+        if let Some(name) = &note.staff_change {
+            match names.iter().position(|n| n.as_deref() == Some(name.as_str())) {
+                Some(index) => *current = Some(index).filter(|&i| i != own),
+                None if !unknown.contains(name) => {
+                    warnings.push(ParseWarning::new(
+                        WarningKind::UndefinedStaff,
+                        format!("\\change Staff = \"{}\": there is no staff with this name", name),
+                        note.span,
+                    ));
+                    unknown.push(name.clone());
+                },
+                None => {}
+            }
+        }
+        if is_timed_note(note) {
+            note.display_staff = *current;
+        }
+    }
+}
+
+/// 拆分 << { } \\ { } >> 临时复调：第 1 声部留在原来的 voice 中，第 2、3… 声部成为新的 voice，
+/// 追加在 staff.voices 末尾（已有 voice 的下标不变）。没有 voice 的 staff 先把自己的音符变成第一个 voice
+fn split_polyphony(staff: &mut Staff) {
+    if staff.voices.is_empty() {
+        if staff.base.notes.iter().all(|n| n.voice.is_none()) {
+            return;
+        }
+        let base = MusicContainerBase {
+            name: None,
+            clef: staff.base.clef.clone(),
+            time_signature: staff.base.time_signature.clone(),
+            key_signature: staff.base.key_signature.clone(),
+            notes: std::mem::take(&mut staff.base.notes),
+            instrument: InstrumentNames::default(),
+        };
+        staff.voices.push(Voice { base, lyrics: Vec::new(), measures: Vec::new(), performance_order: Vec::new(), number: None });
+    }
+    for index in 0..staff.voices.len() {
+        let notes = std::mem::take(&mut staff.voices[index].base.notes);
+        let (main, others) = split_voices(notes);
+        let voice = &mut staff.voices[index];
+        voice.base.notes = main;
+        if others.is_empty() {
+            continue;
+        }
+        voice.number = Some(1);
+        let template = MusicContainerBase { notes: Vec::new(), name: None, instrument: InstrumentNames::default(), ..voice.base.clone() };
+        for (offset, notes) in others.into_iter().enumerate() {
+            staff.voices.push(Voice {
+                base: MusicContainerBase { notes, ..template.clone() },
+                lyrics: Vec::new(),
+                measures: Vec::new(),
+                performance_order: Vec::new(),
+                number: Some(offset as u32 + 2),
+            });
+        }
+    }
+}
+
+/// 一个临时声部：已有的音符，写到的时间点，以及下一个要看的 shadow 位置
+struct PartVoice {
+    notes: Vec<LilyPondNote>,
+    time: f64,
+    next: usize,
+}
+
+/// 把带 voice 编号的音符分成第 1 声部和其余声部
+/// shadow 是第 1 声部的时间线（复调片段处为最长的声部），全部换成 spacer 休止符，并保留拍号、调号、
+/// 谱号和反复等标记；其余声部在没有音符的地方从 shadow 中补齐，因此每个声部都有同样的小节
+fn split_voices(notes: Vec<LilyPondNote>) -> (Vec<LilyPondNote>, Vec<Vec<LilyPondNote>>) {
+    let mut shadow: Vec<(f64, LilyPondNote)> = Vec::new();
+    let mut main = Vec::new();
+    let mut others: Vec<PartVoice> = Vec::new();
+    let mut time = 0.0;
+    let mut notes = notes.into_iter().peekable();
+    while let Some(note) = notes.next() {
+        let Some(number) = note.voice else {
+            if note.note_type != NoteType::Grace {
+                shadow.push((time, as_spacer(&note)));
+            }
+            time += timed_length(&note);
+            main.push(note);
+            continue;
+        };
+        // 一个复调片段：声部编号依次递增，编号变小时是下一个片段
+        let mut parts: Vec<Vec<LilyPondNote>> = vec![Vec::new(); number as usize];
+        parts[number as usize - 1].push(note);
+        let mut current = number;
+        while let Some(number) = notes.peek().and_then(|n| n.voice) {
+            if number < current {
+                break;
+            }
+            current = number;
+            if parts.len() < number as usize {
+                parts.resize(number as usize, Vec::new());
+            }
+            parts[number as usize - 1].extend(notes.next());
+        }
+        let lengths: Vec<f64> = parts.iter().map(|part| part.iter().map(timed_length).sum()).collect();
+        let longest = (0..parts.len()).fold(0, |best, i| if lengths[i] > lengths[best] { i } else { best });
+        let length = lengths[longest];
+
+        let mut next = shadow.len();
+        let mut start = time;
+        for note in parts[longest].iter().filter(|n| n.note_type != NoteType::Grace) {
+            shadow.push((start, as_spacer(note)));
+            start += timed_length(note);
+        }
+        let mut parts = parts.into_iter();
+        main.extend(parts.next().unwrap_or_default());
+        main.extend(fill_from_shadow(&shadow, &mut next, time + lengths[0], time + length));
+        for (offset, part) in parts.enumerate() {
+            if others.len() <= offset {
+                others.push(PartVoice { notes: Vec::new(), time: 0.0, next: 0 });
+            }
+            let voice = &mut others[offset];
+            let padding = fill_from_shadow(&shadow, &mut voice.next, voice.time, time);
+            voice.notes.extend(padding);
+            voice.notes.extend(part);
+            voice.time = time + lengths[offset + 1];
+        }
+        time += length;
+    }
+    let others = others.into_iter()
+        .map(|mut voice| {
+            let padding = fill_from_shadow(&shadow, &mut voice.next, voice.time, f64::INFINITY);
+            voice.notes.extend(padding);
+            voice.notes
+        })
+        .collect();
+    (main, others)
+}
+
+/// 从 shadow 中取出 from 到 to 之间的 spacer 和标记；只有一部分在范围内的音符用更短的 spacer 补齐
+fn fill_from_shadow(shadow: &[(f64, LilyPondNote)], next: &mut usize, from: f64, to: f64) -> Vec<LilyPondNote> {
+    const EPSILON: f64 = 1e-9;
+    let mut result = Vec::new();
+    let mut time = from;
+    while let Some((start, note)) = shadow.get(*next) {
+        let length = timed_length(note);
+        if length == 0.0 {
+            if *start > to + EPSILON {
+                break;
+            }
+            if *start >= from - EPSILON {
+                result.push(note.clone());
+            }
+            *next += 1;
+            continue;
+        }
+        let end = start + length;
+        if *start >= to - EPSILON {
+            break;
+        }
+        if end <= time + EPSILON {
+            *next += 1;
+            continue;
+        }
+        if *start >= time - EPSILON && end <= to + EPSILON {
+            result.push(note.clone());
+            time = end;
+            *next += 1;
+            continue;
+        }
+        let stop = end.min(to);
+        result.extend(spacer_rests(note, stop - time));
+        time = stop;
+        if end > to + EPSILON {
+            break;
+        }
+        *next += 1;
+    }
+    result
+}
+
+/// 只占时值的 spacer 休止符，或去掉小节检查等内容的标记
+fn as_spacer(note: &LilyPondNote) -> LilyPondNote {
+    let mut spacer = note.clone();
+    spacer.bar_checks.clear();
+    spacer.navigation.clear();
+    spacer.properties.clear();
+    spacer.script_attachments.clear();
+    spacer.span = None;
+    spacer.voice = None;
+    spacer.staff_change = None;
+    spacer.display_staff = None;
+    spacer.tuplets.clear();
+    if is_timed_note(note) {
+        spacer.pitch = "r".to_string();
+        spacer.note_type = NoteType::Rest;
+        spacer.spacer = true;
+        spacer.chord_notes.clear();
+        spacer.accidentals.clear();
+        spacer.arpeggio = false;
+        spacer.tremolo = None;
+        spacer.percent_repeat = None;
+        spacer.has_slur = false;
+        spacer.group_start = false;
+        spacer.group_end = false;
+        spacer.stem_direction = None;
+    }
+    spacer
+}
+
+/// 占满 length 的 spacer 休止符，从全音符开始依次取能放下的最长时值
+fn spacer_rests(template: &LilyPondNote, mut length: f64) -> Vec<LilyPondNote> {
+    let mut rests = Vec::new();
+    for duration in ["1", "2", "4", "8", "16", "32", "64"] {
+        let value = duration_to_fraction(duration, "").unwrap_or(0.0);
+        while length > value - 1e-9 {
+            let mut rest = template.clone();
+            rest.duration = duration.to_string();
+            rest.dots = String::new();
+            rest.tuplet_fraction = None;
+            rest.tuplets.clear();
+            rests.push(rest);
+            length -= value;
+        }
+    }
+    rests
+}
+
+/// 音符占用的时值；标记为 0
+fn timed_length(note: &LilyPondNote) -> f64 {
+    if is_timed_note(note) { note_fraction(note).unwrap_or(0.0) } else { 0.0 }
+}
+
+/// 检查写在某个音符之后的小节线检查（`|` 和 `\barNumberCheck`）
+/// position: 该音符结束时在当前小节内的位置；capacity: 当前小节的容量
+/// current_bar: 当前小节的小节号（LilyPond 编号，弱起小节为 0）
+fn check_bar_checks(
+    note: &LilyPondNote,
+    position: f64,
+    capacity: f64,
+    current_bar: u32,
+    warnings: &mut Vec<ParseWarning>,
+) {
+    // 恰好位于小节线上：当前小节刚开始，或者已经填满
+    let at_start = position < 0.001;
+    let at_end = position > capacity - 0.001;
+    // 位于小节线上时，接下来的小节才是检查的对象
+    let bar_after = if at_end && !at_start { current_bar + 1 } else { current_bar };
+
+    for check in &note.bar_checks {
+        match check.bar_number {
+            None => {
+                if !at_start && !at_end {
+                    warnings.push(ParseWarning::new(
+                        WarningKind::BarCheckFailed,
+                        format!("Bar check failed in measure {}: at {} of {}", current_bar, format_fraction(position), format_fraction(capacity)),
+                        Some(check.span),
+                    ));
+                }
+            },
+            Some(expected) => {
+                if bar_after != expected {
+                    warnings.push(ParseWarning::new(
+                        WarningKind::BarNumberCheckFailed,
+                        format!("Bar number check failed: expected bar {}, found bar {}", expected, bar_after),
+                        Some(check.span),
+                    ));
+                }
+            },
+        }
+    }
 }
 
 /// 将一组音符分组成小节
-/// 返回每个小节包含的音符索引范围
+/// 小节（包含的音符索引）存入 measures，返回每个小节应有的时值
 fn organize_notes_into_measures(
     notes: &[LilyPondNote],
     time_signature: &Option<String>,
     partial: &Option<String>,
     measures: &mut Vec<Measure>,
-) -> Result<(), String> {
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<Vec<f64>, String> {
     // 解析时间标记获取小节的容量
-    let measure_capacity = parse_time_signature_fraction(time_signature)?;
+    let mut measure_capacity = parse_time_signature_fraction(time_signature)?;
     
     let mut current_measure_notes = Vec::new();
     let mut current_duration = 0.0;
@@ -2905,6 +5273,8 @@ fn organize_notes_into_measures(
     } else {
         0.0
     };
+    // 有弱起小节时，第一个完整小节为第 1 小节，弱起小节为第 0 小节
+    let first_bar_number: u32 = if partial_val > 0.0 { 0 } else { 1 };
     
     // 对于第一小节，如果有 partial，容量应该减少
     let mut current_capacity = if partial_val > 0.0 {
@@ -2912,16 +5282,44 @@ fn organize_notes_into_measures(
     } else {
         measure_capacity
     };
+    // 每个小节应有的时值，与 measures 一一对应
+    let mut capacities: Vec<f64> = Vec::new();
     
-    // 用于追踪 RepeatStart 和 alternative 区域
-    let mut repeat_start_measure_idx: Option<usize> = None;
-    let mut alternative_start_idx: Option<usize> = None;
-    let mut alternative_start_measure_idx: Option<usize> = None;
+    // 用于追踪 RepeatStart 和 alternative 区域；反复可以嵌套，所以用栈保存
+    // （反复开始所在小节、房子开始所在小节）
+    let mut repeat_start_measures: Vec<usize> = Vec::new();
+    let mut alternative_start_measures: Vec<usize> = Vec::new();
     
     for (idx, note) in notes.iter().enumerate() {
+        // 检查上一个音符之后的小节线检查
+        if idx > 0 {
+            let current_bar = measures.len() as u32 + first_bar_number;
+            check_bar_checks(&notes[idx - 1], current_duration, current_capacity, current_bar, warnings);
+        }
+
+        // 拍号变化：之后的小节使用新的容量
+        if note.note_type == NoteType::Time {
+            measure_capacity = parse_time_signature_fraction(&note.time_sig)?;
+            if current_duration > current_capacity - 0.001 && !current_measure_notes.is_empty() {
+                // 当前小节已满，拍号属于下一个小节
+                measures.push(Measure {
+                    notes: current_measure_notes.clone(),
+                    barline: BarlineType::Single,
+                    grid: None,
+                });
+                capacities.push(current_capacity);
+                current_measure_notes.clear();
+                current_duration = 0.0;
+                current_capacity = measure_capacity;
+            } else if current_duration < 0.001 && !(measures.is_empty() && partial_val > 0.0) {
+                current_capacity = measure_capacity;
+            }
+            current_measure_notes.push(idx as u32);
+            continue;
+        }
+
         // 跳过没有时值的标记（clef, time, key, ottava 等）
-        if note.note_type == NoteType::Time 
-            || note.note_type == NoteType::Key
+        if note.note_type == NoteType::Key
             || note.note_type == NoteType::Grace
             || note.note_type == NoteType::Ottava {
             // 这些标记不计算时间，直接添加到当前小节
@@ -2938,7 +5336,7 @@ fn organize_notes_into_measures(
         
         // 处理 RepeatStart 标记
         if note.note_type == NoteType::RepeatStart {
-            repeat_start_measure_idx = Some(measures.len());
+            repeat_start_measures.push(measures.len());
             current_measure_notes.push(idx as u32);
             continue;
         }
@@ -2948,7 +5346,10 @@ fn organize_notes_into_measures(
             if !current_measure_notes.is_empty() {
                 measures.push(Measure {
                     notes: current_measure_notes.clone(),
+                    barline: BarlineType::Single,
+                    grid: None,
                 });
+                capacities.push(current_capacity);
                 current_measure_notes.clear();
             }
             
@@ -2956,20 +5357,26 @@ fn organize_notes_into_measures(
             current_capacity = measure_capacity;
             current_duration = 0.0;
 
-            alternative_start_idx = Some(idx);
-            alternative_start_measure_idx = Some(measures.len());
+            alternative_start_measures.push(measures.len());
             current_measure_notes.push(idx as u32);
             continue;
         }
         
         // 处理 AlternativeEnd 标记
         if note.note_type == NoteType::AlternativeEnd {
-            
+            let alternative_start_measure = alternative_start_measures.pop();
             
             // 检查下一个节点是否为 RepeatEnd
             let next_is_repeat_end = idx + 1 < notes.len() && notes[idx + 1].note_type == NoteType::RepeatEnd;
             
-            if !next_is_repeat_end {
+            if !next_is_repeat_end && !has_timed_notes(&current_measure_notes, notes) && !measures.is_empty() {
+                // 房子的音符已经在上一个小节里结束（例如房子以嵌套反复结尾），
+                // 标记归入上一个小节，不单独成为一个没有音符的小节
+                if let Some(last_measure) = measures.last_mut() {
+                    last_measure.notes.append(&mut current_measure_notes);
+                    last_measure.notes.push(idx as u32);
+                }
+            } else if !next_is_repeat_end {
                 current_measure_notes.push(idx as u32);
                 
                 // 需要检查 alternative 区域最后一个小节是否不满
@@ -2979,13 +5386,16 @@ fn organize_notes_into_measures(
                 if !current_measure_notes.is_empty() {
                     measures.push(Measure {
                         notes: current_measure_notes.clone(),
+                        barline: BarlineType::Single,
+                        grid: None,
                     });
+                    capacities.push(current_capacity);
                     current_measure_notes.clear();
                     current_duration = 0.0;
                 }
                 
                 // 计算 alternative 区域最后一个小节的时值
-                if let (Some(alt_start_measure_idx), Some(_alt_start_idx)) = (alternative_start_measure_idx, alternative_start_idx) {
+                if let Some(alt_start_measure_idx) = alternative_start_measure {
                     let last_measure_idx = measures.len() - 1;
                     
                     // 只有当最后一个小节存在且时值不满时才处理
@@ -2994,7 +5404,7 @@ fn organize_notes_into_measures(
                         
                         if last_measure_duration < measure_capacity - 0.001 {
                             // 最后一个小节时值不满，需要合并 RepeatStart 所在小节
-                            if let Some(repeat_start_idx) = repeat_start_measure_idx {
+                            if let Some(&repeat_start_idx) = repeat_start_measures.last() {
                                 if repeat_start_idx < measures.len() {
                                     let repeat_start_duration = calculate_measure_duration(&measures[repeat_start_idx], notes)?;
                                     let combined_duration = last_measure_duration + repeat_start_duration;
@@ -3020,8 +5430,6 @@ fn organize_notes_into_measures(
                 
                 // 重置容量为正常小节容量
                 current_capacity = measure_capacity;
-                alternative_start_idx = None;
-                alternative_start_measure_idx = None;
             }
             continue;
         }
@@ -3029,11 +5437,19 @@ fn organize_notes_into_measures(
         // 处理 RepeatEnd 标记
         if note.note_type == NoteType::RepeatEnd {
             current_measure_notes.push(idx as u32);
-            repeat_start_measure_idx = None;
-            if !current_measure_notes.is_empty() {
+            repeat_start_measures.pop();
+            if !has_timed_notes(&current_measure_notes, notes) && !measures.is_empty() {
+                // 反复的最后一个小节已经保存（例如嵌套反复同时结束），标记归入上一个小节
+                if let Some(last_measure) = measures.last_mut() {
+                    last_measure.notes.append(&mut current_measure_notes);
+                }
+            } else if !current_measure_notes.is_empty() {
                 measures.push(Measure {
                     notes: current_measure_notes.clone(),
+                    barline: BarlineType::Single,
+                    grid: None,
                 });
+                capacities.push(current_capacity);
                 current_measure_notes.clear();
             }
             
@@ -3044,20 +5460,7 @@ fn organize_notes_into_measures(
         }
         
         // 计算当前音符的时值
-        let mut note_duration = duration_to_fraction(&note.duration, &note.dots)?;
-        
-        // 如果音符有 tuplet，则调整其时值
-        if let Some(ref tuplet_frac) = note.tuplet_fraction {
-            // tuplet_fraction 格式为 "n/m"，表示 n 个音符在 m 的时间内演奏
-            // 例如 "3/2" 表示 3 个音符在 2 个的时间内演奏（三联音）
-            let parts: Vec<&str> = tuplet_frac.split('/').collect();
-            if parts.len() == 2 {
-                if let (Ok(n), Ok(m)) = (parts[0].parse::<f64>(), parts[1].parse::<f64>()) {
-                    // 实际时值 = 原始时值 * (m / n)
-                    note_duration = note_duration * (m / n);
-                }
-            }
-        }
+        let note_duration = note_fraction(note)?;
         
         // 检查是否需要开始新的小节
         if current_duration + note_duration > current_capacity + 0.001 {
@@ -3065,7 +5468,10 @@ fn organize_notes_into_measures(
             if !current_measure_notes.is_empty() {
                 measures.push(Measure {
                     notes: current_measure_notes.clone(),
+                    barline: BarlineType::Single,
+                    grid: None,
                 });
+                capacities.push(current_capacity);
                 current_measure_notes.clear();
             }
             
@@ -3079,16 +5485,178 @@ fn organize_notes_into_measures(
         current_duration += note_duration;
     }
     
+    // 最后一个音符之后的小节线检查
+    if let Some(last_note) = notes.last() {
+        let current_bar = measures.len() as u32 + first_bar_number;
+        check_bar_checks(last_note, current_duration, current_capacity, current_bar, warnings);
+    }
+    
     // 保存最后一个小节
     if !current_measure_notes.is_empty() {
         measures.push(Measure {
             notes: current_measure_notes,
+            barline: BarlineType::Single,
+            grid: None,
         });
+        capacities.push(current_capacity);
     }
     
+    assign_barlines(notes, measures);
+    report_measure_fullness(notes, measures, &capacities, first_bar_number, warnings)?;
+    
+    Ok(capacities)
+}
+
+/// 为每个小节设置结尾的小节线类型
+/// 先根据 \repeat volta 的标记推断反复记号，再用显式的 \bar 覆盖
+fn assign_barlines(notes: &[LilyPondNote], measures: &mut [Measure]) {
+    let mut inferred: Vec<Option<BarlineType>> = vec![None; measures.len()];
+    let mut explicit: Vec<Option<BarlineType>> = vec![None; measures.len()];
+    // 每个音符所在的小节
+    let mut measure_of: HashMap<usize, usize> = HashMap::new();
+    for (measure_idx, measure) in measures.iter().enumerate() {
+        for &note_idx in &measure.notes {
+            measure_of.insert(note_idx as usize, measure_idx);
+        }
+    }
+
+    for (measure_idx, measure) in measures.iter().enumerate() {
+        for &note_idx in &measure.notes {
+            let idx = note_idx as usize;
+            let note = &notes[idx];
+            match note.note_type {
+                NoteType::RepeatStart => {
+                    // 反复开始记号画在反复的第一个音符所在小节之前；乐曲开头的反复不画
+                    // 上一小节刚好填满时，RepeatStart 标记本身会留在上一小节里
+                    let first_measure = notes.iter().enumerate().skip(idx + 1)
+                        .find(|(_, n)| is_timed_note(n))
+                        .and_then(|(i, _)| measure_of.get(&i).copied())
+                        .unwrap_or(measure_idx);
+                    if first_measure > 0 {
+                        let prev = &mut inferred[first_measure - 1];
+                        *prev = Some(match *prev {
+                            Some(BarlineType::EndRepeat) => BarlineType::EndStartRepeat,
+                            _ => BarlineType::StartRepeat,
+                        });
+                    }
+                },
+                NoteType::AlternativeEnd => {
+                    // 除最后一个房子外，每个房子结尾都是反复结束记号
+                    let is_last = notes.get(idx + 1).is_none_or(|n| n.note_type == NoteType::RepeatEnd);
+                    if !is_last {
+                        inferred[measure_idx] = Some(BarlineType::EndRepeat);
+                    }
+                },
+                NoteType::RepeatEnd => {
+                    // 有房子时反复结束记号已经在房子处理过
+                    let after_alternative = idx > 0 && notes[idx - 1].note_type == NoteType::AlternativeEnd;
+                    if !after_alternative {
+                        inferred[measure_idx] = Some(match inferred[measure_idx] {
+                            Some(BarlineType::StartRepeat) => BarlineType::EndStartRepeat,
+                            _ => BarlineType::EndRepeat,
+                        });
+                    }
+                },
+                _ => {}
+            }
+            if let Some(barline) = note.barline {
+                explicit[measure_idx] = Some(barline);
+            }
+        }
+    }
+
+    for (measure_idx, measure) in measures.iter_mut().enumerate() {
+        measure.barline = explicit[measure_idx]
+            .or(inferred[measure_idx])
+            .unwrap_or(BarlineType::Single);
+    }
+}
+
+/// 报告时值与拍号不符的小节
+/// 反复记号处被拆开的小节（反复、房子的首尾）本来就可能不满，不报告不满
+fn report_measure_fullness(
+    notes: &[LilyPondNote],
+    measures: &[Measure],
+    capacities: &[f64],
+    first_bar_number: u32,
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<(), String> {
+    for (measure_idx, (measure, &capacity)) in measures.iter().zip(capacities).enumerate() {
+        let duration = calculate_measure_duration(measure, notes)?;
+        let bar_number = measure_idx as u32 + first_bar_number;
+        // 用小节中第一个有位置的音符作为报告位置
+        let span = measure.notes.iter()
+            .find_map(|&i| notes[i as usize].span);
+        
+        if duration > capacity + 0.001 {
+            warnings.push(ParseWarning::new(
+                WarningKind::MeasureOverfull,
+                format!("Measure {} is overfull: {} instead of {}", bar_number, format_fraction(duration), format_fraction(capacity)),
+                span,
+            ));
+        } else if duration < capacity - 0.001 {
+            let split_at_repeat = measure.notes.iter().any(|&i| matches!(
+                notes[i as usize].note_type,
+                NoteType::RepeatStart | NoteType::RepeatEnd | NoteType::AlternativeStart | NoteType::AlternativeEnd
+            ));
+            if !split_at_repeat {
+                warnings.push(ParseWarning::new(
+                    WarningKind::MeasureUnderfull,
+                    format!("Measure {} is underfull: {} instead of {}", bar_number, format_fraction(duration), format_fraction(capacity)),
+                    span,
+                ));
+            }
+        }
+    }
     Ok(())
 }
 
+/// 计算一个音符实际占用的时值（考虑 tuplet）
+pub fn note_fraction(note: &LilyPondNote) -> Result<f64, String> {
+    let mut note_duration = duration_to_fraction(&note.duration, &note.dots)?;
+    
+    // 如果音符有 tuplet，则调整其时值
+    if let Some(ref tuplet_frac) = note.tuplet_fraction {
+        // tuplet_fraction 格式为 "n/m"，表示 n 个音符在 m 的时间内演奏
+        // 例如 "3/2" 表示 3 个音符在 2 个的时间内演奏（三联音）
+        let parts: Vec<&str> = tuplet_frac.split('/').collect();
+        if parts.len() == 2 {
+            if let (Ok(n), Ok(m)) = (parts[0].parse::<f64>(), parts[1].parse::<f64>()) {
+                // 实际时值 = 原始时值 * (m / n)
+                note_duration *= m / n;
+            }
+        }
+    }
+    
+    Ok(note_duration)
+}
+
+/// 将时值格式化为分数，例如 0.375 -> "3/8"
+fn format_fraction(value: f64) -> String {
+    for denominator in 1..=1536u32 {
+        let numerator = value * denominator as f64;
+        if (numerator - numerator.round()).abs() < 0.0001 {
+            return format!("{}/{}", numerator.round() as i64, denominator);
+        }
+    }
+    format!("{:.4}", value)
+}
+
+/// 是否为占用时值的音符（音符、休止符、和弦），而不是 clef、time、repeat 等标记
+pub fn is_timed_note(note: &LilyPondNote) -> bool {
+    !matches!(
+        note.note_type,
+        NoteType::Clef | NoteType::Time | NoteType::Key | NoteType::Grace | NoteType::Ottava
+            | NoteType::RepeatStart | NoteType::RepeatEnd
+            | NoteType::AlternativeStart | NoteType::AlternativeEnd
+    )
+}
+
+/// 一组音符索引中是否有占用时值的音符
+fn has_timed_notes(indices: &[u32], notes: &[LilyPondNote]) -> bool {
+    indices.iter().any(|&i| is_timed_note(&notes[i as usize]))
+}
+
 /// 计算一个小节的总时值
 fn calculate_measure_duration(measure: &Measure, notes: &[LilyPondNote]) -> Result<f64, String> {
     let mut duration = 0.0;
@@ -3097,31 +5665,11 @@ fn calculate_measure_duration(measure: &Measure, notes: &[LilyPondNote]) -> Resu
         let note = &notes[note_idx as usize];
         
         // 跳过没有时值的标记
-        if note.note_type == NoteType::Clef 
-            || note.note_type == NoteType::Time 
-            || note.note_type == NoteType::Key
-            || note.note_type == NoteType::Ottava
-            || note.note_type == NoteType::RepeatStart
-            || note.note_type == NoteType::RepeatEnd
-            || note.note_type == NoteType::AlternativeStart
-            || note.note_type == NoteType::AlternativeEnd {
+        if !is_timed_note(note) {
             continue;
         }
         
-        let mut note_duration = duration_to_fraction(&note.duration, &note.dots)?;
-        
-        // 如果音符有 tuplet，则调整其时值
-        if let Some(ref tuplet_frac) = note.tuplet_fraction {
-            let parts: Vec<&str> = tuplet_frac.split('/').collect();
-            if parts.len() == 2 {
-                if let (Ok(n), Ok(m)) = (parts[0].parse::<f64>(), parts[1].parse::<f64>()) {
-                    // 实际时值 = 原始时值 * (m / n)
-                    note_duration = note_duration * (m / n);
-                }
-            }
-        }
-        
-        duration += note_duration;
+        duration += note_fraction(note)?;
     }
     
     Ok(duration)
@@ -2,23 +2,83 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod lilypond_parser;
+mod playback;
+mod pitch;
+mod music_function;
+mod scheme;
+mod markup;
+mod properties;
+mod slice;
+mod performance;
+mod ornaments;
+mod expression;
+mod pedals;
 
 use tauri::Manager;
 use std::fs;
-use lilypond_parser::{parse_lilypond, ApiParsedMusic};
+use lilypond_parser::{parse_lilypond, ApiParsedMusic, Movement};
+use scheme::SchemeValue;
+use slice::{SliceQuery, SoundingNote};
+use performance::{PerformanceOptions, Timeline};
 
+// Without a movement the first \score is shown
 #[tauri::command]
-fn parse_lilypond_content(content: String) -> Result<ApiParsedMusic, String> {
+fn parse_lilypond_content(content: String, movement: Option<usize>) -> Result<ApiParsedMusic, String> {
     let parsed = parse_lilypond(&content)?;
-    Ok(ApiParsedMusic::from(parsed))
+    let mut music = match movement {
+        Some(index) => ApiParsedMusic::movement(parsed, index)?,
+        None => ApiParsedMusic::from(parsed),
+    };
+    music.timeline = performance::performance_timeline(&music, &PerformanceOptions::default());
+    Ok(music)
 }
 
 #[tauri::command]
-async fn parse_lilypond_file(file_path: String) -> Result<ApiParsedMusic, String> {
+async fn parse_lilypond_file(file_path: String, movement: Option<usize>) -> Result<ApiParsedMusic, String> {
     let content = fs::read_to_string(&file_path)
         .map_err(|e| format!("Failed to read file: {}", e))?;
     
-    parse_lilypond_content(content)
+    parse_lilypond_content(content, movement)
+}
+
+#[tauri::command]
+fn list_lilypond_movements(content: String) -> Result<Vec<Movement>, String> {
+    Ok(parse_lilypond(&content)?.movements())
+}
+
+// Value of a property (e.g. Staff.midiInstrument) at a moment in whole notes from the start,
+// in a staff or one of its voices
+#[tauri::command]
+fn get_property_at(
+    content: String,
+    movement: Option<usize>,
+    staff: usize,
+    voice: Option<usize>,
+    context: Option<String>,
+    property: String,
+    moment: f64,
+) -> Result<Option<SchemeValue>, String> {
+    let music = parse_lilypond_content(content, movement)?;
+    let staff = music.staves.get(staff).ok_or_else(|| format!("No staff {}", staff))?;
+    let notes = match voice {
+        Some(index) => &staff.voices.get(index).ok_or_else(|| format!("No voice {}", index))?.base.notes,
+        None => &staff.base.notes,
+    };
+    Ok(properties::property_at(notes, context.as_deref(), &property, moment))
+}
+
+// Notes sounding (or attacked) at a moment or during a stretch of time, in every staff and voice
+#[tauri::command]
+fn get_vertical_slice(content: String, movement: Option<usize>, query: SliceQuery) -> Result<Vec<SoundingNote>, String> {
+    let music = parse_lilypond_content(content, movement)?;
+    Ok(slice::vertical_slice(&music, &query))
+}
+
+// When each note is played, in seconds, with repeats played out and grace notes given their own time
+#[tauri::command]
+fn get_performance_timeline(content: String, movement: Option<usize>, options: Option<PerformanceOptions>) -> Result<Timeline, String> {
+    let music = parse_lilypond_content(content, movement)?;
+    Ok(performance::performance_timeline(&music, &options.unwrap_or_default()))
 }
 
 #[tauri::command]
@@ -64,6 +124,10 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             parse_lilypond_file,
             parse_lilypond_content,
+            list_lilypond_movements,
+            get_property_at,
+            get_vertical_slice,
+            get_performance_timeline,
             get_sample_lilypond
         ])
         .setup(|app| {
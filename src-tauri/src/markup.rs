@@ -0,0 +1,449 @@
+// Parsing of \markup into a tree that renderers can lay out, and its flattening into plain text
+// with light styling for titles and other header text:
+//   \markup { \bold Sonata \fontsize #-2 "in A" }  ->  "Sonata in A", bold 0..6, size -2 7..11
+use serde::{Deserialize, Serialize};
+
+/// Styling of part of a markup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum TextStyle {
+    Bold,
+    Italic,
+    FontSize(f64),        // Steps relative to the normal size, as in \fontsize: +1 is about 12% larger
+    Superscript,
+    Subscript,
+    Dynamic,              // The dynamics font of \dynamic "sfz"
+    Color(String),        // A CSS color: "red", "#ff8000"
+    FromProperty(String), // \fromproperty #'header:title, to be filled in by whoever shows the text
+}
+
+/// A style over the characters start..end of the text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StyleSpan {
+    pub start: usize,
+    pub end: usize,
+    pub style: TextStyle,
+}
+
+/// Markup as plain text, lines separated by '\n', with its styled ranges
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StyledText {
+    pub text: String,
+    pub spans: Vec<StyleSpan>,
+}
+
+/// Horizontal alignment of the lines of a column
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ColumnAlign {
+    Left,
+    Center,
+    Right,
+}
+
+/// A parsed markup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Markup {
+    Text { text: String },
+    Styled { style: TextStyle, markup: Box<Markup> },
+    Line { items: Vec<Markup> },     // Side by side, separated by a space: { ... }, \line, \wordwrap
+    Concat { items: Vec<Markup> },   // Side by side without space
+    FillLine { items: Vec<Markup> }, // Spread over the whole line width
+    Column { align: ColumnAlign, items: Vec<Markup> },
+    MusicGlyph { name: String },     // A glyph of the music font, e.g. "scripts.segno"
+    Note { duration: String, direction: f64 }, // \note {4.} #UP: a note head with its stem up (1) or down (-1)
+    HSpace { amount: f64 },          // In staff spaces
+    VSpace { amount: f64 },
+    FromProperty { property: String },
+    // Any other command, with its Scheme arguments as written and its markup argument if it takes one
+    Command { name: String, arguments: Vec<String>, markup: Option<Box<Markup>> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Command(String),
+    Open,
+    Close,
+    Text(String),   // A word or a quoted string
+    Scheme(String), // #2, #'header:title, #(rgb-color 1 0 0) ...
+}
+
+/// Parses markup source such as `\markup { \bold "Title" }` or `\markup "Title"`.
+/// A list of several markups is a line.
+pub fn parse_markup(source: &str) -> Markup {
+    let mut parser = Parser { tokens: tokenize(source), pos: 0 };
+    let mut items = parser.items(false);
+    if items.len() == 1 {
+        items.remove(0)
+    } else {
+        Markup::Line { items }
+    }
+}
+
+/// Flattens markup source to styled plain text
+pub fn flatten_markup(source: &str) -> StyledText {
+    parse_markup(source).to_styled_text()
+}
+
+impl Markup {
+    /// The text of the markup, lines separated by '\n', with its styled ranges.
+    /// Glyphs, notes and spaces show nothing.
+    pub fn to_styled_text(&self) -> StyledText {
+        let mut flattener = Flattener { out: StyledText::default(), length: 0 };
+        flattener.markup(self);
+        let mut out = flattener.out;
+        out.spans.sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+        out
+    }
+
+    /// Every music glyph of the markup, in order
+    pub fn glyphs(&self) -> Vec<&str> {
+        let mut glyphs = Vec::new();
+        self.visit(&mut |markup| {
+            if let Markup::MusicGlyph { name } = markup {
+                glyphs.push(name.as_str());
+            }
+        });
+        glyphs
+    }
+
+    fn visit<'a>(&'a self, f: &mut dyn FnMut(&'a Markup)) {
+        f(self);
+        match self {
+            Markup::Styled { markup, .. } | Markup::Command { markup: Some(markup), .. } => markup.visit(f),
+            Markup::Line { items } | Markup::Concat { items } | Markup::FillLine { items } | Markup::Column { items, .. } => {
+                for item in items {
+                    item.visit(f);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '%' => {
+                // Comment to the end of the line
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            },
+            '{' => {
+                tokens.push(Token::Open);
+                i += 1;
+            },
+            '}' => {
+                tokens.push(Token::Close);
+                i += 1;
+            },
+            '"' => {
+                let mut text = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i] == '\\' && i + 1 < chars.len() {
+                        i += 1;
+                    }
+                    text.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push(Token::Text(text));
+                i += 1;
+            },
+            '#' => {
+                // A Scheme argument: a balanced (...) or string, or everything up to the next space
+                let start = i;
+                i += 1;
+                while i < chars.len() && (chars[i] == '\'' || chars[i] == '#') {
+                    i += 1;
+                }
+                if i < chars.len() && chars[i] == '(' {
+                    let mut depth = 0;
+                    while i < chars.len() {
+                        match chars[i] {
+                            '(' => depth += 1,
+                            ')' => depth -= 1,
+                            _ => {}
+                        }
+                        i += 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                } else if i < chars.len() && chars[i] == '"' {
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        i += 1;
+                    }
+                    i += 1;
+                } else {
+                    while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '{' && chars[i] != '}' {
+                        i += 1;
+                    }
+                }
+                tokens.push(Token::Scheme(chars[start..i.min(chars.len())].iter().collect()));
+            },
+            '\\' => {
+                let start = i + 1;
+                i += 1;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '-' || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Command(chars[start..i].iter().collect()));
+            },
+            _ => {
+                let start = i;
+                while i < chars.len() && !chars[i].is_whitespace() && !"{}\"\\#".contains(chars[i]) {
+                    i += 1;
+                }
+                tokens.push(Token::Text(chars[start..i].iter().collect()));
+            },
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Markups up to the closing brace (or the end)
+    fn items(&mut self, braced: bool) -> Vec<Markup> {
+        let mut items = Vec::new();
+        while let Some(token) = self.tokens.get(self.pos) {
+            if *token == Token::Close {
+                self.pos += 1;
+                if braced {
+                    break;
+                }
+                // A stray closing brace: nothing to do with it
+                continue;
+            }
+            if let Some(markup) = self.markup() {
+                items.push(markup);
+            }
+        }
+        items
+    }
+
+    // One markup: a word, a string, a { list } or a command with its arguments
+    fn markup(&mut self) -> Option<Markup> {
+        // A command missing its argument must not take the brace that closes its list
+        let token = self.tokens.get(self.pos).cloned().filter(|t| *t != Token::Close)?;
+        self.pos += 1;
+        match token {
+            Token::Text(text) => Some(Markup::Text { text }),
+            Token::Open => Some(Markup::Line { items: self.items(true) }),
+            Token::Close | Token::Scheme(_) => None,
+            Token::Command(name) => self.command(name),
+        }
+    }
+
+    fn scheme_argument(&mut self) -> Option<String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Scheme(code)) => {
+                let code = code.clone();
+                self.pos += 1;
+                Some(code)
+            },
+            _ => None,
+        }
+    }
+
+    fn number_argument(&mut self) -> Option<f64> {
+        self.scheme_argument().and_then(|code| scheme_number(&code))
+    }
+
+    fn styled(&mut self, style: TextStyle) -> Option<Markup> {
+        let markup = self.markup()?;
+        Some(Markup::Styled { style, markup: Box::new(markup) })
+    }
+
+    // A { list } argument, or a single markup
+    fn list(&mut self) -> Vec<Markup> {
+        if self.tokens.get(self.pos) == Some(&Token::Open) {
+            self.pos += 1;
+            self.items(true)
+        } else {
+            self.markup().into_iter().collect()
+        }
+    }
+
+    fn command(&mut self, name: String) -> Option<Markup> {
+        match name.as_str() {
+            "markup" => self.markup(),
+            "bold" => self.styled(TextStyle::Bold),
+            "italic" => self.styled(TextStyle::Italic),
+            "fontsize" => match self.number_argument() {
+                Some(size) => self.styled(TextStyle::FontSize(size)),
+                None => self.markup(),
+            },
+            "teeny" => self.styled(TextStyle::FontSize(-3.0)),
+            "tiny" => self.styled(TextStyle::FontSize(-2.0)),
+            "small" | "smaller" => self.styled(TextStyle::FontSize(-1.0)),
+            "large" | "larger" => self.styled(TextStyle::FontSize(1.0)),
+            "huge" => self.styled(TextStyle::FontSize(2.0)),
+            "super" => self.styled(TextStyle::Superscript),
+            "sub" => self.styled(TextStyle::Subscript),
+            "dynamic" => self.styled(TextStyle::Dynamic),
+            "with-color" => match self.scheme_argument() {
+                Some(color) => self.styled(TextStyle::Color(css_color(&color))),
+                None => self.markup(),
+            },
+            "column" | "left-column" => Some(Markup::Column { align: ColumnAlign::Left, items: self.list() }),
+            "center-column" => Some(Markup::Column { align: ColumnAlign::Center, items: self.list() }),
+            "right-column" => Some(Markup::Column { align: ColumnAlign::Right, items: self.list() }),
+            "dir-column" | "general-column" => {
+                while self.scheme_argument().is_some() {}
+                Some(Markup::Column { align: ColumnAlign::Left, items: self.list() })
+            },
+            "concat" => Some(Markup::Concat { items: self.list() }),
+            "fill-line" => Some(Markup::FillLine { items: self.list() }),
+            "line" | "wordwrap" | "justify" | "center-align" => Some(Markup::Line { items: self.list() }),
+            "musicglyph" => {
+                let name = self.scheme_argument()?;
+                Some(Markup::MusicGlyph { name: scheme_text(&name) })
+            },
+            "note" => {
+                // The duration is written {4.} (LilyPond 2.22 and later) or #"4."
+                let duration = match self.tokens.get(self.pos) {
+                    Some(Token::Open) => {
+                        self.pos += 1;
+                        let mut duration = String::new();
+                        while let Some(token) = self.tokens.get(self.pos) {
+                            self.pos += 1;
+                            match token {
+                                Token::Close => break,
+                                Token::Text(text) => duration.push_str(text),
+                                _ => {},
+                            }
+                        }
+                        duration
+                    },
+                    _ => scheme_text(&self.scheme_argument()?),
+                };
+                let direction = self.number_argument().unwrap_or(1.0);
+                Some(Markup::Note { duration, direction })
+            },
+            "hspace" => Some(Markup::HSpace { amount: self.number_argument().unwrap_or(0.0) }),
+            "vspace" => Some(Markup::VSpace { amount: self.number_argument().unwrap_or(0.0) }),
+            "fromproperty" => {
+                let code = self.scheme_argument()?;
+                Some(Markup::FromProperty { property: code.trim_start_matches(['#', '\'']).to_string() })
+            },
+            // Commands that draw something other than text and take no markup
+            "null" | "char" | "note-by-number" | "strut" | "draw-line" | "draw-circle" | "draw-hline" | "epsfile"
+            | "filled-box" | "rest" | "rest-by-number" | "doubleflat" | "sesquiflat" | "flat" | "semiflat"
+            | "natural" | "semisharp" | "sharp" | "sesquisharp" | "doublesharp" | "fermata" | "segno" | "coda"
+            | "varcoda" => {
+                let mut arguments = Vec::new();
+                while let Some(code) = self.scheme_argument() {
+                    arguments.push(code);
+                }
+                Some(Markup::Command { name, arguments, markup: None })
+            },
+            // Anything else keeps its markup argument, e.g. \box "text" or \override #'(...) "text"
+            _ => {
+                let mut arguments = Vec::new();
+                while let Some(code) = self.scheme_argument() {
+                    arguments.push(code);
+                }
+                let markup = self.markup().map(Box::new);
+                Some(Markup::Command { name, arguments, markup })
+            },
+        }
+    }
+}
+
+// The number of a Scheme argument: #2, #-1.5, #UP, #DOWN
+fn scheme_number(code: &str) -> Option<f64> {
+    match code.trim_start_matches('#') {
+        "UP" | "RIGHT" => Some(1.0),
+        "DOWN" | "LEFT" => Some(-1.0),
+        "CENTER" => Some(0.0),
+        number => number.parse().ok(),
+    }
+}
+
+// The text of a Scheme string or symbol: #"scripts.segno" or #'name
+fn scheme_text(code: &str) -> String {
+    code.trim_start_matches(['#', '\'']).trim_matches('"').to_string()
+}
+
+// A CSS color for a Scheme color: #red, #"red", #(rgb-color 1 0.5 0), #(x11-color 'DarkBlue)
+fn css_color(code: &str) -> String {
+    let code = code.trim_start_matches('#');
+    if let Some(rgb) = code.strip_prefix("(rgb-color").and_then(|rest| rest.strip_suffix(')')) {
+        let channels: Vec<f64> = rgb.split_whitespace().filter_map(|c| c.parse().ok()).collect();
+        if channels.len() == 3 {
+            let byte = |c: f64| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+            return format!("#{:02x}{:02x}{:02x}", byte(channels[0]), byte(channels[1]), byte(channels[2]));
+        }
+    }
+    if let Some(name) = code.strip_prefix("(x11-color").and_then(|rest| rest.strip_suffix(')')) {
+        return scheme_text(name.trim()).to_lowercase();
+    }
+    scheme_text(code)
+}
+
+struct Flattener {
+    out: StyledText,
+    length: usize, // Length of out.text in characters
+}
+
+impl Flattener {
+    fn push_text(&mut self, text: &str) {
+        self.out.text.push_str(text);
+        self.length += text.chars().count();
+    }
+
+    // Items joined by `separator`; items that show nothing get no separator
+    fn items(&mut self, items: &[Markup], separator: &str) {
+        let mut first = true;
+        for item in items {
+            let (text_before, length_before, spans_before) = (self.out.text.len(), self.length, self.out.spans.len());
+            if !first {
+                self.push_text(separator);
+            }
+            let length_after = self.length;
+            self.markup(item);
+            if self.length == length_after && self.out.spans.len() == spans_before {
+                self.out.text.truncate(text_before);
+                self.length = length_before;
+            } else {
+                first = false;
+            }
+        }
+    }
+
+    fn markup(&mut self, markup: &Markup) {
+        match markup {
+            Markup::Text { text } => self.push_text(text),
+            Markup::Styled { style, markup } => {
+                let start = self.length;
+                self.markup(markup);
+                if self.length > start {
+                    self.out.spans.push(StyleSpan { start, end: self.length, style: style.clone() });
+                }
+            },
+            Markup::Line { items } | Markup::FillLine { items } => self.items(items, " "),
+            Markup::Concat { items } => self.items(items, ""),
+            Markup::Column { items, .. } => self.items(items, "\n"),
+            Markup::FromProperty { property } => {
+                let style = TextStyle::FromProperty(property.clone());
+                self.out.spans.push(StyleSpan { start: self.length, end: self.length, style });
+            },
+            Markup::Command { markup: Some(markup), .. } => self.markup(markup),
+            Markup::MusicGlyph { .. } | Markup::Note { .. } | Markup::HSpace { .. } | Markup::VSpace { .. }
+            | Markup::Command { markup: None, .. } => {},
+        }
+    }
+}
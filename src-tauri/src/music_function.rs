@@ -0,0 +1,111 @@
+// User-defined music functions whose body is a LilyPond template:
+//   name = #(define-music-function (m n) (ly:music? number?) #{ ... $m ... #n ... #})
+// A call substitutes the written arguments into the template; the result is parsed as music.
+
+/// A music function defined with a `#{ #}` template body
+#[derive(Debug, Clone, PartialEq)]
+pub struct MusicFunction {
+    pub params: Vec<String>,
+    pub predicates: Vec<String>,
+    pub body: String,
+}
+
+impl MusicFunction {
+    /// Reads `#(define-music-function (params) (predicates) #{ body #})`.
+    /// Returns None for anything else, including functions whose body is other Scheme code.
+    pub fn from_scheme(code: &str) -> Option<Self> {
+        let rest = code.trim().strip_prefix("#(")?.trim_start();
+        let rest = rest.strip_prefix("define-music-function")?.trim_start();
+        let (params, rest) = split_group(rest)?;
+        let (predicates, rest) = split_group(rest.trim_start())?;
+        let body = rest.trim().strip_suffix(')')?.trim_end().strip_prefix("#{")?.strip_suffix("#}")?;
+
+        let mut params: Vec<String> = params.split_whitespace().map(str::to_string).collect();
+        // Before LilyPond 2.18 every function started with (parser location ...)
+        if params.len() >= 2 && params[0] == "parser" && params[1] == "location" {
+            params.drain(..2);
+        }
+        let predicates = top_level_items(predicates);
+        if params.len() != predicates.len() {
+            return None;
+        }
+        Some(Self { params, predicates, body: body.to_string() })
+    }
+
+    /// The template with every `$param` and `#param` replaced by the argument as written
+    pub fn expand(&self, args: &[String]) -> String {
+        let mut result = String::new();
+        let mut rest = self.body.as_str();
+        while let Some(pos) = rest.find(['$', '#']) {
+            result.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+            // The longest parameter name that starts here and is not followed by more letters,
+            // so that $m-. is $m followed by -. and $my-music is a whole name
+            let param = self.params.iter().enumerate()
+                .filter(|(_, p)| !p.is_empty() && after.starts_with(p.as_str())
+                    && !after[p.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+                .max_by_key(|(_, p)| p.len());
+            match param {
+                Some((index, p)) => {
+                    // #param is already in Scheme: a Scheme argument goes in without its own #
+                    let arg = args[index].as_str();
+                    let arg = if rest[pos..].starts_with('#') { arg.strip_prefix('#').unwrap_or(arg) } else { arg };
+                    result.push_str(arg);
+                    rest = &after[p.len()..];
+                },
+                None => {
+                    result.push_str(&rest[pos..pos + 1]);
+                    rest = after;
+                },
+            }
+        }
+        result.push_str(rest);
+        result
+    }
+}
+
+// Splits "(a b (c)) rest" into ("a b (c)", " rest")
+fn split_group(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with('(') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&text[1..i], &text[i + 1..]));
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+// Whitespace-separated items, keeping parenthesized items such as (number? 3) together
+fn top_level_items(text: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in text.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        if c.is_whitespace() && depth == 0 {
+            if !current.is_empty() {
+                items.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        items.push(current);
+    }
+    items
+}
@@ -0,0 +1,155 @@
+// Ornament realisation: the notes actually played for \trill, \prall, \mordent, \turn ...
+// Each ornament is a pattern of steps around the written note (1 the note above, 0 the note
+// itself, -1 the note below); the quick notes last `ornament_speed`, the last one takes what is left:
+//   c'4\prall   ->  d' c' d' c'~~~     (starting on the upper note)
+//   c'4\mordent ->  c' b c'~~~~~
+//   c'4\turn    ->  d' c' b c'~~~
+// A trill alternates for the whole length of the note, ending on the note itself.
+// The notes above and below follow the key signature, unless an accidental is written with the
+// ornament (c'4\turn ^\markup \flat _\markup \sharp: above for the upper note, below for the lower).
+use serde::{Deserialize, Serialize};
+
+use crate::lilypond_parser::{LilyPondNote, Ornament, ScriptContent, ScriptDirection};
+use crate::markup::Markup;
+use crate::performance::PerformanceOptions;
+use crate::pitch::{key_alterations, midi_key, parse_note_name, standard_name, Pitch};
+
+/// The note a trill or short trill begins on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum OrnamentStart {
+    #[default]
+    Upper, // On the note above, as in the 18th century
+    Main,  // On the written note
+}
+
+/// One note of a realised ornament
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct OrnamentNote {
+    pub key: u8,
+    pub onset: f64, // Whole notes
+    pub end: f64,
+}
+
+// Steps of each ornament, starting on the upper note. Variants follow the hooks of their sign:
+// upprall/downprall come from below/above, prallup ends from below, pralldown falls at the end
+fn pattern(ornament: Ornament) -> &'static [i32] {
+    match ornament {
+        Ornament::Trill => &[1, 0],
+        Ornament::Prall => &[1, 0, 1, 0],
+        Ornament::PrallPrall | Ornament::LinePrall => &[1, 0, 1, 0, 1, 0],
+        Ornament::PrallMordent | Ornament::PrallUp => &[1, 0, 1, 0, -1, 0],
+        Ornament::PrallDown => &[1, 0, 1, 0, 1, -1, 0],
+        Ornament::UpPrall => &[-1, 0, 1, 0, 1, 0],
+        Ornament::DownPrall => &[1, 0, -1, 0, 1, 0],
+        Ornament::Mordent => &[0, -1, 0],
+        Ornament::UpMordent => &[-1, 0, -1, 0],
+        Ornament::DownMordent => &[1, 0, -1, 0],
+        Ornament::Turn | Ornament::SlashTurn | Ornament::HaydnTurn => &[1, 0, -1, 0],
+        Ornament::ReverseTurn => &[-1, 0, 1, 0],
+    }
+}
+
+// Ornaments that may begin on the written note instead of the one above
+fn is_trill_like(ornament: Ornament) -> bool {
+    matches!(ornament, Ornament::Trill | Ornament::Prall | Ornament::PrallPrall | Ornament::LinePrall
+        | Ornament::PrallMordent | Ornament::PrallUp | Ornament::PrallDown)
+}
+
+/// The notes played for `ornament` on the head `(name, octave)` from `onset` to `end` (whole notes).
+/// `fifths` is the key signature in effect and `note` the ornamented note, for its accidental markup
+pub(crate) fn realise_ornament(
+    ornament: Ornament,
+    (name, octave): (&str, i32),
+    note: &LilyPondNote,
+    fifths: i32,
+    (onset, end): (f64, f64),
+    options: &PerformanceOptions,
+) -> Vec<OrnamentNote> {
+    let Some(main) = parse_note_name(name) else { return Vec::new() };
+    let (upper_alter, lower_alter) = markup_accidentals(note, ornament);
+    let alterations = key_alterations(fifths);
+    let neighbour = |step: i32, alter: Option<i32>| -> Option<u8> {
+        let position = main.step as i32 + step;
+        let step = position.rem_euclid(7) as usize;
+        let pitch = Pitch { step, alter: alter.unwrap_or(alterations[step]) };
+        midi_key(&standard_name(pitch), octave + position.div_euclid(7))
+    };
+    let (Some(key), Some(upper), Some(lower)) = (midi_key(name, octave), neighbour(1, upper_alter), neighbour(-1, lower_alter)) else {
+        return Vec::new();
+    };
+    let key_of = |step: i32| match step {
+        1 => upper,
+        -1 => lower,
+        _ => key,
+    };
+
+    let length = end - onset;
+    let mut steps = pattern(ornament).to_vec();
+    if options.ornament_start == OrnamentStart::Main && is_trill_like(ornament) {
+        // A trill keeps alternating: main, upper, main ...
+        if ornament == Ornament::Trill {
+            steps.rotate_left(1);
+        } else {
+            steps.remove(0);
+        }
+    }
+    if ornament == Ornament::Trill {
+        // As many notes as fit at this speed, ending on the written note
+        let mut count = ((length / options.ornament_speed).floor() as usize).max(2);
+        if steps[(count - 1) % steps.len()] != 0 {
+            count += 1;
+        }
+        let part = length / count as f64;
+        return (0..count)
+            .map(|i| OrnamentNote { key: key_of(steps[i % steps.len()]), onset: onset + part * i as f64, end: onset + part * (i + 1) as f64 })
+            .collect();
+    }
+
+    // Quick notes, then the last one held; all equal when the note is too short
+    let quick = options.ornament_speed.min(length / steps.len() as f64);
+    let mut notes: Vec<OrnamentNote> = steps.iter().enumerate()
+        .map(|(i, &step)| OrnamentNote { key: key_of(step), onset: onset + quick * i as f64, end: onset + quick * (i + 1) as f64 })
+        .collect();
+    if let Some(last) = notes.last_mut() {
+        last.end = end;
+    }
+    notes
+}
+
+// Alterations written with the ornament as \markup \sharp, \flat ... (upper, lower): above the
+// note for the upper note, below it for the lower one; without a direction, for the note the
+// ornament mostly uses
+fn markup_accidentals(note: &LilyPondNote, ornament: Ornament) -> (Option<i32>, Option<i32>) {
+    let mut upper = None;
+    let mut lower = None;
+    for script in &note.script_attachments {
+        let ScriptContent::Markup(markup) = &script.content else { continue };
+        let Some(alter) = markup_alteration(markup) else { continue };
+        match script.direction {
+            ScriptDirection::Above => upper = Some(alter),
+            ScriptDirection::Below => lower = Some(alter),
+            _ if matches!(ornament, Ornament::Mordent) => lower = Some(alter),
+            _ => upper = Some(alter),
+        }
+    }
+    (upper, lower)
+}
+
+// The first accidental drawn in a markup
+fn markup_alteration(markup: &Markup) -> Option<i32> {
+    match markup {
+        Markup::Command { name, markup, .. } => match name.as_str() {
+            "doublesharp" => Some(2),
+            "sharp" => Some(1),
+            "natural" => Some(0),
+            "flat" => Some(-1),
+            "doubleflat" => Some(-2),
+            _ => markup.as_deref().and_then(markup_alteration),
+        },
+        Markup::Styled { markup, .. } => markup_alteration(markup),
+        Markup::Line { items } | Markup::Concat { items } | Markup::FillLine { items } | Markup::Column { items, .. } => {
+            items.iter().find_map(markup_alteration)
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+// Piano pedals in the performance timeline. \sustainOn ... \sustainOff, \sostenutoOn ... \sostenutoOff
+// and \unaCorda ... \treCorde act at the start of the note they are written on, on the whole piano:
+// the staves of the PianoStaff (or GrandStaff) they are in. A note keeps sounding after its key
+// is released while the sustain pedal is down, or while the sostenuto pedal that caught it is:
+//   c4\sustainOn e g c'\sustainOff\sustainOn  ->  c, e and g ring together until the pedal change
+// The sustain and sostenuto pedals go down pedal_delay after their note is played, as in legato
+// pedalling, so that a pedal change lets go of the notes before it.
+// MIDI export sends the changes as controllers 64 (sustain), 66 (sostenuto) and 67 (una corda).
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lilypond_parser::{is_timed_note, note_fraction, LilyPondNote, PedalEvent, StaffGroup, StaffGroupType};
+use crate::performance::Timeline;
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pedal {
+    Sustain,   // Controller 64
+    Sostenuto, // Controller 66
+    UnaCorda,  // Controller 67
+}
+
+/// A pedal pressed or released
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PedalChange {
+    pub staff: usize,          // Staff it is written in
+    pub staves: Range<usize>,  // Staves it acts on
+    pub time: f64,             // Seconds from the start
+    pub pedal: Pedal,
+    pub down: bool,
+}
+
+/// Pedal events of one staff or voice met in `order`: (moment in whole notes, pedal, pressed)
+pub(crate) fn pedal_events(notes: &[LilyPondNote], order: &[u32]) -> Vec<(f64, Pedal, bool)> {
+    let mut events = Vec::new();
+    let mut time = 0.0;
+    for &index in order {
+        let note = &notes[index as usize];
+        for event in &note.pedals {
+            events.push(match event {
+                PedalEvent::SustainOn => (time, Pedal::Sustain, true),
+                PedalEvent::SustainOff => (time, Pedal::Sustain, false),
+                PedalEvent::SostenutoOn => (time, Pedal::Sostenuto, true),
+                PedalEvent::SostenutoOff => (time, Pedal::Sostenuto, false),
+                PedalEvent::UnaCorda => (time, Pedal::UnaCorda, true),
+                PedalEvent::TreCorde => (time, Pedal::UnaCorda, false),
+            });
+        }
+        if is_timed_note(note) {
+            time += note_fraction(note).unwrap_or(0.0);
+        }
+    }
+    events
+}
+
+/// Staves played by the same instrument as `staff`: those of the innermost piano staff around it
+pub(crate) fn piano_staves(groups: &[StaffGroup], staff: usize) -> Range<usize> {
+    for group in groups.iter().filter(|group| group.staves.contains(&staff)) {
+        let inner = piano_staves(&group.groups, staff);
+        if inner.len() > 1 {
+            return inner;
+        }
+        if matches!(group.group_type, StaffGroupType::PianoStaff | StaffGroupType::GrandStaff) {
+            return group.staves.clone();
+        }
+    }
+    staff..staff + 1
+}
+
+/// Sets how long each note of the timeline sounds with the pedals, and whether it is played una corda
+pub(crate) fn apply_pedals(timeline: &mut Timeline) {
+    timeline.pedals.sort_by(|a, b| a.time.total_cmp(&b.time));
+    let duration = timeline.duration;
+    for note in &mut timeline.notes {
+        let changes: Vec<&PedalChange> = timeline.pedals.iter().filter(|change| change.staves.contains(&note.staff)).collect();
+        // When the pedal pressed at changes[press] is released again
+        let released = |press: usize| {
+            let pedal = changes[press].pedal;
+            changes[press + 1..].iter().find(|c| c.pedal == pedal && !c.down).map_or(duration, |c| c.time)
+        };
+
+        let release = note.start + note.duration;
+        let mut end = release;
+        // The sustain pedal holds the note when it is down as the key is released (a pedal
+        // change right at the release lets it go)
+        let sustain = changes.iter().rposition(|c| c.pedal == Pedal::Sustain && c.time < release - EPSILON);
+        if let Some(press) = sustain.filter(|&press| changes[press].down) {
+            end = end.max(released(press));
+        }
+        // The sostenuto pedal holds the notes whose keys are down when it is pressed
+        let caught = changes.iter().position(|c| {
+            c.pedal == Pedal::Sostenuto && c.down && c.time > note.start - EPSILON && c.time < release - EPSILON
+        });
+        if let Some(press) = caught {
+            end = end.max(released(press));
+        }
+        note.sounding = end - note.start;
+        note.soft = changes.iter().rev()
+            .find(|c| c.pedal == Pedal::UnaCorda && c.time < note.start + EPSILON)
+            .is_some_and(|c| c.down);
+    }
+}
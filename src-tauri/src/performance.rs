@@ -0,0 +1,262 @@
+// Performance timeline: when each note is played, in seconds, for playback and MIDI export.
+// Repeats and jumps are played out and tied notes sound once. Grace notes take no time in the
+// score, so here they get time of their own:
+//   \acciaccatura, \grace, \slashedGrace  ->  quickly, just before the beat, shortening the note before
+//   \appoggiatura  ->  on the beat, taking half of the main note (two thirds of a dotted one)
+// Ornaments are played out note by note (see ornaments.rs), and dynamics, articulations, slurs
+// and fermatas shape the velocity and length of the notes (see expression.rs). Pedals keep notes
+// sounding after they are released (see pedals.rs).
+use serde::{Deserialize, Serialize};
+
+use crate::expression::{fermata_holds, is_detached, length_factor, note_velocity, Clock, Dynamics, ExpressionOptions};
+use crate::lilypond_parser::{note_fraction, ApiParsedMusic, GraceKind, LilyPondNote, NoteType};
+use crate::ornaments::{realise_ornament, OrnamentStart};
+use crate::pedals::{apply_pedals, pedal_events, piano_staves, Pedal, PedalChange};
+use crate::pitch::{fifths_from_key_name, midi_key};
+use crate::slice::{pitches, tempo_changes, tied_chains, TempoMap, TiedChain, DEFAULT_BPM};
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PerformanceOptions {
+    pub grace_length: f64,           // Longest time of a grace note played before the beat, in whole notes
+    pub ornament_start: OrnamentStart,
+    pub ornament_speed: f64,         // Length of the quick notes of ornaments, in whole notes
+    pub expression: ExpressionOptions,
+    pub pedal_delay: f64,            // Seconds after the note the sustain and sostenuto pedals go down, as in legato pedalling
+}
+
+impl Default for PerformanceOptions {
+    fn default() -> Self {
+        Self {
+            grace_length: 1.0 / 32.0,
+            ornament_start: OrnamentStart::Upper,
+            ornament_speed: 1.0 / 32.0,
+            expression: ExpressionOptions::default(),
+            pedal_delay: 0.05,
+        }
+    }
+}
+
+/// A note (or chord) as played
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PerformedNote {
+    pub staff: usize,
+    pub voice: Option<usize>,      // None when the staff has no voices
+    pub note: u32,                 // Index in its staff or voice; the first of tied notes
+    pub start: f64,                // Seconds from the start
+    pub duration: f64,             // Seconds
+    pub keys: Vec<u8>,             // MIDI key of each head
+    pub velocity: f64,             // From 0 to 1
+    pub sounding: f64,             // Seconds the note rings, held on by the pedals; at least its duration
+    pub soft: bool,                // Played with the una corda pedal down
+    pub grace: Option<GraceKind>,
+    pub ornament: bool,            // One note of a played-out ornament
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Timeline {
+    pub notes: Vec<PerformedNote>, // By start, then staff and voice
+    pub duration: f64,             // Seconds until the last note ends
+    pub pedals: Vec<PedalChange>,  // By time
+}
+
+/// Every staff and voice of the music as played
+pub fn performance_timeline(music: &ApiParsedMusic, options: &PerformanceOptions) -> Timeline {
+    let mut lines = Vec::new();
+    for (staff_index, staff) in music.staves.iter().enumerate() {
+        let staff_key = staff.base.key_signature.as_ref().or(music.key_signature.as_ref());
+        if staff.voices.is_empty() {
+            lines.push((staff_index, None, &staff.base.notes, played_order(&staff.base.notes, &staff.performance_order), staff_key));
+        }
+        for (voice_index, voice) in staff.voices.iter().enumerate() {
+            let voice_key = voice.base.key_signature.as_ref().or(staff_key);
+            lines.push((staff_index, Some(voice_index), &voice.base.notes, played_order(&voice.base.notes, &voice.performance_order), voice_key));
+        }
+    }
+    let expression = &options.expression;
+    let tempo = TempoMap::new(
+        music.tempo_bpm.unwrap_or(DEFAULT_BPM),
+        lines.iter().flat_map(|(_, _, notes, order, _)| tempo_changes(notes, order)).collect(),
+    );
+    let clock = Clock::new(tempo, lines.iter().flat_map(|(_, _, notes, order, _)| fermata_holds(notes, order, expression)).collect());
+
+    let mut timeline = Timeline::default();
+    for (staff, voice, notes, order, key) in &lines {
+        let fifths = key_at_notes(notes, order, key.and_then(|key| fifths_from_key_name(key)).unwrap_or(0));
+        let dynamics = Dynamics::new(notes, order, expression);
+        let mut chains = tied_chains(notes, order);
+        place_grace_notes(notes, &mut chains, options);
+        let mut in_slur = false;
+        for chain in chains {
+            let first = &notes[chain.notes[0] as usize];
+            let last = &notes[*chain.notes.last().unwrap() as usize];
+            // Slurs go from the note before ( to the note before ), grace notes aside
+            let (legato, slur_end) = if first.grace.is_some() {
+                (false, false)
+            } else {
+                in_slur |= chain.notes.iter().any(|&n| notes[n as usize].slur_start);
+                let ends = chain.notes.iter().any(|&n| notes[n as usize].slur_end);
+                let slurred = (in_slur && !ends, in_slur && ends);
+                in_slur &= !ends;
+                slurred
+            };
+            let heads: Vec<(u8, (String, i32))> = pitches(first).into_iter()
+                .filter_map(|(name, octave)| Some((midi_key(&name, octave)?, (name, octave))))
+                .collect();
+            if heads.is_empty() {
+                continue;
+            }
+            let velocity = note_velocity(first, dynamics.level(chain.onset), expression);
+            let performed = |onset: f64, end: f64, keys: Vec<u8>, ornament: bool| {
+                let start = clock.seconds(onset);
+                PerformedNote {
+                    staff: *staff,
+                    voice: *voice,
+                    note: chain.notes[0],
+                    start,
+                    duration: clock.seconds(end) - start,
+                    keys,
+                    velocity,
+                    sounding: 0.0,
+                    soft: false,
+                    grace: first.grace,
+                    ornament,
+                }
+            };
+            timeline.duration = timeline.duration.max(clock.seconds(chain.end));
+
+            // An ornament is played on the top head; the other heads of a chord are held
+            let ornament = first.ornament.and_then(|ornament| {
+                let (top, head) = heads.iter().max_by_key(|(key, _)| *key)?;
+                let played = realise_ornament(ornament, (&head.0, head.1), first, fifths[chain.notes[0] as usize], (chain.onset, chain.end), options);
+                (!played.is_empty()).then_some((*top, played))
+            });
+            match ornament {
+                Some((top, played)) => {
+                    let held: Vec<u8> = heads.iter().map(|(key, _)| *key).filter(|&key| key != top).collect();
+                    if !held.is_empty() {
+                        timeline.notes.push(performed(chain.onset, chain.end, held, false));
+                    }
+                    for note in played {
+                        timeline.notes.push(performed(note.onset, note.end, vec![note.key], true));
+                    }
+                },
+                None => {
+                    let mut note = performed(chain.onset, chain.end, heads.iter().map(|(key, _)| *key).collect(), false);
+                    if first.grace.is_none() {
+                        note.duration *= length_factor(last, slur_end, expression);
+                        if legato && !is_detached(last) {
+                            note.duration += expression.legato_overlap;
+                        }
+                    }
+                    timeline.notes.push(note);
+                },
+            }
+        }
+    }
+    timeline.notes.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.staff.cmp(&b.staff)).then(a.voice.cmp(&b.voice)));
+
+    for (staff, _, notes, order, _) in &lines {
+        for (moment, pedal, down) in pedal_events(notes, order) {
+            let delay = if down && pedal != Pedal::UnaCorda { options.pedal_delay } else { 0.0 };
+            timeline.pedals.push(PedalChange {
+                staff: *staff,
+                staves: piano_staves(&music.staff_groups, *staff),
+                time: clock.seconds(moment) + delay,
+                pedal,
+                down,
+            });
+        }
+    }
+    apply_pedals(&mut timeline);
+    timeline
+}
+
+// Note indices in playing order; music without notes has no performance order
+fn played_order(notes: &[LilyPondNote], performance: &[u32]) -> Vec<u32> {
+    if performance.is_empty() {
+        (0..notes.len() as u32).collect()
+    } else {
+        performance.to_vec()
+    }
+}
+
+// Key signature (sharps or flats) in effect at each note index, following \key changes met in `order`
+fn key_at_notes(notes: &[LilyPondNote], order: &[u32], initial: i32) -> Vec<i32> {
+    let mut fifths = vec![initial; notes.len()];
+    let mut current = initial;
+    for &index in order {
+        let note = &notes[index as usize];
+        if note.note_type == NoteType::Key {
+            if let Some(key) = note.key_sig.as_deref().and_then(fifths_from_key_name) {
+                current = key;
+            }
+        }
+        fifths[index as usize] = current;
+    }
+    fifths
+}
+
+// Gives each group of grace notes (chains of zero length, met just before their main note) its
+// own time, as described at the top of this file
+fn place_grace_notes(notes: &[LilyPondNote], chains: &mut [TiedChain], options: &PerformanceOptions) {
+    let is_grace = |chain: &TiedChain| notes[chain.notes[0] as usize].grace.is_some();
+    let mut index = 0;
+    while index < chains.len() {
+        if !is_grace(&chains[index]) {
+            index += 1;
+            continue;
+        }
+        let first = index;
+        let onset = chains[first].onset;
+        while index < chains.len() && is_grace(&chains[index]) && (chains[index].onset - onset).abs() < EPSILON {
+            index += 1;
+        }
+        let main = (index < chains.len() && (chains[index].onset - onset).abs() < EPSILON).then_some(index);
+        let written: Vec<f64> = chains[first..index].iter()
+            .map(|chain| note_fraction(&notes[chain.notes[0] as usize]).unwrap_or(0.0))
+            .collect();
+        let main_length = main.map_or(0.0, |main| chains[main].end - chains[main].onset);
+        let kind = notes[chains[first].notes[0] as usize].grace;
+
+        // Whole notes the group takes, and where it starts
+        let appoggiatura = main.filter(|_| kind == Some(GraceKind::Appoggiatura));
+        let (length, start) = if let Some(main) = appoggiatura {
+            let main_note = &notes[chains[main].notes[0] as usize];
+            let share = if main_note.dots.is_empty() { 1.0 / 2.0 } else { 2.0 / 3.0 };
+            (main_length * share, onset)
+        } else {
+            let wanted: f64 = written.iter().map(|&w| w.min(options.grace_length)).sum();
+            // Not earlier than halfway through the note before (or the start of the music)
+            let previous = first.checked_sub(1).map(|p| &chains[p]);
+            let room = previous.map_or(onset, |p| onset - (p.onset + p.end) / 2.0).max(0.0);
+            if room > EPSILON {
+                let length = wanted.min(room);
+                (length, onset - length)
+            } else {
+                // Nothing to take the time from: on the beat, at most half of the main note
+                let length = if main.is_some() { wanted.min(main_length / 2.0) } else { wanted };
+                (length, onset)
+            }
+        };
+
+        // Share the group's time in proportion to the written lengths
+        let total: f64 = written.iter().sum();
+        let mut time = start;
+        for (chain, &w) in chains[first..index].iter_mut().zip(&written) {
+            let part = if total > EPSILON { length * w / total } else { length / written.len() as f64 };
+            chain.onset = time;
+            chain.end = time + part;
+            time += part;
+        }
+        if start < onset {
+            if let Some(previous) = first.checked_sub(1).map(|p| &mut chains[p]) {
+                previous.end = previous.end.min(start);
+            }
+        } else if let Some(main) = main {
+            chains[main].onset += length;
+        }
+    }
+}
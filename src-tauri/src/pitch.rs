@@ -0,0 +1,302 @@
+// Pitch spelling, key signatures and the accidental-display engine.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::lilypond_parser::{LilyPondNote, Measure, NoteType};
+
+// Position of each step (c d e f g a b) on the circle of fifths, relative to C
+const STEP_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+// Steps in the order sharps are added to a key signature (F C G D A E B)
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+
+/// A spelled pitch class: step 0..6 for c..b, alter in semitones (-2..2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Pitch {
+    pub step: usize,
+    pub alter: i32,
+}
+
+/// Parses a standard (Dutch) note name such as "c", "fis", "bes", "eeses" or "as"
+/// (a bare "s" only after a and e). Language-specific names must go through `get_standard_pitch` first.
+pub fn parse_note_name(name: &str) -> Option<Pitch> {
+    let mut chars = name.chars();
+    let step = match chars.next()? {
+        'c' => 0,
+        'd' => 1,
+        'e' => 2,
+        'f' => 3,
+        'g' => 4,
+        'a' => 5,
+        'b' | 'h' => 6,
+        _ => return None,
+    };
+    let alter = match (step, chars.as_str()) {
+        (_, "") => 0,
+        (_, "is") => 1,
+        (_, "isis") => 2,
+        (_, "es") | (2 | 5, "s") => -1,
+        (_, "eses") | (2 | 5, "ses") => -2,
+        _ => return None,
+    };
+    Some(Pitch { step, alter })
+}
+
+// Semitones of each natural step above c
+const STEP_SEMITONES: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// MIDI key number of a standard note name and octave (c' is octave 4, middle C = 60)
+pub fn midi_key(name: &str, octave: i32) -> Option<u8> {
+    let pitch = parse_note_name(name)?;
+    let key = (octave + 1) * 12 + STEP_SEMITONES[pitch.step] + pitch.alter;
+    u8::try_from(key).ok().filter(|&key| key < 128)
+}
+
+/// Standard (Dutch) name of a pitch: "c", "fis", "bes", "ees", "aeses", "gisis"
+pub fn standard_name(pitch: Pitch) -> String {
+    let suffix = match pitch.alter {
+        2 => "isis",
+        1 => "is",
+        -1 => "es",
+        -2 => "eses",
+        _ => "",
+    };
+    format!("{}{}", ['c', 'd', 'e', 'f', 'g', 'a', 'b'][pitch.step], suffix)
+}
+
+/// Transposes a note name and octave by the interval between `from` and `to`
+/// (each a pitch with its octave, as in `\transpose c d`). The interval keeps its
+/// spelling: c -> e transposes fis to ais, not to bes.
+pub fn transpose_pitch(name: &str, octave: i32, from: (Pitch, i32), to: (Pitch, i32)) -> Option<(String, i32)> {
+    let pitch = parse_note_name(name)?;
+    let position = |p: Pitch, octave: i32| octave * 7 + p.step as i32;
+    let semitones = |p: Pitch, octave: i32| octave * 12 + STEP_SEMITONES[p.step] + p.alter;
+
+    let target = position(pitch, octave) + position(to.0, to.1) - position(from.0, from.1);
+    let target_semitones = semitones(pitch, octave) + semitones(to.0, to.1) - semitones(from.0, from.1);
+    let mut new_octave = target.div_euclid(7);
+    let mut step = target.rem_euclid(7) as usize;
+    let mut alter = target_semitones - semitones(Pitch { step, alter: 0 }, new_octave);
+    // Respell anything beyond a double sharp or flat on the neighbouring step
+    while alter > 2 {
+        let next = (step + 1) % 7;
+        if next == 0 {
+            new_octave += 1;
+        }
+        alter = target_semitones - semitones(Pitch { step: next, alter: 0 }, new_octave);
+        step = next;
+    }
+    while alter < -2 {
+        let prev = (step + 6) % 7;
+        if step == 0 {
+            new_octave -= 1;
+        }
+        alter = target_semitones - semitones(Pitch { step: prev, alter: 0 }, new_octave);
+        step = prev;
+    }
+    Some((standard_name(Pitch { step, alter }), new_octave))
+}
+
+/// Renderer key name moved by the interval between two pitches ("G" by c -> d = "A")
+pub fn transpose_key_name(name: &str, from: Pitch, to: Pitch) -> Option<String> {
+    let fifths = fifths_from_key_name(name)?;
+    let shift = STEP_FIFTHS[to.step] + 7 * to.alter - STEP_FIFTHS[from.step] - 7 * from.alter;
+    Some(key_name(fifths + shift, name.ends_with('m')))
+}
+
+/// Mode of a `\key` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMode {
+    Major,
+    Minor,
+    Ionian,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Aeolian,
+    Locrian,
+}
+
+impl KeyMode {
+    /// Mode from LilyPond syntax, with or without the backslash ("\\dorian", "minor")
+    pub fn from_lilypond(mode: &str) -> Option<Self> {
+        match mode.trim_start_matches('\\') {
+            "major" => Some(KeyMode::Major),
+            "minor" => Some(KeyMode::Minor),
+            "ionian" => Some(KeyMode::Ionian),
+            "dorian" => Some(KeyMode::Dorian),
+            "phrygian" => Some(KeyMode::Phrygian),
+            "lydian" => Some(KeyMode::Lydian),
+            "mixolydian" => Some(KeyMode::Mixolydian),
+            "aeolian" => Some(KeyMode::Aeolian),
+            "locrian" => Some(KeyMode::Locrian),
+            _ => None,
+        }
+    }
+
+    // Fifths of the mode relative to the major key on the same tonic
+    fn fifths_offset(self) -> i32 {
+        match self {
+            KeyMode::Major | KeyMode::Ionian => 0,
+            KeyMode::Lydian => 1,
+            KeyMode::Mixolydian => -1,
+            KeyMode::Dorian => -2,
+            KeyMode::Minor | KeyMode::Aeolian => -3,
+            KeyMode::Phrygian => -4,
+            KeyMode::Locrian => -5,
+        }
+    }
+
+    fn is_minor(self) -> bool {
+        matches!(self, KeyMode::Minor | KeyMode::Aeolian)
+    }
+}
+
+/// Number of sharps (positive) or flats (negative) of the key, e.g. d dorian = 0, gis major = 8
+pub fn key_fifths(tonic: Pitch, mode: KeyMode) -> i32 {
+    STEP_FIFTHS[tonic.step] + 7 * tonic.alter + mode.fifths_offset()
+}
+
+/// Key signature name used by the renderer ("C", "F#", "Bbm").
+/// The tonic keeps its spelling beyond seven sharps or flats: gis major is "G#", with eight sharps.
+pub fn key_name(fifths: i32, minor: bool) -> String {
+    let tonic_fifths = if minor { fifths + 3 } else { fifths };
+    // The step whose place on the circle of fifths is a whole number of sharps or flats away
+    let step = (0..7).find(|&step| (tonic_fifths - STEP_FIFTHS[step]).rem_euclid(7) == 0).unwrap_or(0);
+    let alter = (tonic_fifths - STEP_FIFTHS[step]) / 7;
+    let sign = if alter > 0 { "#" } else { "b" };
+    format!(
+        "{}{}{}",
+        ['C', 'D', 'E', 'F', 'G', 'A', 'B'][step],
+        sign.repeat(alter.unsigned_abs() as usize),
+        if minor { "m" } else { "" }
+    )
+}
+
+/// Renderer key name for `\key <tonic> <mode>`, or None if the tonic or mode is unknown
+pub fn key_signature_name(tonic: &str, mode: &str) -> Option<String> {
+    let tonic = parse_note_name(tonic)?;
+    let mode = KeyMode::from_lilypond(mode)?;
+    Some(key_name(key_fifths(tonic, mode), mode.is_minor()))
+}
+
+/// Sharps/flats count of a renderer key name ("Bb" = -2, "F#m" = 3)
+pub fn fifths_from_key_name(name: &str) -> Option<i32> {
+    let (name, minor) = match name.strip_suffix('m') {
+        Some(tonic) => (tonic, true),
+        None => (name, false),
+    };
+    let mut chars = name.chars();
+    let letter = chars.next()?.to_ascii_lowercase();
+    let alter = match chars.as_str() {
+        signs if signs.chars().all(|c| c == '#') => signs.len() as i32,
+        signs if signs.chars().all(|c| c == 'b') => -(signs.len() as i32),
+        _ => return None,
+    };
+    let tonic = Pitch { step: parse_note_name(&letter.to_string())?.step, alter };
+    let mode = if minor { KeyMode::Minor } else { KeyMode::Major };
+    Some(key_fifths(tonic, mode))
+}
+
+/// Alteration of each step (c..b) in the key signature; past seven sharps or flats the
+/// steps are altered again in the same order (gis major has a double-sharp f)
+pub fn key_alterations(fifths: i32) -> [i32; 7] {
+    let mut alterations = [0; 7];
+    for i in 0..fifths.unsigned_abs() as usize {
+        if fifths > 0 {
+            alterations[SHARP_ORDER[i % 7]] += 1;
+        } else {
+            alterations[SHARP_ORDER[6 - i % 7]] -= 1;
+        }
+    }
+    alterations
+}
+
+/// Accidental sign printed before a note head
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Accidental {
+    DoubleFlat,
+    Flat,
+    Natural,
+    Sharp,
+    DoubleSharp,
+}
+
+impl Accidental {
+    pub fn from_alter(alter: i32) -> Option<Self> {
+        match alter {
+            -2 => Some(Accidental::DoubleFlat),
+            -1 => Some(Accidental::Flat),
+            0 => Some(Accidental::Natural),
+            1 => Some(Accidental::Sharp),
+            2 => Some(Accidental::DoubleSharp),
+            _ => None,
+        }
+    }
+}
+
+/// An accidental to print; cautionary ones are drawn in parentheses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrintedAccidental {
+    pub accidental: Accidental,
+    pub cautionary: bool,
+}
+
+/// Decides which accidentals are printed in one voice and stores them in `note.accidentals`,
+/// one entry per note head (the main pitch first, then `chord_notes`).
+///
+/// An accidental is printed when a note differs from the key signature or from an earlier
+/// note of the same step and octave in the measure. Notes tied from the previous note print
+/// nothing, even across a barline. `!` always prints the accidental, `?` prints it in parentheses.
+pub fn assign_accidentals(notes: &mut [LilyPondNote], measures: &[Measure], key_signature: Option<&str>) {
+    let mut key = key_signature.and_then(fifths_from_key_name).unwrap_or(0);
+    // Pitches held by a tie from the previous note: (step, octave) -> alter
+    let mut tied: HashMap<(usize, i32), i32> = HashMap::new();
+
+    for measure in measures {
+        // Accidentals in effect in this measure: (step, octave) -> alter
+        let mut current: HashMap<(usize, i32), i32> = HashMap::new();
+
+        for &idx in &measure.notes {
+            let note = &mut notes[idx as usize];
+            if note.note_type == NoteType::Key {
+                if let Some(fifths) = note.key_sig.as_deref().and_then(fifths_from_key_name) {
+                    key = fifths;
+                }
+                continue;
+            }
+            if !matches!(note.note_type, NoteType::Default | NoteType::Chord) || note.pitch == "r" || note.pitch == "R" {
+                continue;
+            }
+
+            let alterations = key_alterations(key);
+            let heads: Vec<(String, i32)> = std::iter::once((note.pitch.clone(), note.octave))
+                .chain(note.chord_notes.iter().cloned())
+                .collect();
+            let mut next_tied = HashMap::new();
+            note.accidentals = heads.iter().map(|(name, octave)| {
+                let pitch = parse_note_name(name)?;
+                let slot = (pitch.step, *octave);
+                if note.has_slur {
+                    next_tied.insert(slot, pitch.alter);
+                }
+                if tied.get(&slot) == Some(&pitch.alter) {
+                    // The tie carries the accidental over; only the first note shows it
+                    current.insert(slot, pitch.alter);
+                    return None;
+                }
+                let expected = current.get(&slot).copied().unwrap_or(alterations[pitch.step]);
+                current.insert(slot, pitch.alter);
+                let forced = note.accidental_modifier.is_some();
+                if pitch.alter == expected && !forced {
+                    return None;
+                }
+                Accidental::from_alter(pitch.alter).map(|accidental| PrintedAccidental {
+                    accidental,
+                    cautionary: note.accidental_modifier.as_deref() == Some("?"),
+                })
+            }).collect();
+            tied = next_tied;
+        }
+    }
+}
@@ -0,0 +1,168 @@
+// Performance order: unrolls volta repeats and D.C./D.S. jumps into the order
+// in which notes are actually played.
+use std::collections::HashSet;
+
+use crate::lilypond_parser::{JumpEnd, LilyPondNote, NavigationMark, NoteType};
+
+// One open \repeat volta while unrolling
+struct RepeatFrame {
+    body_start: usize,   // First index after the RepeatStart marker
+    end: usize,          // Index of the matching RepeatEnd marker
+    times: u32,
+    alternatives: u32,   // Number of alternatives at this level
+    pass: u32,           // 1-based
+}
+
+/// Returns the indices of `notes` in playing order, markers included.
+///
+/// Repeats may be nested. On each pass of a repeat, the alternatives whose
+/// `alternative_index` contains the pass number are played. Each jump is taken once. After a jump, repeats are played only once (taking their last alternative),
+/// Fine ends the piece and To Coda jumps to the Coda mark.
+pub fn performance_order(notes: &[LilyPondNote]) -> Vec<u32> {
+    let mut order = Vec::new();
+    let mut stack: Vec<RepeatFrame> = Vec::new();
+    let mut taken_jumps: HashSet<usize> = HashSet::new();
+    // Set once a D.C./D.S. has been taken
+    let mut jump_end: Option<JumpEnd> = None;
+
+    let mut idx = 0;
+    while idx < notes.len() {
+        let note = &notes[idx];
+        order.push(idx as u32);
+        let mut next = idx + 1;
+
+        match note.note_type {
+            NoteType::RepeatStart => {
+                if let Some(end) = find_repeat_end(notes, idx) {
+                    let times = note.repeat_times.unwrap_or(2).max(1);
+                    stack.push(RepeatFrame {
+                        body_start: idx + 1,
+                        end,
+                        times,
+                        alternatives: count_alternatives(notes, idx, end),
+                        pass: if jump_end.is_some() { times } else { 1 },
+                    });
+                }
+            },
+            NoteType::AlternativeStart => {
+                if let Some(frame) = stack.last() {
+                    if !note.alternative_index.contains(&(frame.pass as i32)) {
+                        // Not this pass: skip to the marker that ends this alternative
+                        if let Some(alt_end) = find_alternative_end(notes, idx) {
+                            next = alt_end + 1;
+                        }
+                    }
+                }
+            },
+            NoteType::AlternativeEnd => {
+                if let Some(frame) = stack.last_mut() {
+                    let more_alternatives = notes.get(idx + 1)
+                        .is_some_and(|n| n.note_type == NoteType::AlternativeStart);
+                    if frame.pass < frame.times {
+                        frame.pass += 1;
+                        next = frame.body_start;
+                    } else if more_alternatives {
+                        // All passes done: the remaining alternatives are not played
+                        next = frame.end;
+                    }
+                }
+            },
+            NoteType::RepeatEnd => {
+                if let Some(frame) = stack.last_mut() {
+                    if frame.alternatives == 0 && frame.pass < frame.times {
+                        frame.pass += 1;
+                        next = frame.body_start;
+                    } else {
+                        stack.pop();
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        // Jumps only happen when the music goes on, not while a repeat loops back
+        if next > idx {
+            for mark in &note.navigation {
+                match mark {
+                    NavigationMark::Fine if jump_end.is_some() && jump_end != Some(JumpEnd::Coda) => {
+                        return order;
+                    },
+                    NavigationMark::ToCoda if jump_end == Some(JumpEnd::Coda) && !taken_jumps.contains(&idx) => {
+                        taken_jumps.insert(idx);
+                        if let Some(coda) = find_coda(notes, idx) {
+                            stack.clear();
+                            next = coda;
+                        }
+                    },
+                    NavigationMark::DaCapo(end) | NavigationMark::DalSegno(end) if !taken_jumps.contains(&idx) => {
+                        taken_jumps.insert(idx);
+                        jump_end = Some(*end);
+                        stack.clear();
+                        next = match mark {
+                            // The nearest segno before the D.S., or the beginning
+                            NavigationMark::DalSegno(_) => notes[..=idx].iter()
+                                .rposition(|n| n.navigation.contains(&NavigationMark::Segno))
+                                .unwrap_or(0),
+                            _ => 0,
+                        };
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        idx = next;
+    }
+
+    order
+}
+
+// Matching RepeatEnd for the RepeatStart at `start`
+fn find_repeat_end(notes: &[LilyPondNote], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, note) in notes.iter().enumerate().skip(start + 1) {
+        match note.note_type {
+            NoteType::RepeatStart => depth += 1,
+            NoteType::RepeatEnd if depth == 0 => return Some(i),
+            NoteType::RepeatEnd => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// Matching AlternativeEnd for the AlternativeStart at `start`
+fn find_alternative_end(notes: &[LilyPondNote], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, note) in notes.iter().enumerate().skip(start + 1) {
+        match note.note_type {
+            NoteType::AlternativeStart => depth += 1,
+            NoteType::AlternativeEnd if depth == 0 => return Some(i),
+            NoteType::AlternativeEnd => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// Number of alternatives belonging to the repeat between `start` and `end` (not to nested repeats)
+fn count_alternatives(notes: &[LilyPondNote], start: usize, end: usize) -> u32 {
+    let mut depth = 0;
+    let mut count = 0;
+    for note in &notes[start + 1..end] {
+        match note.note_type {
+            NoteType::RepeatStart => depth += 1,
+            NoteType::RepeatEnd => depth -= 1,
+            NoteType::AlternativeStart if depth == 0 => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+// Coda mark reached from the To Coda at `from`: the next one after it, else the first one
+fn find_coda(notes: &[LilyPondNote], from: usize) -> Option<usize> {
+    let is_coda = |n: &LilyPondNote| n.navigation.contains(&NavigationMark::Coda);
+    notes.iter().skip(from + 1).position(is_coda).map(|i| i + from + 1)
+        .or_else(|| notes.iter().position(is_coda))
+}
@@ -0,0 +1,91 @@
+// Context properties set in the music (\set, \unset, \override, \revert, \once and \with),
+// recorded where they happen so that their value can be looked up at any moment:
+//   \set Staff.midiInstrument = #"violin"  ->  Set, context Staff, property midiInstrument
+//   \once \override Stem.direction = #UP  ->  Override, no context (the voice), property Stem.direction
+use serde::{Deserialize, Serialize};
+
+use crate::lilypond_parser::{is_timed_note, note_fraction, LilyPondNote, SourceSpan};
+use crate::scheme::SchemeValue;
+
+// Context names that can start a property path; anything else is a property or layout object
+const CONTEXTS: &[&str] = &[
+    "Global", "Score", "Timing", "StaffGroup", "PianoStaff", "GrandStaff", "ChoirStaff", "Staff", "RhythmicStaff",
+    "TabStaff", "DrumStaff", "Voice", "TabVoice", "DrumVoice", "CueVoice", "NullVoice", "Lyrics", "Dynamics",
+    "ChordNames", "FiguredBass", "NoteNames", "FretBoards",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PropertyAction {
+    Set,      // \set, or property = value in \with
+    Unset,    // \unset
+    Override, // \override
+    Revert,   // \revert
+}
+
+/// One property change, kept on the note it comes before
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PropertyEvent {
+    pub action: PropertyAction,
+    pub context: Option<String>,      // "Staff" in \set Staff.midiInstrument; None for the voice
+    pub property: String,             // "midiInstrument", or "Stem.direction" for a layout object
+    pub value: Option<SchemeValue>,   // None for \unset and \revert, or a value we cannot evaluate
+    pub once: bool,                   // \once: only for the note it comes before
+    pub span: Option<SourceSpan>,
+}
+
+impl PropertyEvent {
+    /// Event for a property path as written, e.g. ["Staff", "Stem", "direction"]
+    pub fn new(action: PropertyAction, path: &[String], value: Option<SchemeValue>, span: Option<SourceSpan>) -> Self {
+        let (context, property) = match path {
+            [first, rest @ ..] if !rest.is_empty() && CONTEXTS.contains(&first.as_str()) => (Some(first.clone()), rest.join(".")),
+            _ => (None, path.join(".")),
+        };
+        Self { action, context, property, value, once: false, span }
+    }
+
+    /// The value as text, for string and symbol values
+    pub fn text(&self) -> Option<&str> {
+        match &self.value {
+            Some(SchemeValue::String(text)) | Some(SchemeValue::Symbol(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn is_for(&self, context: Option<&str>, property: &str) -> bool {
+        self.context.as_deref() == context && self.property == property
+    }
+}
+
+/// Value of a property at `moment` (in whole notes from the start of `notes`), taking every
+/// \set, \unset, \override and \revert written up to then. `context` is None for properties
+/// set without one, such as \override Stem.direction. Repeats count as written once.
+pub fn property_at(notes: &[LilyPondNote], context: Option<&str>, property: &str, moment: f64) -> Option<SchemeValue> {
+    const EPSILON: f64 = 1e-9;
+    let mut value = None;
+    let mut start = 0.0;
+    for note in notes {
+        if start > moment + EPSILON {
+            break;
+        }
+        let length = if is_timed_note(note) { note_fraction(note).unwrap_or(0.0) } else { 0.0 };
+        // \once applies while its note sounds; afterwards the earlier value is back
+        let sounding = moment < start + length - EPSILON || (length == 0.0 && moment <= start + EPSILON);
+        let mut once = None;
+        for event in note.properties.iter().filter(|e| e.is_for(context, property)) {
+            let new_value = match event.action {
+                PropertyAction::Set | PropertyAction::Override => event.value.clone(),
+                PropertyAction::Unset | PropertyAction::Revert => None,
+            };
+            if !event.once {
+                value = new_value;
+            } else if sounding {
+                once = Some(new_value);
+            }
+        }
+        if let Some(once) = once {
+            return once;
+        }
+        start += length;
+    }
+    value
+}
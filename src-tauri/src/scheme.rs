@@ -0,0 +1,474 @@
+// A small evaluator for the Scheme found in LilyPond files:
+//   #(set-global-staff-size 18), #(ly:make-moment 1/8), #(string-append "a" "b"), ##t, #'symbol
+// It is sandboxed: only data and a fixed set of pure built-ins are available. There are no
+// lambdas, no I/O and no access to the host; nesting depth and evaluation steps are limited.
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Deepest nesting of lists, both when reading and when evaluating
+const MAX_DEPTH: usize = 64;
+// Most evaluation steps spent on one expression
+const MAX_STEPS: usize = 10_000;
+
+/// A Scheme value as typed data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum SchemeValue {
+    Boolean(bool),
+    Integer(i64),
+    Rational { numerator: i64, denominator: i64 },
+    Real(f64),
+    String(String),
+    Symbol(String),
+    List(Vec<SchemeValue>),
+    Moment { numerator: i64, denominator: i64 }, // ly:make-moment: a length in whole notes
+    Unspecified,                                 // Result of define and other side effects
+}
+
+impl SchemeValue {
+    /// Numeric value of a number or moment
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SchemeValue::Integer(n) => Some(*n as f64),
+            SchemeValue::Rational { numerator, denominator } | SchemeValue::Moment { numerator, denominator } => {
+                Some(*numerator as f64 / *denominator as f64)
+            },
+            SchemeValue::Real(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Text as `display` would print it: strings without quotes, #t/#f for booleans
+    pub fn display(&self) -> String {
+        match self {
+            SchemeValue::Boolean(b) => if *b { "#t" } else { "#f" }.to_string(),
+            SchemeValue::Integer(n) => n.to_string(),
+            SchemeValue::Rational { numerator, denominator } => format!("{}/{}", numerator, denominator),
+            SchemeValue::Real(x) => x.to_string(),
+            SchemeValue::String(s) | SchemeValue::Symbol(s) => s.clone(),
+            SchemeValue::List(items) => {
+                format!("({})", items.iter().map(SchemeValue::display).collect::<Vec<_>>().join(" "))
+            },
+            SchemeValue::Moment { numerator, denominator } => format!("#<Mom {}/{}>", numerator, denominator),
+            SchemeValue::Unspecified => String::new(),
+        }
+    }
+}
+
+/// Why an expression could not be evaluated
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemeError {
+    Unsupported(String), // Valid Scheme outside the supported subset, e.g. a procedure we do not know
+    Invalid(String),     // Malformed code or a wrong argument
+}
+
+impl SchemeError {
+    pub fn message(&self) -> &str {
+        match self {
+            SchemeError::Unsupported(message) | SchemeError::Invalid(message) => message,
+        }
+    }
+}
+
+/// Top-level Scheme state of a file: `define`d names and settings made by Scheme calls
+#[derive(Debug, Clone, Default)]
+pub struct SchemeEnv {
+    pub definitions: HashMap<String, SchemeValue>,
+    pub global_staff_size: Option<f64>,
+}
+
+impl SchemeEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evaluates LilyPond Scheme code such as `#(+ 1 2)`, `##t` or `#'UP`.
+    /// `lookup` supplies names that are not `define`d, such as LilyPond variables.
+    pub fn eval(&mut self, code: &str, lookup: &dyn Fn(&str) -> Option<SchemeValue>) -> Result<SchemeValue, SchemeError> {
+        // One # (or $) switches from LilyPond to Scheme
+        let code = code.trim();
+        let code = code.strip_prefix('#').or_else(|| code.strip_prefix('$')).unwrap_or(code);
+        let mut reader = Reader { tokens: tokenize(code)?, pos: 0 };
+        let expr = reader.read(0)?;
+        if reader.pos < reader.tokens.len() {
+            return Err(SchemeError::Invalid(format!("Unexpected text after {}", expr.display())));
+        }
+        let mut evaluator = Evaluator { env: self, lookup, steps: 0 };
+        evaluator.eval(&expr, 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Quote,
+    Atom(String),
+    Str(String),
+}
+
+fn tokenize(code: &str) -> Result<Vec<Token>, SchemeError> {
+    let mut tokens = Vec::new();
+    let mut chars = code.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            },
+            ';' => {
+                // Comment to the end of the line
+                while chars.next().is_some_and(|c| c != '\n') {}
+            },
+            '(' | '[' => {
+                chars.next();
+                tokens.push(Token::Open);
+            },
+            ')' | ']' => {
+                chars.next();
+                tokens.push(Token::Close);
+            },
+            '\'' => {
+                chars.next();
+                tokens.push(Token::Quote);
+            },
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => text.push('\n'),
+                            Some('t') => text.push('\t'),
+                            Some(other) => text.push(other),
+                            None => return Err(SchemeError::Invalid("Unterminated string".to_string())),
+                        },
+                        Some(other) => text.push(other),
+                        None => return Err(SchemeError::Invalid("Unterminated string".to_string())),
+                    }
+                }
+                tokens.push(Token::Str(text));
+            },
+            _ => {
+                let mut atom = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()[]'\";".contains(c) {
+                        break;
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                if atom == "`" || atom == "," || atom.starts_with("#{") {
+                    return Err(SchemeError::Unsupported(format!("Unsupported syntax {}", atom)));
+                }
+                tokens.push(Token::Atom(atom));
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+struct Reader {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Reader {
+    fn read(&mut self, depth: usize) -> Result<SchemeValue, SchemeError> {
+        if depth > MAX_DEPTH {
+            return Err(SchemeError::Invalid("Expression is nested too deeply".to_string()));
+        }
+        let token = self.tokens.get(self.pos).cloned()
+            .ok_or_else(|| SchemeError::Invalid("Unexpected end of expression".to_string()))?;
+        self.pos += 1;
+        match token {
+            Token::Open => {
+                let mut items = Vec::new();
+                while self.tokens.get(self.pos) != Some(&Token::Close) {
+                    if self.pos >= self.tokens.len() {
+                        return Err(SchemeError::Invalid("Missing )".to_string()));
+                    }
+                    items.push(self.read(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(SchemeValue::List(items))
+            },
+            Token::Close => Err(SchemeError::Invalid("Unexpected )".to_string())),
+            Token::Quote => {
+                let quoted = self.read(depth + 1)?;
+                Ok(SchemeValue::List(vec![SchemeValue::Symbol("quote".to_string()), quoted]))
+            },
+            Token::Str(text) => Ok(SchemeValue::String(text)),
+            Token::Atom(atom) => Ok(read_atom(&atom)),
+        }
+    }
+}
+
+fn read_atom(atom: &str) -> SchemeValue {
+    match atom {
+        "#t" | "#true" => return SchemeValue::Boolean(true),
+        "#f" | "#false" => return SchemeValue::Boolean(false),
+        _ => {}
+    }
+    if let Ok(n) = atom.parse::<i64>() {
+        return SchemeValue::Integer(n);
+    }
+    if let Some((numerator, denominator)) = atom.split_once('/') {
+        if let (Ok(n), Ok(d)) = (numerator.parse::<i64>(), denominator.parse::<i64>()) {
+            if d != 0 {
+                return Number::Exact(n, d).normalize().into_value();
+            }
+        }
+    }
+    if atom.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '-' || c == '+') {
+        if let Ok(x) = atom.parse::<f64>() {
+            return SchemeValue::Real(x);
+        }
+    }
+    SchemeValue::Symbol(atom.to_string())
+}
+
+// Operation on two fractions n1/d1 and n2/d2; None on overflow
+type ExactOp = fn(i64, i64, i64, i64) -> Option<(i64, i64)>;
+
+// Exact numbers stay fractions; anything inexact (or too large) becomes a float
+#[derive(Debug, Clone, Copy)]
+enum Number {
+    Exact(i64, i64),
+    Inexact(f64),
+}
+
+// None when it overflows, as for i64::MIN and -1
+fn gcd(a: i64, b: i64) -> Option<i64> {
+    if b == 0 { Some(a.checked_abs()?.max(1)) } else { gcd(b, a.checked_rem(b)?) }
+}
+
+impl Number {
+    fn from_value(value: &SchemeValue) -> Option<Self> {
+        match value {
+            SchemeValue::Integer(n) => Some(Number::Exact(*n, 1)),
+            SchemeValue::Rational { numerator, denominator } => Some(Number::Exact(*numerator, *denominator)),
+            SchemeValue::Real(x) => Some(Number::Inexact(*x)),
+            _ => None,
+        }
+    }
+
+    // Lowest terms with a positive denominator; a float when that overflows
+    fn normalize(self) -> Self {
+        match self {
+            Number::Exact(n, d) => {
+                let reduced = gcd(n, d).and_then(|g| {
+                    let g = g.checked_mul(d.signum())?;
+                    Some(Number::Exact(n.checked_div(g)?, d.checked_div(g)?))
+                });
+                reduced.unwrap_or(Number::Inexact(n as f64 / d as f64))
+            },
+            inexact => inexact,
+        }
+    }
+
+    fn to_f64(self) -> f64 {
+        match self {
+            Number::Exact(n, d) => n as f64 / d as f64,
+            Number::Inexact(x) => x,
+        }
+    }
+
+    fn into_value(self) -> SchemeValue {
+        match self.normalize() {
+            Number::Exact(n, 1) => SchemeValue::Integer(n),
+            Number::Exact(numerator, denominator) => SchemeValue::Rational { numerator, denominator },
+            Number::Inexact(x) => SchemeValue::Real(x),
+        }
+    }
+
+    // Applies an exact operation on (n1, d1, n2, d2), falling back to floats on overflow
+    fn combine(self, other: Self, exact: ExactOp, inexact: fn(f64, f64) -> f64) -> Self {
+        if let (Number::Exact(n1, d1), Number::Exact(n2, d2)) = (self, other) {
+            if let Some((n, d)) = exact(n1, d1, n2, d2) {
+                return Number::Exact(n, d).normalize();
+            }
+        }
+        Number::Inexact(inexact(self.to_f64(), other.to_f64()))
+    }
+
+    fn add(self, other: Self) -> Self {
+        self.combine(other, |n1, d1, n2, d2| {
+            Some((n1.checked_mul(d2)?.checked_add(n2.checked_mul(d1)?)?, d1.checked_mul(d2)?))
+        }, |a, b| a + b)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        self.combine(other, |n1, d1, n2, d2| {
+            Some((n1.checked_mul(d2)?.checked_sub(n2.checked_mul(d1)?)?, d1.checked_mul(d2)?))
+        }, |a, b| a - b)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        self.combine(other, |n1, d1, n2, d2| Some((n1.checked_mul(n2)?, d1.checked_mul(d2)?)), |a, b| a * b)
+    }
+
+    fn div(self, other: Self) -> Self {
+        self.combine(other, |n1, d1, n2, d2| Some((n1.checked_mul(d2)?, d1.checked_mul(n2)?)), |a, b| a / b)
+    }
+}
+
+struct Evaluator<'a> {
+    env: &'a mut SchemeEnv,
+    lookup: &'a dyn Fn(&str) -> Option<SchemeValue>,
+    steps: usize,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &SchemeValue, depth: usize) -> Result<SchemeValue, SchemeError> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS || depth > MAX_DEPTH {
+            return Err(SchemeError::Invalid("Expression takes too long to evaluate".to_string()));
+        }
+        match expr {
+            SchemeValue::Symbol(name) => self.variable(name),
+            SchemeValue::List(items) => {
+                let (head, args) = items.split_first()
+                    .ok_or_else(|| SchemeError::Invalid("Cannot evaluate ()".to_string()))?;
+                let name = match head {
+                    SchemeValue::Symbol(name) => name.as_str(),
+                    other => return Err(SchemeError::Invalid(format!("{} is not a procedure", other.display()))),
+                };
+                match name {
+                    "quote" => match args {
+                        [quoted] => Ok(quoted.clone()),
+                        _ => Err(SchemeError::Invalid("quote takes one argument".to_string())),
+                    },
+                    "define" => match args {
+                        [SchemeValue::Symbol(name), value] => {
+                            let value = self.eval(value, depth + 1)?;
+                            self.env.definitions.insert(name.clone(), value);
+                            Ok(SchemeValue::Unspecified)
+                        },
+                        [SchemeValue::List(_), ..] => Err(SchemeError::Unsupported("Procedure definitions are not supported".to_string())),
+                        _ => Err(SchemeError::Invalid("define takes a name and a value".to_string())),
+                    },
+                    "if" => match args {
+                        [condition, then, rest @ ..] if rest.len() <= 1 => {
+                            if self.eval(condition, depth + 1)? != SchemeValue::Boolean(false) {
+                                self.eval(then, depth + 1)
+                            } else {
+                                rest.first().map_or(Ok(SchemeValue::Unspecified), |e| self.eval(e, depth + 1))
+                            }
+                        },
+                        _ => Err(SchemeError::Invalid("if takes a condition and one or two branches".to_string())),
+                    },
+                    _ => {
+                        let values = args.iter().map(|arg| self.eval(arg, depth + 1)).collect::<Result<Vec<_>, _>>()?;
+                        self.apply(name, &values)
+                    },
+                }
+            },
+            value => Ok(value.clone()),
+        }
+    }
+
+    fn variable(&self, name: &str) -> Result<SchemeValue, SchemeError> {
+        if let Some(value) = self.env.definitions.get(name) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = (self.lookup)(name) {
+            return Ok(value);
+        }
+        match name {
+            "UP" | "RIGHT" => Ok(SchemeValue::Integer(1)),
+            "DOWN" | "LEFT" => Ok(SchemeValue::Integer(-1)),
+            "CENTER" => Ok(SchemeValue::Integer(0)),
+            _ => Err(SchemeError::Unsupported(format!("Unbound variable {}", name))),
+        }
+    }
+
+    fn apply(&mut self, name: &str, args: &[SchemeValue]) -> Result<SchemeValue, SchemeError> {
+        let numbers = || args.iter().map(|arg| {
+            Number::from_value(arg).ok_or_else(|| SchemeError::Invalid(format!("{}: {} is not a number", name, arg.display())))
+        }).collect::<Result<Vec<_>, _>>();
+
+        match name {
+            "+" => Ok(numbers()?.into_iter().fold(Number::Exact(0, 1), Number::add).into_value()),
+            "*" => Ok(numbers()?.into_iter().fold(Number::Exact(1, 1), Number::mul).into_value()),
+            "-" | "/" => {
+                let numbers = numbers()?;
+                let (first, rest) = numbers.split_first()
+                    .ok_or_else(|| SchemeError::Invalid(format!("{} needs at least one argument", name)))?;
+                let (op, identity): (fn(Number, Number) -> Number, _) = if name == "-" {
+                    (Number::sub, Number::Exact(0, 1))
+                } else {
+                    (Number::div, Number::Exact(1, 1))
+                };
+                let divisors = if rest.is_empty() { std::slice::from_ref(first) } else { rest };
+                if name == "/" && divisors.iter().any(|n| n.to_f64() == 0.0) {
+                    return Err(SchemeError::Invalid("Division by zero".to_string()));
+                }
+                let result = if rest.is_empty() { op(identity, *first) } else { rest.iter().fold(*first, |acc, n| op(acc, *n)) };
+                Ok(result.into_value())
+            },
+            "=" | "<" | ">" | "<=" | ">=" => {
+                let numbers = numbers()?;
+                let holds = numbers.windows(2).all(|pair| {
+                    let (a, b) = (pair[0].to_f64(), pair[1].to_f64());
+                    match name {
+                        "=" => a == b,
+                        "<" => a < b,
+                        ">" => a > b,
+                        "<=" => a <= b,
+                        _ => a >= b,
+                    }
+                });
+                Ok(SchemeValue::Boolean(holds))
+            },
+            "min" | "max" => {
+                let numbers = numbers()?;
+                let (first, rest) = numbers.split_first()
+                    .ok_or_else(|| SchemeError::Invalid(format!("{} needs at least one argument", name)))?;
+                let result = rest.iter().fold(*first, |acc, n| {
+                    let smaller = n.to_f64() < acc.to_f64();
+                    if (name == "min") == smaller { *n } else { acc }
+                });
+                Ok(result.into_value())
+            },
+            "abs" => match numbers()?.as_slice() {
+                [n] if n.to_f64() < 0.0 => Ok(Number::Exact(0, 1).sub(*n).into_value()),
+                [n] => Ok(n.into_value()),
+                _ => Err(SchemeError::Invalid("abs takes one number".to_string())),
+            },
+            "not" => match args {
+                [value] => Ok(SchemeValue::Boolean(*value == SchemeValue::Boolean(false))),
+                _ => Err(SchemeError::Invalid("not takes one argument".to_string())),
+            },
+            "list" => Ok(SchemeValue::List(args.to_vec())),
+            "string-append" => args.iter().map(|arg| match arg {
+                SchemeValue::String(s) => Ok(s.as_str()),
+                other => Err(SchemeError::Invalid(format!("string-append: {} is not a string", other.display()))),
+            }).collect::<Result<String, _>>().map(SchemeValue::String),
+            "number->string" => match args {
+                [value] if Number::from_value(value).is_some() => Ok(SchemeValue::String(value.display())),
+                _ => Err(SchemeError::Invalid("number->string takes one number".to_string())),
+            },
+            "ly:make-moment" => {
+                // (ly:make-moment 1/8) or the older (ly:make-moment 1 8)
+                let moment = match numbers()?.as_slice() {
+                    [length] => *length,
+                    [numerator, denominator] if denominator.to_f64() != 0.0 => numerator.div(*denominator),
+                    _ => return Err(SchemeError::Invalid("ly:make-moment takes a fraction".to_string())),
+                };
+                match moment.normalize() {
+                    Number::Exact(numerator, denominator) => Ok(SchemeValue::Moment { numerator, denominator }),
+                    Number::Inexact(_) => Err(SchemeError::Invalid("ly:make-moment needs exact numbers".to_string())),
+                }
+            },
+            "set-global-staff-size" => match numbers()?.as_slice() {
+                [size] if size.to_f64() > 0.0 => {
+                    self.env.global_staff_size = Some(size.to_f64());
+                    Ok(SchemeValue::Unspecified)
+                },
+                _ => Err(SchemeError::Invalid("set-global-staff-size takes a positive number".to_string())),
+            },
+            _ => Err(SchemeError::Unsupported(format!("Unknown procedure {}", name))),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// Vertical slices: what sounds at a moment (or during a stretch of time) in every staff and voice
+// of a score. Times are whole notes from the start of the score, or seconds at the score's tempo:
+//   { from: 1.0, to: 1.0 }  ->  the notes sounding on the first beat of bar 2 in 4/4
+//   { from: 0.0, to: 1.0, mode: Onset }  ->  the notes attacked during bar 1
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lilypond_parser::{is_timed_note, measure_starts, note_fraction, ApiParsedMusic, GridMeasure, LilyPondNote, Measure, NoteType};
+use crate::properties::PropertyAction;
+
+const EPSILON: f64 = 1e-9;
+// Quarter notes per minute when the score gives no tempo, as in playback
+pub(crate) const DEFAULT_BPM: f64 = 120.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SliceMode {
+    #[default]
+    Overlap, // Notes sounding at some point of the window (held notes included)
+    Onset,   // Notes attacked inside the window
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum TimeUnit {
+    #[default]
+    Wholes,  // Whole notes from the start of the score
+    Seconds, // At the tempo of the score, following tempoWholesPerMinute changes
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SliceQuery {
+    #[serde(default)]
+    pub from: f64,
+    #[serde(default)]
+    pub to: Option<f64>,                // Exclusive; equal to `from` for a single moment, None for the end of the score
+    #[serde(default)]
+    pub unit: TimeUnit,
+    #[serde(default)]
+    pub mode: SliceMode,
+    #[serde(default)]
+    pub measures: Option<Range<usize>>, // Only notes in these measures of the score's measure grid
+    #[serde(default)]
+    pub unroll_repeats: bool,           // Repeats and jumps played out; otherwise each note is met once, as written
+}
+
+/// A note (or chord) found by a slice, with the notes tied on from it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SoundingNote {
+    pub staff: usize,
+    pub voice: Option<usize>,      // None when the staff has no voices
+    pub notes: Vec<u32>,           // Index of the attacked note, then of the notes tied to it
+    pub measure: usize,            // Measure of the attacked note, in its staff or voice
+    pub onset: f64,                // In the unit of the query
+    pub end: f64,                  // Equal to onset for grace notes
+    pub pitches: Vec<(String, i32)>,
+    pub grace: bool,
+}
+
+/// Notes matching `query` in every staff and voice, by onset, then staff and voice
+pub fn vertical_slice(music: &ApiParsedMusic, query: &SliceQuery) -> Vec<SoundingNote> {
+    let mut lines = Vec::new();
+    for (staff_index, staff) in music.staves.iter().enumerate() {
+        if staff.voices.is_empty() {
+            lines.push((staff_index, None, &staff.base.notes, &staff.measures, &staff.performance_order));
+        }
+        for (voice_index, voice) in staff.voices.iter().enumerate() {
+            lines.push((staff_index, Some(voice_index), &voice.base.notes, &voice.measures, &voice.performance_order));
+        }
+    }
+
+    let order = |notes: &[LilyPondNote], performance: &[u32]| -> Vec<u32> {
+        if query.unroll_repeats && !performance.is_empty() {
+            performance.to_vec()
+        } else {
+            (0..notes.len() as u32).collect()
+        }
+    };
+    let tempo = TempoMap::new(
+        music.tempo_bpm.unwrap_or(DEFAULT_BPM),
+        lines.iter().flat_map(|(_, _, notes, _, performance)| tempo_changes(notes, &order(notes, performance))).collect(),
+    );
+    let time = |wholes: f64| match query.unit {
+        TimeUnit::Wholes => wholes,
+        TimeUnit::Seconds => tempo.seconds(wholes),
+    };
+
+    let mut found = Vec::new();
+    for (staff, voice, notes, measures, performance) in lines {
+        let measure_of = measure_lookup(notes.len(), measures);
+        let positions = grid_positions(notes, measures, &music.measure_grid);
+        for chain in tied_chains(notes, &order(notes, performance)) {
+            let first = chain.notes[0] as usize;
+            let Some(measure) = measure_of[first] else { continue };
+            if let Some(range) = &query.measures {
+                if !positions[measure].is_some_and(|position| range.contains(&position)) {
+                    continue;
+                }
+            }
+            let sounding = SoundingNote {
+                staff,
+                voice,
+                notes: chain.notes,
+                measure,
+                onset: time(chain.onset),
+                end: time(chain.end),
+                pitches: pitches(&notes[first]),
+                grace: notes[first].note_type == NoteType::Grace,
+            };
+            if matches(query, &sounding) {
+                found.push(sounding);
+            }
+        }
+    }
+    found.sort_by(|a, b| a.onset.total_cmp(&b.onset).then(a.staff.cmp(&b.staff)).then(a.voice.cmp(&b.voice)));
+    found
+}
+
+fn matches(query: &SliceQuery, note: &SoundingNote) -> bool {
+    let from = query.from;
+    let to = query.to.unwrap_or(f64::INFINITY);
+    let instant = to <= from + EPSILON;
+    let attacked = if instant {
+        (note.onset - from).abs() < EPSILON
+    } else {
+        note.onset > from - EPSILON && note.onset < to - EPSILON
+    };
+    // Grace notes take no time in the score: they belong to the moment of the note they lead to
+    if query.mode == SliceMode::Onset || note.grace {
+        return attacked;
+    }
+    if instant {
+        note.onset < from + EPSILON && from < note.end - EPSILON
+    } else {
+        note.onset < to - EPSILON && note.end > from + EPSILON
+    }
+}
+
+// A note and the notes tied on from it, in the order they are met
+pub(crate) struct TiedChain {
+    pub notes: Vec<u32>,
+    pub onset: f64,  // Whole notes
+    pub end: f64,
+}
+
+// Sounding notes of one staff or voice, met in `order`: a note with ~ runs on into the next
+// timed note when it has the same pitches; rests, spacers and markers only take up time
+pub(crate) fn tied_chains(notes: &[LilyPondNote], order: &[u32]) -> Vec<TiedChain> {
+    let mut chains: Vec<TiedChain> = Vec::new();
+    let mut open: Option<usize> = None;
+    let mut time = 0.0;
+    for &index in order {
+        let note = &notes[index as usize];
+        if note.note_type == NoteType::Grace {
+            chains.push(TiedChain { notes: vec![index], onset: time, end: time });
+            continue;
+        }
+        if !is_timed_note(note) {
+            continue;
+        }
+        let length = note_fraction(note).unwrap_or(0.0);
+        let tied_into = open.take().filter(|&chain| {
+            let last = *chains[chain].notes.last().unwrap() as usize;
+            pitches(&notes[last]) == pitches(note)
+        });
+        let chain = match tied_into {
+            Some(chain) => {
+                chains[chain].notes.push(index);
+                chains[chain].end = time + length;
+                Some(chain)
+            },
+            None if note.note_type != NoteType::Rest => {
+                chains.push(TiedChain { notes: vec![index], onset: time, end: time + length });
+                Some(chains.len() - 1)
+            },
+            None => None,
+        };
+        if note.has_slur {
+            open = chain;
+        }
+        time += length;
+    }
+    chains
+}
+
+// Pitch and octave of every head of a note or chord
+pub(crate) fn pitches(note: &LilyPondNote) -> Vec<(String, i32)> {
+    let mut heads = vec![(note.pitch.clone(), note.octave)];
+    heads.extend(note.chord_notes.iter().cloned());
+    heads
+}
+
+// Measure (in `measures`) of each note index
+fn measure_lookup(note_count: usize, measures: &[Measure]) -> Vec<Option<usize>> {
+    let mut lookup = vec![None; note_count];
+    for (measure_index, measure) in measures.iter().enumerate() {
+        for &note in &measure.notes {
+            if let Some(slot) = lookup.get_mut(note as usize) {
+                *slot = Some(measure_index);
+            }
+        }
+    }
+    lookup
+}
+
+// Grid measure of each measure: the one it starts on, or else the one it starts inside
+fn grid_positions(notes: &[LilyPondNote], measures: &[Measure], grid: &[GridMeasure]) -> Vec<Option<usize>> {
+    measures.iter().zip(measure_starts(notes, measures))
+        .map(|(measure, start)| measure.grid.or_else(|| grid.iter().rposition(|g| g.start <= start + EPSILON)))
+        .collect()
+}
+
+// Tempo changes (moment in whole notes, quarter notes per minute) from
+// \set Score.tempoWholesPerMinute, met in `order`
+pub(crate) fn tempo_changes(notes: &[LilyPondNote], order: &[u32]) -> Vec<(f64, f64)> {
+    let mut changes = Vec::new();
+    let mut time = 0.0;
+    for &index in order {
+        let note = &notes[index as usize];
+        for event in note.properties.iter().filter(|e| e.property == "tempoWholesPerMinute" && e.action == PropertyAction::Set) {
+            if let Some(wholes) = event.value.as_ref().and_then(|v| v.as_f64()) {
+                changes.push((time, wholes * 4.0));
+            }
+        }
+        if is_timed_note(note) {
+            time += note_fraction(note).unwrap_or(0.0);
+        }
+    }
+    changes
+}
+
+pub(crate) struct TempoMap {
+    initial: f64,
+    changes: Vec<(f64, f64)>, // Sorted by moment
+}
+
+impl TempoMap {
+    pub fn new(initial: f64, mut changes: Vec<(f64, f64)>) -> Self {
+        changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        changes.dedup_by(|a, b| (a.0 - b.0).abs() < EPSILON);
+        Self { initial, changes }
+    }
+
+    // Seconds from the start of the score to `wholes`
+    pub fn seconds(&self, wholes: f64) -> f64 {
+        let mut seconds = 0.0;
+        let mut moment = 0.0;
+        let mut bpm = self.initial;
+        for &(change, new_bpm) in &self.changes {
+            if change > wholes {
+                break;
+            }
+            seconds += (change - moment) * 240.0 / bpm;
+            moment = change;
+            bpm = new_bpm;
+        }
+        seconds + (wholes - moment) * 240.0 / bpm
+    }
+}
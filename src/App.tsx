@@ -15,6 +15,7 @@ function App() {
   const [isPlaying, setIsPlaying] = useState(false);
   const [currentNoteIndices, setCurrentNoteIndices] = useState<Map<number, number>>(new Map());
   const [lilypondContent, setLilypondContent] = useState('');
+  const [musicSource, setMusicSource] = useState<{ content?: string; filePath?: string }>({});
   const [isSettingsOpen, setIsSettingsOpen] = useState(false);
   const [isExportDropdownOpen, setIsExportDropdownOpen] = useState(false);
   const [isExporting, setIsExporting] = useState(false);
@@ -45,10 +46,71 @@ function App() {
   // Get available instruments from AudioPlayer's INSTRUMENT_SAMPLES
   const availableInstruments = Object.keys(INSTRUMENT_SAMPLES);
 
+  // Show parsed music and reset per-staff playback state for its staves
+  const showParsedMusic = (parsed: ParsedMusic) => {
+    setMusicData(parsed);
+    
+    // Initialize currentNoteIndices to highlight the first real note (not Clef/Time marker) of each staff
+    const initialIndices = new Map<number, number>();
+    if (parsed?.staves && parsed.staves.length > 0) {
+      parsed.staves.forEach((staff, staffIndex) => {
+        // Check if staff has voices or notes
+        if (staff.voices && staff.voices.length > 0) {
+          // For multi-voice staves, find first real note in first voice
+          const firstVoice = staff.voices[0];
+          // Use ALL notes in base (complete list with all markers)
+          const allNotes = firstVoice.base.notes || [];
+          const firstRealNoteIndex = allNotes.findIndex(
+            note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key' 
+                 && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
+                 && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
+          );
+          if (firstRealNoteIndex !== -1) {
+            initialIndices.set(staffIndex, firstRealNoteIndex);
+          } else {
+            initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
+          }
+        } else if (staff.measures && staff.measures.length > 0) {
+          // For single-voice staff, find first real note in all notes
+          // Use ALL notes in staff (complete list with all markers)
+          const allNotes = staff.notes || [];
+          const firstRealNoteIndex = allNotes.findIndex(
+            note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key'
+                 && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
+                 && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
+          );
+          if (firstRealNoteIndex !== -1) {
+            initialIndices.set(staffIndex, firstRealNoteIndex);
+          } else {
+            initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
+          }
+        }
+      });
+      
+      // Initialize staff instruments and volumes
+      const newStaffInstruments = new Map<number, string>();
+      const newStaffVolumes = new Map<number, boolean>();
+      
+      // Try to restore staffInstruments from localStorage
+      const savedInstruments = localStorage.getItem('staffInstruments');
+      const instrumentsMap = savedInstruments ? JSON.parse(savedInstruments) : {};
+      
+      parsed.staves.forEach((_, staffIndex) => {
+        // Use saved instrument if available, otherwise use selectedInstrument
+        newStaffInstruments.set(staffIndex, instrumentsMap[staffIndex] || selectedInstrument);
+        newStaffVolumes.set(staffIndex, true);
+      });
+      setStaffInstruments(newStaffInstruments);
+      setStaffVolumes(newStaffVolumes);
+    }
+    setCurrentNoteIndices(initialIndices);
+  };
+
   const loadSampleMusic = async () => {
     try {
       const sampleContent = await invoke<string>('get_sample_lilypond');
       setLilypondContent(sampleContent);
+      setMusicSource({ content: sampleContent });
       console.log('Sample content:', sampleContent);
       const parsed = await invoke<ParsedMusic>('parse_lilypond_content', { content: sampleContent });
       console.log('Parsed music data:', parsed);
@@ -60,62 +122,7 @@ function App() {
         }
       }) || [];
       console.log('Number of notes:', allNotes.length);
-      setMusicData(parsed);
-      
-      // Initialize currentNoteIndices to highlight the first real note (not Clef/Time marker) of each staff
-      const initialIndices = new Map<number, number>();
-      if (parsed?.staves && parsed.staves.length > 0) {
-        parsed.staves.forEach((staff, staffIndex) => {
-          // Check if staff has voices or notes
-          if (staff.voices && staff.voices.length > 0) {
-            // For multi-voice staves, find first real note in first voice
-            const firstVoice = staff.voices[0];
-            // Use ALL notes in base (complete list with all markers)
-            const allNotes = firstVoice.base.notes || [];
-            const firstRealNoteIndex = allNotes.findIndex(
-              note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key' 
-                   && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
-                   && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
-            );
-            if (firstRealNoteIndex !== -1) {
-              initialIndices.set(staffIndex, firstRealNoteIndex);
-            } else {
-              initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
-            }
-          } else if (staff.measures && staff.measures.length > 0) {
-            // For single-voice staff, find first real note in all notes
-            // Use ALL notes in staff (complete list with all markers)
-            const allNotes = staff.notes || [];
-            const firstRealNoteIndex = allNotes.findIndex(
-              note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key'
-                   && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
-                   && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
-            );
-            if (firstRealNoteIndex !== -1) {
-              initialIndices.set(staffIndex, firstRealNoteIndex);
-            } else {
-              initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
-            }
-          }
-        });
-        
-        // Initialize staff instruments and volumes
-        const newStaffInstruments = new Map<number, string>();
-        const newStaffVolumes = new Map<number, boolean>();
-        
-        // Try to restore staffInstruments from localStorage
-        const savedInstruments = localStorage.getItem('staffInstruments');
-        const instrumentsMap = savedInstruments ? JSON.parse(savedInstruments) : {};
-        
-        parsed.staves.forEach((_, staffIndex) => {
-          // Use saved instrument if available, otherwise use selectedInstrument
-          newStaffInstruments.set(staffIndex, instrumentsMap[staffIndex] || selectedInstrument);
-          newStaffVolumes.set(staffIndex, true);
-        });
-        setStaffInstruments(newStaffInstruments);
-        setStaffVolumes(newStaffVolumes);
-      }
-      setCurrentNoteIndices(initialIndices);
+      showParsedMusic(parsed);
     } catch (error) {
       console.error('Error loading sample music:', error);
     }
@@ -133,68 +140,26 @@ function App() {
 
       if (selected) {
         const parsed = await invoke<ParsedMusic>('parse_lilypond_file', { filePath: selected });
-        setMusicData(parsed);
-        
-        // Initialize currentNoteIndices to highlight the first real note (not Clef/Time marker) of each staff
-        const initialIndices = new Map<number, number>();
-        if (parsed?.staves && parsed.staves.length > 0) {
-          parsed.staves.forEach((staff, staffIndex) => {
-            // Check if staff has voices or notes
-            if (staff.voices && staff.voices.length > 0) {
-              // For multi-voice staves, find first real note in first voice
-              const firstVoice = staff.voices[0];
-              // Use ALL notes in base (complete list with all markers)
-              const allNotes = firstVoice.base.notes || [];
-              const firstRealNoteIndex = allNotes.findIndex(
-                note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key'
-                     && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
-                     && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
-              );
-              if (firstRealNoteIndex !== -1) {
-                initialIndices.set(staffIndex, firstRealNoteIndex);
-              } else {
-                initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
-              }
-            } else if (staff.measures && staff.measures.length > 0) {
-              // For single-voice staff, find first real note in all notes
-              // Use ALL notes in staff (complete list with all markers)
-              const allNotes = staff.notes || [];
-              const firstRealNoteIndex = allNotes.findIndex(
-                note => note.note_type !== 'Clef' && note.note_type !== 'Time' && note.note_type !== 'Key'
-                     && note.note_type !== 'RepeatStart' && note.note_type !== 'RepeatEnd'
-                     && note.note_type !== 'AlternativeStart' && note.note_type !== 'AlternativeEnd'
-              );
-              if (firstRealNoteIndex !== -1) {
-                initialIndices.set(staffIndex, firstRealNoteIndex);
-              } else {
-                initialIndices.set(staffIndex, 0); // Default to 0 if no real notes found
-              }
-            }
-          });
-          
-          // Initialize staff instruments and volumes
-          const newStaffInstruments = new Map<number, string>();
-          const newStaffVolumes = new Map<number, boolean>();
-          
-          // Try to restore staffInstruments from localStorage
-          const savedInstruments = localStorage.getItem('staffInstruments');
-          const instrumentsMap = savedInstruments ? JSON.parse(savedInstruments) : {};
-          
-          parsed.staves.forEach((_, staffIndex) => {
-            // Use saved instrument if available, otherwise use selectedInstrument
-            newStaffInstruments.set(staffIndex, instrumentsMap[staffIndex] || selectedInstrument);
-            newStaffVolumes.set(staffIndex, true);
-          });
-          setStaffInstruments(newStaffInstruments);
-          setStaffVolumes(newStaffVolumes);
-        }
-        setCurrentNoteIndices(initialIndices);
+        setMusicSource({ filePath: selected });
+        showParsedMusic(parsed);
       }
     } catch (error) {
       console.error('Error opening file:', error);
     }
   };
 
+  // Load another \score of the same file
+  const selectMovement = async (movement: number) => {
+    try {
+      const parsed = musicSource.filePath
+        ? await invoke<ParsedMusic>('parse_lilypond_file', { filePath: musicSource.filePath, movement })
+        : await invoke<ParsedMusic>('parse_lilypond_content', { content: musicSource.content ?? '', movement });
+      showParsedMusic(parsed);
+    } catch (error) {
+      console.error('Error loading movement:', error);
+    }
+  };
+
   const handlePlay = () => {
     if (audioPlayerRef.current) {
       audioPlayerRef.current.play();
@@ -330,6 +295,24 @@ function App() {
           <>
             <div className="controls">
               <div className="controls-left">
+                {musicData.movements && musicData.movements.length > 1 && (
+                  <div className="instrument-control">
+                    <label htmlFor="movementSelect">乐章:</label>
+                    <select
+                      id="movementSelect"
+                      value={musicData.movement ?? 0}
+                      onChange={(e) => selectMovement(parseInt(e.target.value, 10))}
+                      className="instrument-select"
+                      disabled={isPlaying || isExporting}
+                    >
+                      {musicData.movements.map(movement => (
+                        <option key={movement.index} value={movement.index}>
+                          {movement.index + 1}. {movement.title || `Score ${movement.index + 1}`}
+                        </option>
+                      ))}
+                    </select>
+                  </div>
+                )}
                 {musicData?.staves && musicData.staves.length > 0 ? (
                   <div className="staff-controls-container">
                     {musicData.staves.map((staff, staffIndex) => (
@@ -507,6 +490,7 @@ function App() {
             <AudioPlayer
               ref={audioPlayerRef}
               staves={musicData?.staves}
+              timeline={musicData?.timeline}
               onNoteProgress={handleNoteProgress}
               onPlaybackEnd={handlePlaybackEnd}
               selectedInstrument={selectedInstrument}
@@ -1,8 +1,8 @@
 import { useImperativeHandle, forwardRef, useRef, useEffect } from 'react';
 import * as Tone from 'tone';
 import { Midi } from '@tonejs/midi';
-import { durationToSecondsMap } from '../utils/musicMaps';
-import type { LilyPondNote, Staff } from '../utils/musicMaps';
+import { durationToSecondsMap, pedalControllers } from '../utils/musicMaps';
+import type { LilyPondNote, Staff, Timeline } from '../utils/musicMaps';
 
 // Helper function to check if a note is non-playable (metadata or rest)
 const isNonPlayableNote = (note: LilyPondNote): boolean => {
@@ -12,6 +12,11 @@ const isNonPlayableNote = (note: LilyPondNote): boolean => {
   || note.note_type === 'Key' || note.note_type === 'Ottava';
 };
 
+// Time in seconds (at the score's tempo) when the last note stops sounding, pedals included
+const timelineEnd = (timeline: Timeline): number => {
+  return timeline.notes.reduce((end, note) => Math.max(end, note.start + note.sounding), timeline.duration);
+};
+
 // Helper function to process notes with repeat volta logic
 const processNotesWithRepeatVolta = (allNotes: LilyPondNote[]): { note: LilyPondNote; transportTime: number; originalIndex: number }[] => {
   const processedNotes: { note: LilyPondNote; transportTime: number }[] = [];
@@ -314,8 +319,12 @@ export const INSTRUMENT_SAMPLES = {
   }
 };
 
+// Velocity of notes played with the una corda pedal down, as a part of their own
+const UNA_CORDA_VELOCITY = 0.7;
+
 interface AudioPlayerProps {
   staves?: Staff[];
+  timeline?: Timeline;  // The music as played, from the backend; preferred over the staves when present
   onNoteProgress: (staffIndex: number, noteIndex: number) => void;
   onPlaybackEnd: () => void;
   selectedInstrument?: string;
@@ -324,7 +333,7 @@ interface AudioPlayerProps {
   tempo?: number;
 }
 
-const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress, onPlaybackEnd, selectedInstrument = 'piano', staffInstruments = new Map(), staffVolumes = new Map(), tempo = 1.0 }, ref) => {
+const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, timeline, onNoteProgress, onPlaybackEnd, selectedInstrument = 'piano', staffInstruments = new Map(), staffVolumes = new Map(), tempo = 1.0 }, ref) => {
   const synthsRef = useRef<Map<number, Tone.Sampler | Tone.Synth>>(new Map());
   const isPlayingRef = useRef(false);
   const scheduledEventsRef = useRef<number[]>([]);
@@ -498,7 +507,7 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
     }).toDestination();
   };
 
-  const playNote = (synth: Tone.Sampler | Tone.Synth, pitch: string | string[], duration: number, time: number, volume: boolean = true) => {
+  const playNote = (synth: Tone.Sampler | Tone.Synth, pitch: string | string[], duration: number, time: number, volume: boolean = true, velocity: number = 1) => {
     // If volume is off, don't play the note
     if (!volume) {
       return;
@@ -507,14 +516,61 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
     if (Array.isArray(pitch)) {
       // Chord: play all notes simultaneously
       pitch.forEach(p => {
-        synth.triggerAttackRelease(p, duration, time);
+        synth.triggerAttackRelease(p, duration, time, velocity);
       });
     } else {
       // Single note
-      synth.triggerAttackRelease(pitch, duration, time);
+      synth.triggerAttackRelease(pitch, duration, time, velocity);
     }
   };
 
+  // Schedule every note of the performance timeline (grace notes already have their own time,
+  // and each note its velocity and played length). Notes ring on while the pedals hold them,
+  // and the una corda pedal makes them softer.
+  // With highlight, the notes of each staff's first voice are highlighted as they start
+  const scheduleTimeline = (
+    timeline: Timeline,
+    synths: Map<number, Tone.Sampler | Tone.Synth>,
+    highlight: boolean
+  ) => {
+    timeline.notes.forEach((performed) => {
+      const synth = synths.get(performed.staff);
+      if (!synth) return;
+      const staffVolume = staffVolumes.get(performed.staff) ?? true;
+      const pitches = performed.keys.map(key => Tone.Frequency(key, 'midi').toNote());
+      const start = performed.start / tempo;
+      const noteEventId = Tone.Transport.schedule((time) => {
+        if (highlight && !isPlayingRef.current) return;
+        const velocity = performed.soft ? performed.velocity * UNA_CORDA_VELOCITY : performed.velocity;
+        playNote(synth, pitches, performed.sounding / tempo, time, staffVolume, velocity);
+      }, start);
+      scheduledEventsRef.current.push(noteEventId);
+
+      if (highlight && (performed.voice ?? 0) === 0) {
+        // Update UI slightly BEFORE the audio starts to avoid visual lag
+        const uiEventId = Tone.Transport.schedule((time) => {
+          if (!isPlayingRef.current) return;
+          Tone.Draw.schedule(() => {
+            if (isPlayingRef.current) {
+              currentNoteIndicesRef.current.set(performed.staff, performed.note);
+              onNoteProgress(performed.staff, performed.note);
+            }
+          }, time);
+        }, Math.max(0, start - 0.05));
+        scheduledEventsRef.current.push(uiEventId);
+      }
+    });
+  };
+
+  // Synth of each loaded staff
+  const synthsByStaff = (loadedStaves: ({ staffIndex: number; synth: Tone.Sampler | Tone.Synth } | null)[]) => {
+    const synths = new Map<number, Tone.Sampler | Tone.Synth>();
+    loadedStaves.forEach((staffData) => {
+      if (staffData) synths.set(staffData.staffIndex, staffData.synth);
+    });
+    return synths;
+  };
+
   const exportAudio = async (format: 'wav' | 'mp3', filename?: string) => {
     try {
       if (Tone.context.state !== 'running') {
@@ -561,7 +617,8 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
       const loadedStaves = await Promise.all(synthPromises);
       
       loadedStaves.forEach((staffData) => {
-        if (!staffData) return;
+        // The timeline is scheduled below instead
+        if (!staffData || timeline) return;
         const { staff, staffIndex, synth } = staffData;
         const staffVolume = staffVolumes.get(staffIndex) ?? true;
 
@@ -642,7 +699,10 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
           totalDuration = Math.max(totalDuration, staffDuration);
         }
       });
-      
+      if (timeline) {
+        scheduleTimeline(timeline, synthsByStaff(loadedStaves), false);
+        totalDuration = timelineEnd(timeline) / tempo;
+      }
 
       // Start playback
       Tone.Transport.start();
@@ -732,8 +792,47 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
         return baseDuration;
       };
 
+      // The performance timeline: one track per staff or voice
+      if (timeline) {
+        const tracks = new Map<string, ReturnType<typeof midi.addTrack>>();
+        timeline.notes.forEach((performed) => {
+          if (!(staffVolumes.get(performed.staff) ?? true)) return;
+          const key = `${performed.staff}/${performed.voice ?? 0}`;
+          let track = tracks.get(key);
+          if (!track) {
+            track = midi.addTrack();
+            const staff = staves![performed.staff];
+            const voice = performed.voice !== null ? staff?.voices?.[performed.voice] : undefined;
+            track.name = [staff?.name, voice?.base.name].filter(Boolean).join(' - ');
+            tracks.set(key, track);
+          }
+          performed.keys.forEach(midiNote => {
+            track!.addNote({
+              midi: midiNote,
+              time: performed.start,
+              duration: performed.duration,
+              velocity: performed.velocity
+            });
+          });
+        });
+        // Pedals as controllers on the tracks of the staves they act on
+        timeline.pedals.forEach((change) => {
+          tracks.forEach((track, key) => {
+            const staff = Number(key.split('/')[0]);
+            if (staff < change.staves.start || staff >= change.staves.end) return;
+            track.addCC({
+              number: pedalControllers[change.pedal],
+              value: change.down ? 1 : 0,
+              time: change.time
+            });
+          });
+        });
+      }
+
       // Process all staves
       staves!.forEach((staff, staffIndex) => {
+        // The timeline has been exported above instead
+        if (timeline) return;
         // Check if staff has voices or measures
         const hasVoices = staff.voices && staff.voices.length > 0;
         const hasMeasures = staff.measures && staff.measures.length > 0;
@@ -957,7 +1056,8 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
         const loadedStaves = await Promise.all(synthPromises);
         
         loadedStaves.forEach((staffData) => {
-          if (!staffData) return;
+          // The timeline is scheduled below instead
+          if (!staffData || timeline) return;
           const { staff, staffIndex, synth } = staffData;
           const staffVolume = staffVolumes.get(staffIndex) ?? true;
 
@@ -1061,6 +1161,10 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
           }
         });
 
+        if (timeline) {
+          scheduleTimeline(timeline, synthsByStaff(loadedStaves), true);
+        }
+
         // Calculate total duration (max duration across all staves)
         let maxDuration = 0;
         staves!.forEach(staff => {
@@ -1122,7 +1226,7 @@ const AudioPlayer = forwardRef<any, AudioPlayerProps>(({ staves, onNoteProgress,
             
             onPlaybackEnd();
           }, time);
-        }, maxDuration);
+        }, timeline ? timelineEnd(timeline) / tempo : maxDuration);
 
         scheduledEventsRef.current.push(endEventId);
         
@@ -1,7 +1,7 @@
 import React, { useEffect, useRef } from 'react';
-import { Renderer, Stave, StaveNote, Voice, Formatter, Accidental, Flow, StaveConnector, Beam, Dot, Curve, TextBracket, GraceNote, GraceNoteGroup, Volta, Barline, ClefNote, Tuplet, Note } from 'vexflow';
-import { durationMap, pitchMap, jianpuMap, vexFlowDurationMap, shouldShowAccidental } from '../utils/musicMaps';
-import type { LilyPondNote, Lyric, VoiceData, Staff, ParsedMusic, Measure } from '../utils/musicMaps';
+import { Renderer, Stave, StaveNote, Voice, Formatter, Accidental, Flow, StaveConnector, Beam, Dot, Curve, TextBracket, GraceNote, GraceNoteGroup, GhostNote, Volta, Barline, ClefNote, Tuplet, Note, Ornament, Tremolo, KeySignature } from 'vexflow';
+import { durationMap, pitchMap, jianpuMap, vexFlowDurationMap, vexFlowOrnaments, tremoloStrokes, vexFlowKeySignature, shouldShowAccidental, accidentalSymbols } from '../utils/musicMaps';
+import type { LilyPondNote, Lyric, VoiceData, Staff, ParsedMusic, Measure, PrintedAccidental, StaffGroup, InstrumentNames, TupletGroup } from '../utils/musicMaps';
 
 interface MusicNotationProps {
   musicData: ParsedMusic;
@@ -167,6 +167,36 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     
     ctx.restore();
   };
+
+  /**
+   * Draw a percent repeat sign (a slash between two dots) in the middle of a measure
+   * @param ctx - Canvas rendering context
+   * @param stave - Stave of the repeated measure
+   * @param isHighlighted - Draw in the highlight color
+   */
+  const drawPercentRepeat = (ctx: any, stave: any, isHighlighted: boolean = false) => {
+    const centerX = stave.getX() + stave.getWidth() / 2;
+    const top = stave.getYForLine(1);
+    const bottom = stave.getYForLine(3);
+    const color = isHighlighted ? '#ff6b6b' : '#000000';
+
+    ctx.save();
+    ctx.strokeStyle = color;
+    ctx.fillStyle = color;
+    ctx.lineWidth = 5;
+    ctx.beginPath();
+    ctx.moveTo(centerX - 8, bottom);
+    ctx.lineTo(centerX + 8, top);
+    ctx.stroke();
+
+    ctx.beginPath();
+    ctx.arc(centerX - 9, top + 3, 2.5, 0, Math.PI * 2, false);
+    ctx.fill();
+    ctx.beginPath();
+    ctx.arc(centerX + 9, bottom - 3, 2.5, 0, Math.PI * 2, false);
+    ctx.fill();
+    ctx.restore();
+  };
   /**
    * Convert VexFlow note to Jianpu (simplified notation) information
    * Extracts information directly from the VexFlow StaveNote object
@@ -487,7 +517,8 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     if (measureIndex === 0) {
       if (musicData.key_signature) {
         try {
-          measureStave.addKeySignature(musicData.key_signature);
+          const { spec, alter } = vexFlowKeySignature(musicData.key_signature);
+          measureStave.addModifier(new KeySignature(spec, undefined, alter));
         } catch (error) {
           measureStave.addKeySignature('C');
         }
@@ -539,6 +570,10 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     return { stave: measureStave, clefToDisplay, timeSignatureToDisplay };
   };
 
+  // Stem direction from \voiceOne, \stemUp ... (1 = up, -1 = down); otherwise VexFlow chooses
+  const stemOptions = (note: LilyPondNote) =>
+    note.stem_direction ? { stem_direction: note.stem_direction === 'Up' ? 1 : -1 } : { auto_stem: true };
+
   /**
    * Create a VexFlow note from a LilyPond note
    * @param note - LilyPond note object
@@ -646,7 +681,7 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
         clef: clef,
         keys: keys,
         duration: duration,
-        auto_stem: true
+        ...stemOptions(note)
       });
       
       // Add dots
@@ -657,6 +692,7 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       (staveNote as any)._lilypondPitch = note.pitch;
       (staveNote as any)._lilypondOctave = note.octave;
       (staveNote as any)._lilypondChordNotes = note.chord_notes;
+      (staveNote as any)._lilypondAccidentals = note.accidentals;
 
       // Add arpeggio marking if present
       if (note.arpeggio) {
@@ -667,7 +703,10 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       const vexNote = convertLilyPondToVexFlow(note);
       const parts = vexNote.split('/');
       
-      if (parts[0] === 'r') {
+      if (parts[0] === 'r' && note.spacer) {
+        // Spacer rest (s, or padding of a temporary voice): takes its time but draws nothing
+        staveNote = new GhostNote({ duration: parts[1] + 'd'.repeat(note.dots?.length || 0) });
+      } else if (parts[0] === 'r') {
         // Create a regular rest
         let duration = parts[1];
         if (note.dots && note.dots.length > 0) {
@@ -730,7 +769,9 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
           clef: clef,
           keys: [`${pitch}/${octave}`],
           duration: noteDuration,
-          auto_stem: true
+          ...stemOptions(note),
+          // Slashed stem for \acciaccatura and \slashedGrace
+          ...(isGraceNote ? { slash: note.grace_slash ?? false } : {})
         });
 
         // Add dots only for non-grace notes
@@ -741,6 +782,7 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
         // Store pitch and octave info for later accidental processing
         (staveNote as any)._lilypondPitch = note.pitch;
         (staveNote as any)._lilypondOctave = note.octave;
+        (staveNote as any)._lilypondAccidentals = note.accidentals;
         
         // Mark grace notes for later attachment to the following note
         if (isGraceNote) {
@@ -754,6 +796,24 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       (staveNote as any)._arpeggio = true;
     }
 
+    // Ornament sign above the note
+    if (note.ornament) {
+      staveNote.addModifier(new Ornament(vexFlowOrnaments[note.ornament]), 0);
+    }
+
+    // Tremolo strokes through the stem; both notes of a measured tremolo carry them
+    if (note.tremolo) {
+      const strokes = tremoloStrokes(note.tremolo, note.duration);
+      if (strokes > 0) {
+        staveNote.addModifier(new Tremolo(strokes), 0);
+      }
+    }
+
+    // Notes repeated by \repeat percent are drawn as a percent sign instead
+    if (note.percent_repeat) {
+      (staveNote as any)._percentRepeat = note.percent_repeat;
+    }
+
     // Highlight current note for this staff
     const currentNoteForStaff = currentNoteIndices.get(staffIndex);
     if (currentNoteForStaff !== undefined && noteIndex === currentNoteForStaff) {
@@ -769,6 +829,8 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     if (note.tuplet_fraction) {
       (staveNote as any)._tupletFraction = note.tuplet_fraction;
     }
+    // Tuplet brackets around the note, outermost first
+    (staveNote as any)._tuplets = note.tuplets ?? [];
 
     return staveNote;
   };
@@ -791,10 +853,18 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
   ) => {
     // Create beams using Beam.generateBeams for automatic beaming
     // Filter out grace notes from beaming since they're handled by GraceNoteGroup
-    const nonGraceNotes = measureNotes.filter((note: any) => !(note as any)._isGraceNote && !(note as any)._tupletFraction);
+    const nonGraceNotes = measureNotes.filter((note: any) => !(note as any)._isGraceNote && !(note as any)._tupletFraction && !(note instanceof GhostNote));
     var beams = Beam.generateBeams(nonGraceNotes);
     beams = beams.concat(beamsForTuplets);
     
+    // A measure made only of \repeat percent notes shows just the percent sign
+    if (measureNotes.length > 0 && measureNotes.every((note: any) => note._percentRepeat)) {
+      voice.getTickables().forEach((note: any) => note.setStave(note._crossStave ?? measureStave));
+      const isHighlighted = measureNotes.some((note: any) => note.getStyle?.()?.fillStyle === '#ff6b6b');
+      drawPercentRepeat(context, measureStave, isHighlighted);
+      return;
+    }
+
     // Hide multi-measure rests before drawing voice (they will be drawn manually later)
     // Grace notes should NOT be hidden since they're attached as modifiers to regular notes
     const hiddenNotes: any[] = [];
@@ -812,8 +882,9 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     }
     
     // Beams should be created before voice rendering.
-    // Draw voice with beamed notes
-    voice.draw(context, measureStave);
+    // Draw voice with beamed notes, each on its own stave (see _crossStave)
+    voice.getTickables().forEach((note: any) => note.setStave(note._crossStave ?? measureStave));
+    voice.draw(context);
     
     // Restore original styles
     for (const note of hiddenNotes) {
@@ -1480,39 +1551,35 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       context.font = 'bold 16px Arial';
       context.fillStyle = '#666666';
       context.textAlign = 'right';
-      // Measure numbers start from 1
-      const measureNumber = startMeasure + 1;
+      // Bar numbers come from the score's measure grid (a pickup is bar 0)
+      const measureNumber = musicData.measure_grid?.[startMeasure]?.number ?? startMeasure + 1;
       context.fillText(measureNumber.toString(), marginLeft - 20, rowY + 25);
       context.restore();
     }
   };
 
-  // Helper function to create and configure a tuplet with proper direction
-  const createTuplet = (tupletNotes: any[], fraction: string | null) => {
-    if (tupletNotes.length === 0 || !fraction) return { tuplet: undefined, beam: undefined };
+  // Helper function to create and configure a tuplet bracket with proper direction.
+  // Only the outermost bracket beams its notes; nested brackets are drawn over that beam
+  const createTuplet = (tupletNotes: any[], numerator: number, outermost: boolean) => {
+    if (tupletNotes.length === 0) return { tuplet: undefined, beam: undefined };
 
-    const beam: Beam = new Beam(tupletNotes, true);
+    const beam: Beam | undefined = outermost ? new Beam(tupletNotes, true) : undefined;
     // Determine direction using beam information first
-    const tupletDirection = beam.getStemDirection?.();
+    const tupletDirection = beam?.getStemDirection?.();
     const tupletLocation = tupletDirection === -1 ? 
       (Tuplet as any).LOCATION_BOTTOM : 
       (Tuplet as any).LOCATION_TOP;
     
+    // The number shown is the tuplet's numerator; ticks stay as written, as measureTicks
+    // applies the combined ratio itself
     const tuplet = new Tuplet(tupletNotes, {
       location: tupletLocation,
+      num_notes: numerator,
+      notes_occupied: numerator,
     });
     tupletNotes.forEach((note) => {
       note.setTuplet(tuplet);
     });
-    
-    // Parse tuplet_fraction format: "n/m" where n notes occupy m beats
-    const fractionParts = fraction.split('/');
-    if (fractionParts.length === 2) {
-      const notesCount = parseInt(fractionParts[0], 10);
-      if (!isNaN(notesCount)) {
-        tuplet.setNotesOccupied(notesCount);
-      }
-    }
       
     return {tuplet, beam};
   };
@@ -1529,7 +1596,8 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
     beatValue: number,
     numBeats: number,
     staff: Staff,
-    rowIndex: number) => {
+    rowIndex: number,
+    staffYFor: (staffIndex: number) => number) => {
     const rowStaffMeasures: Stave[] = [];
 
     var bottomYPosition = -1;
@@ -1586,9 +1654,8 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
           // Process grace notes and tuplets together: group consecutive grace notes/tuplets and attach to following note
           const processedNotes: any[] = [];
           var graceNoteBuffer: any[] = [];
-          var currentTupletFraction: string | null = null;
-          var currentTupletNotes: any[] = [];
-          var tupletNotesPerGroup = 0;
+          // Notes of each tuplet bracket in this measure, by bracket id, outer brackets first
+          const tupletGroups = new Map<number, { notes: any[]; numerator: number; outermost: boolean }>();
           const tuplets: Tuplet[] = [];
           const beamsForTuplets: Beam[] = [];
           
@@ -1599,51 +1666,12 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
               // Collect grace notes
               graceNoteBuffer.push(currentNote);
             } else {
-              // First, handle any pending tuplets before processing the regular note
-              if ((currentNote as any)._tupletFraction) {
-                const tupletFraction = (currentNote as any)._tupletFraction;
-                
-                // If tuplet fraction changed, finalize previous tuplet group
-                if (tupletFraction !== currentTupletFraction) {
-                  if (currentTupletNotes.length > 0) {
-                    const {tuplet, beam} = createTuplet(currentTupletNotes, currentTupletFraction);
-                    if (tuplet) tuplets.push(tuplet);
-                    if (beam) beamsForTuplets.push(beam);
-                  }
-                  currentTupletNotes = [];
-                  tupletNotesPerGroup = 0;
-                  currentTupletFraction = tupletFraction;
-                  
-                  // Parse new tuplet fraction
-                  const fractionParts = tupletFraction.split('/');
-                  if (fractionParts.length === 2) {
-                    tupletNotesPerGroup = parseInt(fractionParts[0], 10);
-                    if (isNaN(tupletNotesPerGroup)) {
-                      tupletNotesPerGroup = 0;
-                    }
-                  }
-                }
-                
-                currentTupletNotes.push(currentNote);
-                
-                // When we have enough notes for a tuplet group, create the tuplet
-                if (tupletNotesPerGroup > 0 && currentTupletNotes.length === tupletNotesPerGroup) {
-                  const {tuplet, beam} = createTuplet(currentTupletNotes, currentTupletFraction);
-                  if (tuplet) tuplets.push(tuplet);
-                  if (beam) beamsForTuplets.push(beam);
-                  currentTupletNotes = [];
-                }
-              } else {
-                // Non-tuplet note: finalize any pending tuplet
-                if (currentTupletNotes.length > 0) {
-                  const {tuplet, beam} = createTuplet(currentTupletNotes, currentTupletFraction);
-                  if (tuplet) tuplets.push(tuplet);
-                  if (beam) beamsForTuplets.push(beam);
-                }
-                currentTupletNotes = [];
-                currentTupletFraction = null;
-                tupletNotesPerGroup = 0;
-              }
+              // First, add the note to the tuplet brackets it is in
+              ((currentNote as any)._tuplets ?? []).forEach((group: TupletGroup, level: number) => {
+                const bracket = tupletGroups.get(group.id) ?? { notes: [], numerator: group.numerator, outermost: level === 0 };
+                bracket.notes.push(currentNote);
+                tupletGroups.set(group.id, bracket);
+              });
               
               // Create GraceNoteGroup for buffered grace notes and attach to current note
               if (graceNoteBuffer.length > 0) {
@@ -1670,13 +1698,14 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
             }
           }
           
-          // Finalize any remaining tuplets or grace notes (edge case)
-          if (currentTupletNotes.length > 0) {
-            const {tuplet, beam} = createTuplet(currentTupletNotes, currentTupletFraction);
+          // Create the tuplet brackets, outer brackets first so that nested ones stack above them
+          tupletGroups.forEach(({ notes, numerator, outermost }) => {
+            const {tuplet, beam} = createTuplet(notes, numerator, outermost);
             if (tuplet) tuplets.push(tuplet);
             if (beam) beamsForTuplets.push(beam);
-          }
-          
+          });
+
+          // Finalize any remaining grace notes (edge case)
           if (graceNoteBuffer.length > 0) {
             console.warn(`[GraceNoteGroup] Trailing grace notes not attached:`, graceNoteBuffer.length);
             processedNotes.push(...graceNoteBuffer);
@@ -1723,6 +1752,15 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
         //formatter.joinVoices(allVoices).format(allVoices, formatWidth);
         formatter.joinVoices(allVoices).formatToStave(allVoices, measureStave);
         // const minWidth = formatter.preCalculateMinTotalWidth(allVoices);
+
+        // Notes moved by \change Staff keep their place in the measure but sit on the other staff
+        voicesToRender.forEach(({ measureNotes }) => {
+          measureNotes.forEach((note: any) => {
+            if (note._displayStaff !== undefined) {
+              note._crossStave = new Stave(measureStave.getX(), staffYFor(note._displayStaff), measureStave.getWidth());
+            }
+          });
+        });
         
         voicesToRender.forEach(({ voice, measureNotes, tuplets, beamsForTuplets}) => {
           drawVoiceWithDecorations(context, voice, measureStave, measureNotes, tuplets, beamsForTuplets, marginLeft);
@@ -1745,6 +1783,43 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       }
     }
   };
+  // A connector at the left of the row between two staff positions, optionally labelled
+  const drawConnector = (context: any, type: number, topY: number, bottomY: number, x: number, xShift: number, label: string | null) => {
+    const connector = new StaveConnector(new Stave(x, topY, 0), new Stave(x, bottomY, 0));
+    connector.setType(type);
+    connector.setXShift(xShift);
+    if (label) {
+      connector.setText(label);
+    }
+    connector.setContext(context).draw();
+  };
+
+  const instrumentLabel = (instrument: InstrumentNames | undefined, firstRow: boolean) =>
+    (firstRow ? instrument?.name : instrument?.short_name) ?? null;
+
+  // Levels of nesting below (and including) these groups
+  const groupDepth = (groups: StaffGroup[]): number =>
+    groups.reduce((depth, group) => Math.max(depth, 1 + groupDepth(group.groups)), 0);
+
+  const delimiterTypes: { [delimiter: string]: number } = {
+    Brace: StaveConnector.type.BRACE,
+    Bracket: StaveConnector.type.BRACKET,
+    Square: StaveConnector.type.BRACKET,
+    Bar: StaveConnector.type.SINGLE_LEFT,
+  };
+  // Outer groups are drawn further left than the groups nested in them
+  const drawStaffGroups = (context: any, groups: StaffGroup[], staffY: (staffIndex: number) => number,
+                           x: number, firstRow: boolean, level: number, depth: number) => {
+    groups.forEach((group) => {
+      if (group.staves.end > group.staves.start) {
+        const shift = -(depth - level - 1) * 12;
+        const label = instrumentLabel(group.instrument, firstRow);
+        drawConnector(context, delimiterTypes[group.delimiter], staffY(group.staves.start), staffY(group.staves.end - 1), x, shift, label);
+      }
+      drawStaffGroups(context, group.groups, staffY, x, firstRow, level + 1, depth);
+    });
+  };
+
   const drawOttavaMarkers = (context: any, ottavaMarkers: any[], allNotes: any[], notesPerRow: any[]) => {
     // Process each ottava marker
     ottavaMarkers.forEach((marker) => {
@@ -1990,7 +2065,12 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
             }
           }
           
-          const vexFlowNote = createVexFlowNote(noteToProcess, currentClef, noteIdx, staffIdx);
+          // A note moved by \change Staff is spelled in the clef of the staff it is drawn on
+          const displayClef = note.display_staff != null ? (staves[note.display_staff]?.clef || 'treble') : currentClef;
+          const vexFlowNote = createVexFlowNote(noteToProcess, displayClef, noteIdx, staffIdx);
+          if (note.display_staff != null) {
+            (vexFlowNote as any)._displayStaff = note.display_staff;
+          }
           // Update clef if this note is a ClefNote
           if (note.note_type === 'Clef' && note.clef) {
             currentClef = note.clef;
@@ -2022,6 +2102,16 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
               if (isRest) {
                 return;
               }
+              // Accidentals decided by the backend (key signature, measure context, ties, ! and ?)
+              if (staveNote._lilypondAccidentals && staveNote._lilypondAccidentals.length > 0) {
+                staveNote._lilypondAccidentals.forEach((printed: PrintedAccidental | null, i: number) => {
+                  if (!printed) return;
+                  const accidental = new Accidental(accidentalSymbols[printed.accidental]);
+                  if (printed.cautionary) accidental.setAsCautionary();
+                  staveNote.addModifier(accidental, i);
+                });
+                return;
+              }
               if (staveNote._lilypondPitch) {
                 const pitch = staveNote._lilypondPitch;
                 const octave = staveNote._lilypondOctave;
@@ -2042,8 +2132,8 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
       })
     );
 
-    // Get the maximum number of measures across all staves (all voices should have same measure count)
-    const maxMeasures = Math.max(...staffMeasures.map(staffVoices => 
+    // The score's measure grid; fall back to the longest staff or voice
+    const maxMeasures = Math.max(musicData.measure_grid?.length ?? 0, ...staffMeasures.map(staffVoices => 
       Math.max(...staffVoices.map(voiceMeasures => voiceMeasures.length))
     ));
 
@@ -2110,27 +2200,29 @@ const MusicNotation: React.FC<MusicNotationProps> = ({ musicData, currentNoteInd
           beatValue,
           numBeats,
           staff,
-          rowIndex);
+          rowIndex,
+          (index) => rowY + index * (staveHeight + staffSpacing));
 
       });
 
-      // Connect staves vertically with a single brace on the left side of the row
-      if (staves.length > 1) {
-        const firstStaveY = rowY;
-        const lastStaveY = rowY + (staves.length - 1) * (staveHeight + staffSpacing);
-        
-        // Create temporary staves for the brace connector at the leftmost position
-        const leftStave = new Stave(marginLeft, firstStaveY, 0);
-        const rightStave = new Stave(marginLeft, lastStaveY, 0);
-        
-        // Set canvas style for brace connector
-        context.strokeStyle = '#000000';
-        context.lineWidth = 1;
-        
-        const connector = new StaveConnector(leftStave, rightStave);
-        connector.setType(StaveConnector.type.BRACE);
-        connector.setContext(context).draw();
+      // Connect staves vertically on the left side of the row: each staff group with its own
+      // brace or bracket, or a single brace when the file has no groups
+      const staffGroups = musicData.staff_groups ?? [];
+      const rowStaffY = (staffIndex: number) => rowY + staffIndex * (staveHeight + staffSpacing);
+      context.strokeStyle = '#000000';
+      context.lineWidth = 1;
+      if (staffGroups.length > 0) {
+        drawStaffGroups(context, staffGroups, rowStaffY, marginLeft, rowIndex === 0, 0, groupDepth(staffGroups));
+      } else if (staves.length > 1) {
+        drawConnector(context, StaveConnector.type.BRACE, rowStaffY(0), rowStaffY(staves.length - 1), marginLeft, 0, null);
       }
+      // Instrument names of the staves: the full name on the first row, the short one after
+      staves.forEach((staff, staffIndex) => {
+        const label = instrumentLabel(staff.instrument, rowIndex === 0);
+        if (label) {
+          drawConnector(context, StaveConnector.type.NONE, rowStaffY(staffIndex), rowStaffY(staffIndex), marginLeft, 0, label);
+        }
+      });
       
     });
     
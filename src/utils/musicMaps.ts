@@ -31,9 +31,103 @@ export interface LilyPondNote {
   group_start?: boolean;  // True if this note starts a slur group
   group_end?: boolean;  // True if this note ends a slur group
   alternative_index?: number[];  // Alternative index (for alternative endings)
-  tuplet_fraction?: string;  // Tuplet fraction (e.g., "3/2" for triplet, "5/4" for quintuplet)
+  tuplet_fraction?: string;  // Tuplet fraction (e.g., "3/2" for triplet, "5/4" for quintuplet); combined ratio for nested tuplets
+  navigation?: NavigationMark[];  // Segno/Coda/D.C./D.S./Fine marks
+  tremolo?: Tremolo;  // Stem or measured tremolo
+  percent_repeat?: number;  // Pass number of notes repeated by \repeat percent (drawn as a percent sign)
+  accidentals?: (PrintedAccidental | null)[];  // Printed accidental per note head (pitch, then chord_notes)
+  properties?: PropertyEvent[];  // \set, \override ... written just before this note
+  stem_direction?: 'Up' | 'Down' | null;  // From \voiceOne, \stemUp ...; null is automatic
+  voice?: number | null;  // Part number inside << { } \\ { } >> (1 is the main voice)
+  spacer?: boolean;  // Spacer rest: takes time, prints nothing
+  staff_change?: string | null;  // \change Staff = "name" (or \autoChange) written just before this note
+  display_staff?: number | null;  // Staff the note is drawn on when moved off its own (index among the score's staves)
+  tuplets?: TupletGroup[];  // Tuplet brackets around the note, outermost first
+  grace?: GraceKind | null;  // Set on grace notes
+  grace_slash?: boolean;  // Slashed stem (\acciaccatura, \slashedGrace)
+  ornament?: Ornament | null;  // \trill, \mordent, \turn ...
+  articulations?: Articulation[];  // \staccato, -. , \accent, -> ... in written order
+  fermata?: Fermata | null;
+  dynamic?: string | null;  // Dynamic mark without backslash: 'p', 'mf', 'sfz' ...
+  hairpins?: Hairpin[];  // \<, \> and \! written after the note
+  slur_start?: boolean;  // A slur starts on this note (not set by ties)
+  slur_end?: boolean;  // A slur ends on this note
+  pedals?: PedalEvent[];  // \sustainOn, \sustainOff ... in written order
 }
 
+/**
+ * Scheme value as typed data, e.g. { type: 'String', value: 'violin' }
+ */
+export type SchemeValue =
+  | { type: 'Boolean'; value: boolean }
+  | { type: 'Integer' | 'Real'; value: number }
+  | { type: 'Rational' | 'Moment'; value: { numerator: number; denominator: number } }
+  | { type: 'String' | 'Symbol'; value: string }
+  | { type: 'List'; value: SchemeValue[] }
+  | { type: 'Unspecified' };
+
+/**
+ * One \set, \unset, \override or \revert (context is null for the voice)
+ */
+export interface PropertyEvent {
+  action: 'Set' | 'Unset' | 'Override' | 'Revert';
+  context: string | null;
+  property: string;
+  value: SchemeValue | null;
+  once: boolean;
+  span: { start: number; end: number } | null;  // Byte offsets in the source
+}
+
+export type GraceKind = 'Grace' | 'Acciaccatura' | 'Appoggiatura';
+
+export type Ornament =
+  | 'Trill' | 'Prall' | 'PrallPrall' | 'PrallMordent' | 'PrallUp' | 'PrallDown' | 'UpPrall' | 'DownPrall'
+  | 'LinePrall' | 'Mordent' | 'UpMordent' | 'DownMordent' | 'Turn' | 'ReverseTurn' | 'SlashTurn' | 'HaydnTurn';
+
+export type Articulation = 'Accent' | 'Marcato' | 'Staccato' | 'Staccatissimo' | 'Tenuto' | 'Portato' | 'Espressivo';
+
+export type Fermata = 'VeryShort' | 'Short' | 'Normal' | 'Long' | 'VeryLong';
+
+export type Hairpin = 'Crescendo' | 'Decrescendo' | 'Stop';
+
+export type PedalEvent = 'SustainOn' | 'SustainOff' | 'SostenutoOn' | 'SostenutoOff' | 'UnaCorda' | 'TreCorde';
+
+/**
+ * Tuplet bracket around a note: numerator notes in the time of denominator
+ */
+export interface TupletGroup {
+  id: number;  // Same for every note of one bracket
+  numerator: number;
+  denominator: number;
+  start: boolean;
+  end: boolean;
+}
+
+/**
+ * Tremolo strokes on a note; the value is the note value of the strokes (16 = sixteenths)
+ */
+export type Tremolo = { Stem: number } | { Start: number } | { End: number };
+
+/**
+ * Accidental printed before a note head; cautionary ones are drawn in parentheses
+ */
+export type AccidentalSign = 'DoubleFlat' | 'Flat' | 'Natural' | 'Sharp' | 'DoubleSharp';
+export interface PrintedAccidental {
+  accidental: AccidentalSign;
+  cautionary: boolean;
+}
+
+/**
+ * Where playback stops after a D.C./D.S. jump
+ */
+export type JumpEnd = 'End' | 'Fine' | 'Coda';
+
+/**
+ * Navigation mark on a note: Segno and Coda mark the note a jump lands on,
+ * the others take effect after the note
+ */
+export type NavigationMark = 'Segno' | 'Coda' | 'ToCoda' | 'Fine' | { DaCapo: JumpEnd } | { DalSegno: JumpEnd };
+
 /**
  * Lyric data for a voice
  */
@@ -41,11 +135,97 @@ export interface Lyric {
   text_nodes: string[];
 }
 
+/**
+ * Barline drawn at the end of a measure
+ */
+export type BarlineType = 'Single' | 'Double' | 'Final' | 'StartRepeat' | 'EndRepeat' | 'EndStartRepeat' | 'Dashed' | 'Invisible';
+
 /**
  * Measure containing note indices
  */
 export interface Measure {
   notes: number[];
+  barline?: BarlineType;  // Barline at the end of this measure
+  grid?: number | null;  // Index into measure_grid
+}
+
+/**
+ * Measure of the grid shared by every staff of a score
+ */
+export interface GridMeasure {
+  number: number;  // Bar number (0 for a pickup)
+  start: number;   // In whole notes from the start of the score
+  length: number;  // Written length in whole notes
+}
+
+/**
+ * A note or chord as played; times in seconds at the score's tempo
+ */
+export interface PerformedNote {
+  staff: number;
+  voice: number | null;
+  note: number;  // Index in its staff or voice (the first of tied notes)
+  start: number;
+  duration: number;
+  keys: number[];  // MIDI keys
+  velocity: number;  // From 0 to 1
+  sounding: number;  // Seconds the note rings, held on by the pedals
+  soft: boolean;  // Played with the una corda pedal down
+  grace: GraceKind | null;
+  ornament: boolean;  // One note of a played-out ornament
+}
+
+export type Pedal = 'Sustain' | 'Sostenuto' | 'UnaCorda';
+
+// MIDI controller of each pedal
+export const pedalControllers: { [key in Pedal]: number } = {
+  Sustain: 64,
+  Sostenuto: 66,
+  UnaCorda: 67,
+};
+
+export interface PedalChange {
+  staff: number;  // Staff it is written in
+  staves: { start: number; end: number };  // Staves it acts on
+  time: number;  // Seconds
+  pedal: Pedal;
+  down: boolean;
+}
+
+/**
+ * Performance timeline: repeats played out, ties joined, grace notes given their own time
+ */
+export interface Timeline {
+  notes: PerformedNote[];
+  duration: number;
+  pedals: PedalChange[];  // By time
+}
+
+/**
+ * Query of the get_vertical_slice command: notes sounding from `from` to `to`
+ * (to == from for a single moment, null for the end of the score)
+ */
+export interface SliceQuery {
+  from: number;
+  to?: number | null;
+  unit?: 'Wholes' | 'Seconds';
+  mode?: 'Overlap' | 'Onset';  // Overlap includes held notes; Onset only notes attacked in the window
+  measures?: { start: number; end: number } | null;  // Measures of measure_grid, end excluded
+  unroll_repeats?: boolean;
+}
+
+/**
+ * Note or chord returned by get_vertical_slice, with the notes tied to it
+ */
+export interface SoundingNote {
+  staff: number;
+  voice: number | null;
+  notes: number[];  // Attacked note, then the notes tied on from it
+  measure: number;  // In its staff or voice
+  onset: number;    // In the unit of the query
+  end: number;
+  pitches: [string, number][];
+  grace: boolean;
 }
 
 /**
@@ -59,6 +239,8 @@ export interface VoiceData {
   };
   lyrics: Lyric[];
   measures?: Measure[];
+  performance_order?: number[];  // Note indices in playing order (repeats and jumps unrolled)
+  number?: number | null;  // Voice number of << { } \\ { } >> passages (1 continues the music around them)
 }
 
 /**
@@ -72,10 +254,99 @@ export interface Staff {
   voices?: VoiceData[];
   lyrics?: any[];
   measures?: Measure[];
+  performance_order?: number[];  // Note indices in playing order (repeats and jumps unrolled)
+  instrument?: InstrumentNames;
+}
+
+/**
+ * instrumentName (first system) and shortInstrumentName (following systems)
+ */
+export interface InstrumentNames {
+  name: string | null;
+  short_name: string | null;
+}
+
+/**
+ * A PianoStaff, GrandStaff, ChoirStaff or StaffGroup; staves index ParsedMusic.staves
+ */
+export interface StaffGroup {
+  group_type: 'PianoStaff' | 'GrandStaff' | 'ChoirStaff' | 'StaffGroup';
+  name: string | null;
+  delimiter: 'Brace' | 'Bracket' | 'Square' | 'Bar';
+  instrument: InstrumentNames;
+  staves: { start: number; end: number };
+  groups: StaffGroup[];
+}
+
+/**
+ * Styling of part of a flattened markup; start/end count characters
+ */
+export type TextStyle =
+  | { type: 'Bold' }
+  | { type: 'Italic' }
+  | { type: 'FontSize'; value: number }       // Steps relative to the normal size
+  | { type: 'Superscript' }
+  | { type: 'Subscript' }
+  | { type: 'Dynamic' }                       // Dynamics font, as in \dynamic "sfz"
+  | { type: 'Color'; value: string }          // CSS color
+  | { type: 'FromProperty'; value: string };  // e.g. "header:piece", to be filled in when shown
+
+export interface StyleSpan {
+  start: number;
+  end: number;
+  style: TextStyle;
+}
+
+/**
+ * Markup flattened to plain text (lines separated by '\n') with styled ranges
+ */
+export interface StyledText {
+  text: string;
+  spans: StyleSpan[];
 }
 
 /**
- * Complete parsed music data from LilyPond
+ * A parsed \markup, to be laid out
+ */
+export type Markup =
+  | { type: 'Text'; text: string }
+  | { type: 'Styled'; style: TextStyle; markup: Markup }
+  | { type: 'Line'; items: Markup[] }
+  | { type: 'Concat'; items: Markup[] }
+  | { type: 'FillLine'; items: Markup[] }
+  | { type: 'Column'; align: 'Left' | 'Center' | 'Right'; items: Markup[] }
+  | { type: 'MusicGlyph'; name: string }                 // e.g. "scripts.segno"
+  | { type: 'Note'; duration: string; direction: number } // \note {4.} #UP
+  | { type: 'HSpace'; amount: number }
+  | { type: 'VSpace'; amount: number }
+  | { type: 'FromProperty'; property: string }
+  | { type: 'Command'; name: string; arguments: string[]; markup: Markup | null };
+
+export type HeaderValue =
+  | { type: 'Text'; value: string }
+  | { type: 'Markup'; value: StyledText }
+  | { type: 'Boolean'; value: boolean };  // ##f hides the field
+
+/**
+ * \header fields in the order they were written
+ */
+export interface Header {
+  fields: { name: string; value: HeaderValue }[];
+}
+
+/**
+ * A \score of the file, listed so one can be chosen
+ */
+export interface Movement {
+  index: number;
+  book: number;
+  part: number;
+  title?: string;
+  staff_count: number;
+}
+
+/**
+ * Complete parsed music data from LilyPond (one movement of the file)
  */
 export interface ParsedMusic {
   title?: string;
@@ -84,7 +355,15 @@ export interface ParsedMusic {
   key_signature?: string;
   time_signature?: string;
   partial?: string;  // Pickup measure duration (e.g., "8", "4", "16")
+  tempo_bpm?: number;  // Quarter notes per minute
+  global_staff_size?: number;  // From #(set-global-staff-size ...)
+  header?: Header;  // Every header field of the movement, including inherited ones
   staves?: Staff[];
+  staff_groups?: StaffGroup[];  // Outermost groups, with the groups nested in them
+  measure_grid?: GridMeasure[];  // Measures shared by every staff
+  timeline?: Timeline;  // The music as played
+  movements?: Movement[];  // Every \score of the file
+  movement?: number;  // Index of the movement shown
 }
 
 /**
@@ -100,6 +379,34 @@ export const durationMap: { [key: string]: string } = {
   '32': '32'  // thirty-second note
 };
 
+// VexFlow ornament type of each ornament; those without a glyph of their own use the closest one
+export const vexFlowOrnaments: { [key in Ornament]: string } = {
+  Trill: 'tr',
+  Prall: 'mordent_inverted',
+  PrallPrall: 'prallprall',
+  PrallMordent: 'prallprall',
+  PrallUp: 'prallup',
+  PrallDown: 'pralldown',
+  UpPrall: 'upprall',
+  DownPrall: 'downprall',
+  LinePrall: 'lineprall',
+  Mordent: 'mordent',
+  UpMordent: 'upmordent',
+  DownMordent: 'downmordent',
+  Turn: 'turn',
+  ReverseTurn: 'turn_inverted',
+  SlashTurn: 'turn',
+  HaydnTurn: 'turn',
+};
+
+// Strokes drawn through the stem for a tremolo: eighths give one, sixteenths two, thirty-seconds three,
+// less the flags or beams the note already has
+export const tremoloStrokes = (tremolo: Tremolo, duration: string): number => {
+  const value = Object.values(tremolo)[0];
+  const flags = Math.max(0, Math.log2(parseInt(duration) || 4) - 2);
+  return Math.max(0, Math.round(Math.log2(value) - 2 - flags));
+};
+
 /**
  * Pitch mapping from LilyPond to VexFlow format
  * Maps LilyPond pitch names to VexFlow pitch names
@@ -130,6 +437,15 @@ export const durationToSecondsMap: { [key: string]: number } = {
   '32': 0.0625  // Thirty-second note
 };
 
+// Accidental signs from the backend to VexFlow accidental codes
+export const accidentalSymbols: { [key in AccidentalSign]: string } = {
+  'DoubleFlat': 'bb',
+  'Flat': 'b',
+  'Natural': 'n',
+  'Sharp': '#',
+  'DoubleSharp': '##',
+};
+
 /**
  * Jianpu (simplified notation) mapping
  * Maps LilyPond pitch names to Jianpu numbers (1-7 for do-ti)
@@ -160,6 +476,30 @@ export const vexFlowDurationMap: { [key: string]: string } = {
   '32': '32'  // thirty-second note
 };
 
+/**
+ * VexFlow key spec for a key signature name. Keys past seven sharps or flats ("G#" for gis major)
+ * are drawn on the seven-accidental key, with the extra accidentals turned into doubles
+ * @param keySignature - Key signature string (e.g., 'F', 'Bbm', 'G#')
+ * @returns VexFlow key spec and, for those keys, the accidental drawn for each of its seven signs
+ */
+export const vexFlowKeySignature = (keySignature: string): { spec: string; alter?: string[] } => {
+  const minor = keySignature.endsWith('m');
+  const tonic = minor ? keySignature.slice(0, -1) : keySignature;
+  const stepFifths: { [step: string]: number } = { C: 0, D: 2, E: 4, F: -1, G: 1, A: 3, B: 5 };
+  const signs = tonic.slice(1);
+  const alter = signs.split('#').length - signs.split('b').length;
+  const fifths = (stepFifths[tonic.charAt(0)] ?? 0) + 7 * alter - (minor ? 3 : 0);
+  if (Math.abs(fifths) <= 7) {
+    return { spec: keySignature };
+  }
+  const sharp = fifths > 0;
+  const extra = Math.abs(fifths) - 7;
+  return {
+    spec: sharp ? (minor ? 'A#m' : 'C#') : (minor ? 'Abm' : 'Cb'),
+    alter: Array.from({ length: 7 }, (_, i) => (sharp ? '#' : 'b').repeat(i < extra ? 2 : 1))
+  };
+};
+
 /**
  * Determine if an accidental should be shown for a note given the key signature
  * @param pitch - LilyPond pitch (e.g., 'bes', 'fis', 'c', 'b')